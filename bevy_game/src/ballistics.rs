@@ -66,7 +66,7 @@ pub fn solve_ballistic_arc(
 
     res.high = Some(Angle{angle: high_ang, time: proj_speed*high_ang.cos()});
 
-    return res;
+    res
 }
 
 pub fn calc_shot_dir(
@@ -88,8 +88,8 @@ pub fn calc_shot_dir(
         gravity,
     );
 
-    let angle = if result.low.is_some() {
-        result.low.unwrap().angle
+    let angle = if let Some(low) = result.low {
+        low.angle
     } else {
         FRAC_PI_4
     };
@@ -98,5 +98,5 @@ pub fn calc_shot_dir(
 //            Debug.Log("calcShotDir startShellSpeed " + startShellSpeed + " " + m_FireTransform.position + " " + tmpDir  + " " + angle);
     let cos_angle = angle.cos();
 
-    return Vec3::new(tmp_dir_xz.x*cos_angle, angle.sin(), tmp_dir_xz.z*cos_angle).normalize();
+    Vec3::new(tmp_dir_xz.x*cos_angle, angle.sin(), tmp_dir_xz.z*cos_angle).normalize()
 }
@@ -0,0 +1,20 @@
+//! Dedicated server: runs the game simulation without window, rendering and audio.
+//!
+//! cargo run --bin bb-server --no-default-features --features headless -- --host
+
+use std::time::Duration;
+
+use bevy::app::ScheduleRunnerSettings;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use bevy_game::{HeadlessPlugins, ServerPlugin};
+
+fn main() {
+    App::new()
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(1. / 60.)))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LogPlugin::default())
+        .add_plugins(HeadlessPlugins)
+        .add_plugin(ServerPlugin)
+        .run();
+}
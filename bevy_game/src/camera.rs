@@ -10,7 +10,6 @@ use bevy_rapier3d::plugin::RapierContext;
 use bevy_rapier3d::prelude::InteractionGroups;
 //use heron::rapier_plugin::PhysicsWorld;
 
-use std::f32::consts::FRAC_PI_2;
 use std::{marker::PhantomData, ops::Mul};
 
 #[derive(Component)]
@@ -103,21 +102,22 @@ impl<T: 'static + Send + Sync> Default for CameraPlugin<T> {
     }
 }
 
+#[allow(clippy::extra_unused_type_parameters)]
 fn setup<T: 'static + Send + Sync>(
     mut commands: Commands,
 //    mut meshes: ResMut<Assets<Mesh>>,
 //    mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
     commands
-        .spawn_bundle(Camera3dBundle {
+        .spawn(Camera3dBundle {
             transform: Transform::from_xyz(0., 50.0, 0.0).looking_at(Vec3::ZERO, Vec3::Y),
             ..Default::default()
         })
         .insert(MyCamera);
 
     commands
-        .spawn_bundle((Transform::IDENTITY, GlobalTransform::IDENTITY))
-        /* .spawn_bundle(PbrBundle {
+        .spawn((Transform::IDENTITY, GlobalTransform::IDENTITY))
+        /* .spawn(PbrBundle {
             mesh: meshes.add(Mesh::from(UVSphere {
                 radius: 0.1,
                 sectors: 8,
@@ -193,7 +193,7 @@ fn update_dir_by_mouse(
     mut camera_state: ResMut<CameraState>,
     camera_query: Query<&Transform, With<MyCamera>>,
 ) {
-    if cursor.len() < 1 {
+    if cursor.is_empty() {
         return;
     }
 
@@ -242,14 +242,14 @@ fn update_ray_with_cursor(
 
         camera_state.mouse_ray = screen_to_world_dir(
             &camera_state.cursor_latest,
-            &Vec2::from([window.width() as f32, window.height() as f32]),
+            &Vec2::from([window.width(), window.height()]),
             camera,
             camera_transform,
         );
 
         camera_state.center_screen_ray = screen_to_world_dir(
-            &Vec2::from([window.width()/2. as f32, window.height()/2. as f32]),
-            &Vec2::from([window.width() as f32, window.height() as f32]),
+            &Vec2::from([window.width()/2., window.height()/2.]),
+            &Vec2::from([window.width(), window.height()]),
             camera,
             camera_transform,
         );
@@ -274,9 +274,7 @@ fn update_ray_with_cursor(
                 Color::BLACK,
             );
     */
-    } else {
-        return;
-    };
+    }
 }
 
 pub fn screen_to_world_dir(
@@ -306,7 +304,7 @@ pub fn screen_to_world_dir(
 
     //    dbg![mouse_position, cursor_ndc, camera_transform.translation, camera_near, cursor_pos_near, dir];
 
-    return dir.normalize();
+    dir.normalize()
 }
 
 fn move_camera_target_by_mouse(
@@ -424,7 +422,7 @@ fn obr_mouse(
     mouse_event: Res<Input<MouseButton>>
 ) {
     if mouse_event.just_pressed(MouseButton::Right) {
-        camera_state.mleft_press_position = camera_state.mouse_hit_position.clone();
+        camera_state.mleft_press_position = camera_state.mouse_hit_position;
     } else if mouse_event.just_released(MouseButton::Right) {
         camera_state.mleft_press_position = None;
     }
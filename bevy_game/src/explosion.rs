@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::AppState;
 
-use crate::game::{InMesVec, COLLISION_ENVIRONMENT, COLLISION_TRIGGER, COLLISION_UNIT};
+use crate::game::{InMes, InMesVec, COLLISION_ENVIRONMENT, COLLISION_TRIGGER, COLLISION_UNIT};
 use crate::menu::is_play_online;
 use crate::player::PlayerData;
 
@@ -273,7 +273,7 @@ fn process_in_explosion(
     mut input: ResMut<InMesVec<NetData>>,
     rapier_context: Res<RapierContext>,
 ) {
-    for (player, explosion) in &input.data {
+    for (player, InMes { data: explosion, .. }) in &input.data {
         log::info!(
             "Explosion process_in_explosion add_explosion pos:{:?}",
             explosion.pos
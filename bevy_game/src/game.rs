@@ -4,23 +4,25 @@ use bevy_rapier3d::prelude::*;
 use iyes_loopless::prelude::*;
 use rand::prelude::*;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
+use std::mem::discriminant;
 
 use crate::explosion::NetData as ExplosionData;
 use crate::explosion::*;
+use crate::interpolation::{Interpolate, Snapshots};
 use crate::menu::{is_play_offline, is_play_online};
-use crate::network::NetPlugin;
+use crate::network::{Authority, Health, NetPlugin, Obstacle};
 use crate::player::*;
 use crate::shot::*;
 use crate::tank::*;
 
 use crate::cleanup::cleanup_system;
 
-use crate::camera::CameraPlugin;
+use crate::camera::{CameraPlugin, CameraState};
 use crate::loading::ModelAssets;
 use crate::terrain::*;
-use crate::AppState;
+use crate::{AppState, Headless};
 
 pub struct GamePlugin;
 
@@ -87,29 +89,57 @@ pub struct OutGameMessages<T>
     pub data: Vec<T>,
 }
 
+/// Received message with the seconds passed since the sender sent it.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InMes<T> {
+    pub data: T,
+    pub age: f32,
+}
+
 #[derive(Debug, Default, Resource)]
 pub struct InMesMap<T>
 //where T: 'static + Serialize + Deserialize + DeserializeOwned + Default + Component + PartialEq,
 {
-    pub data: HashMap<PlayerHandle, T>,
+    pub data: HashMap<PlayerHandle, InMes<T>>,
+}
+
+/// Received messages of every player in the order they were sent.
+#[derive(Debug, Default, Resource)]
+pub struct InMesQueue<T> {
+    pub data: HashMap<PlayerHandle, VecDeque<InMes<T>>>,
+}
+
+impl InMesQueue<GameMessage> {
+    /// Queue the message, a queued state of the same kind is replaced by the newer one.
+    pub fn push(&mut self, player: PlayerHandle, mes: InMes<GameMessage>) {
+        let queue = self.data.entry(player).or_default();
+
+        if mes.data.is_state() {
+            queue.retain(|old| discriminant(&old.data) != discriminant(&mes.data));
+        }
+
+        queue.push_back(mes);
+    }
 }
 
 #[derive(Debug, Default, Resource)]
 pub struct InMesVec<T>
 //where T: 'static + Serialize + Deserialize + DeserializeOwned + Default + Component + PartialEq,
 {
-    pub data: Vec<(PlayerHandle, T)>,
+    pub data: Vec<(PlayerHandle, InMes<T>)>,
 }
 
 #[derive(Component, Debug, Default, PartialEq, Resource)]
 pub struct MesState<T: Default + Component> {
     pub data: T,
+    /// `Time::elapsed_seconds` when the data was sent.
     pub time: f32,
 }
 
 #[repr(C)]
-#[derive(Serialize, Deserialize, Component, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Component, Debug, Clone, PartialEq, Default)]
 pub enum GameMessage {
+    #[default]
     None,
     DataRequest,
     InitData(NewTankData),
@@ -118,11 +148,31 @@ pub enum GameMessage {
     CannonRotate(CannonRotation),
     Shot(ShotData),
     Explosion(ExplosionData),
+    Correction(CorrectionData),
 }
 
-impl Default for GameMessage {
-    fn default() -> Self {
-        GameMessage::None
+impl GameMessage {
+    /// Latest value of the sender, an older one is of no use.
+    pub fn is_state(&self) -> bool {
+        matches!(
+            self,
+            GameMessage::BodyMove(_) | GameMessage::TurretRotate(_) | GameMessage::CannonRotate(_)
+        )
+    }
+
+    /// Name of the variant for the traffic counters.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GameMessage::None => "None",
+            GameMessage::DataRequest => "DataRequest",
+            GameMessage::InitData(_) => "InitData",
+            GameMessage::BodyMove(_) => "BodyMove",
+            GameMessage::TurretRotate(_) => "TurretRotate",
+            GameMessage::CannonRotate(_) => "CannonRotate",
+            GameMessage::Shot(_) => "Shot",
+            GameMessage::Explosion(_) => "Explosion",
+            GameMessage::Correction(_) => "Correction",
+        }
     }
 }
 
@@ -163,6 +213,7 @@ impl From<CannonRotation> for GameMessage {
     }
 }
 
+
 impl From<ShotData> for GameMessage {
     fn from(data: ShotData) -> Self {
         GameMessage::Shot(data)
@@ -179,18 +230,25 @@ impl From<ExplosionData> for GameMessage {
 /// Game logic is only active during the State `AppState::Playing`
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(DebugLinesPlugin::with_depth_test(true))
-            .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
+        if app.world.contains_resource::<Headless>() {
+            // nothing is drawn, but the game systems still read these
+            app.init_resource::<DebugLines>()
+                .init_resource::<CameraState>();
+        } else {
+            app.add_plugin(DebugLinesPlugin::with_depth_test(true))
+                .add_plugin(CameraPlugin::<TempForCamera>::default());
+        }
+
+        app.add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
             //            .add_plugin(RapierPhysicsPlugin::<&CustomFilterTag>::default())
             //            .add_plugin(RapierDebugRenderPlugin::default())
-            .add_plugin(CameraPlugin::<TempForCamera>::default())
             .add_plugin(TerrainPlugin)
             .add_plugin(PlayerPlugin)
             .add_plugin(TankPlugin)
             .add_plugin(ShotPlugin)
             .add_plugin(ExplosionPlugin)
             .add_plugin(NetPlugin)
-            .insert_resource(InMesMap::<GameMessage>::default())
+            .insert_resource(InMesQueue::<GameMessage>::default())
             .insert_resource(InMesMap::<TankBodyData>::default())
             .insert_resource(InMesMap::<TurretRotation>::default())
             .insert_resource(InMesMap::<CannonRotation>::default())
@@ -245,12 +303,12 @@ fn is_create_physics(playing_scene: Res<PlayingScene>) -> bool {
 
 /*
 fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
-       commands.spawn_bundle(UiCameraBundle::default());
+       commands.spawn(UiCameraBundle::default());
 
        let font = asset_server.load("fonts/FiraMono-Medium.ttf");
 
       commands
-           .spawn_bundle(TextBundle {
+           .spawn(TextBundle {
                style: Style {
                    align_self: AlignSelf::FlexStart,
                    flex_direction: FlexDirection::Column,
@@ -291,7 +349,7 @@ fn setup(mut commands: Commands, model_assets: Res<ModelAssets>) {
 
     commands.insert_resource(TerrainScene::new(model_assets.terrain.clone()));
 
-    commands.spawn_bundle(DirectionalLightBundle {
+    commands.spawn(DirectionalLightBundle {
         transform: Transform::from_translation(Vec3::new(4.0, 18.0, 4.0))
             .looking_at(Vec3::ZERO, Vec3::Y),
         ..Default::default()
@@ -359,10 +417,52 @@ fn display_events(mut collision_events: EventReader<bevy_rapier3d::prelude::Coll
     }
 }
 
+/// Cube obstacle, `body` is kinematic on the clients of an authoritative host.
+pub fn spawn_obstacle(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    obstacle: Obstacle,
+    transform: Transform,
+    body: RigidBody,
+) {
+    let half_size = obstacle.size / 2.;
+
+    let linear_damping = 0.2 / obstacle.size;
+    let angular_damping = 0.03 / obstacle.size;
+
+    commands
+        .spawn(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Cube::new(half_size*2.))),
+            material: materials.add(Color::BLACK.into()),
+            transform,
+            ..Default::default()
+        })
+        .insert(body)
+        .insert(bevy_rapier3d::prelude::Collider::cuboid(half_size, half_size, half_size))
+        .insert(CollisionGroups::new(
+            unsafe { Group::from_bits_unchecked(COLLISION_ENVIRONMENT)},
+            unsafe { Group::from_bits_unchecked(COLLISION_ALL)},
+        ))
+        .insert(SolverGroups::new(
+            unsafe { Group::from_bits_unchecked(COLLISION_ENVIRONMENT)},
+            unsafe { Group::from_bits_unchecked(COLLISION_ALL)},
+        ))
+        .insert(Restitution::coefficient(0.7))
+        .insert(ColliderMassProperties::Density(1.0))
+        .insert(Damping {
+            linear_damping,
+            angular_damping,
+        })
+        .insert(obstacle);
+}
+
 pub fn start_game(
     mut commands: Commands,
     rapier_context: Res<RapierContext>,
     local_handles: Res<LocalHandles>,
+    authority: Res<Authority>,
+    headless: Option<Res<Headless>>,
     mut tank_data: ResMut<NewTanksData>,
     //   model_assets: Res<ModelAssets>,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -387,13 +487,16 @@ pub fn start_game(
     let start_angle = rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI);
 
     if let Some(pos) = get_pos_on_ground(start_pos, &rapier_context) {
-        tank_data.vector.push(NewTank {
-            handle,
-            pos: Vec2::new(pos.x, pos.z),
-            angle: start_angle,
-        });
+        // the dedicated server has no tank of its own
+        if headless.is_none() {
+            tank_data.vector.push(NewTank {
+                handle,
+                pos: Vec2::new(pos.x, pos.z),
+                angle: start_angle,
+            });
+        }
 
-        if true {
+        if authority.spawns_obstacles() {
             let mut rng = rand::thread_rng();
             //    let y: f64 = rng.gen(); // generates a float between 0 and 1
 
@@ -416,40 +519,17 @@ pub fn start_game(
                     rng.gen_range(0.07..0.2)
                 };
 
-                let half_size = size / 2.;
-
-                let linear_damping = 0.2 / size;
-                let angular_damping = 0.03 / size;
-
                 if let Some(pos) =
-                    get_pos_on_ground(Vec3::new(pos_x, half_size + 1., pos_z), &rapier_context)
+                    get_pos_on_ground(Vec3::new(pos_x, size / 2. + 1., pos_z), &rapier_context)
                 {
-                    commands
-                            .spawn_bundle(PbrBundle {
-                                mesh: meshes.add(Mesh::from(shape::Cube::new(half_size*2.))),
-                                material: materials.add(Color::BLACK.into()),
-                                transform: Transform::from_translation(pos),
-                                ..Default::default()
-                            })
-                            .insert(bevy_rapier3d::prelude::RigidBody::Dynamic)
-                            .insert(bevy_rapier3d::prelude::Collider::cuboid(half_size, half_size, half_size))
-                            .insert(CollisionGroups::new(
-                                unsafe { Group::from_bits_unchecked(COLLISION_ENVIRONMENT)},
-                                unsafe { Group::from_bits_unchecked(COLLISION_ALL)},
-                            ))
-                            .insert(SolverGroups::new(
-                                unsafe { Group::from_bits_unchecked(COLLISION_ENVIRONMENT)},
-                                unsafe { Group::from_bits_unchecked(COLLISION_ALL)},
-                            ))
-                            .insert(Restitution::coefficient(0.7))
-                            .insert(ColliderMassProperties::Density(1.0))
-                            .insert(Damping {
-                                linear_damping,
-                                angular_damping,
-                            })
-                        //                .insert(Transform::from_xyz(x as f32 * 4.0, 0.5, z as f32 * 4.0))
-                        //                    .insert(PathObstacle);
-                            ;
+                    spawn_obstacle(
+                        &mut commands,
+                        &mut meshes,
+                        &mut materials,
+                        Obstacle { id: i as u32, size },
+                        Transform::from_translation(pos),
+                        RigidBody::Dynamic,
+                    );
                 }
             }
         }
@@ -494,7 +574,7 @@ fn process_new_handles(
 */
 pub fn process_in_raw_message(
     mut commands: Commands,
-    mut raw: ResMut<InMesMap<GameMessage>>,
+    mut raw: ResMut<InMesQueue<GameMessage>>,
     mut in_body: ResMut<InMesMap<TankBodyData>>,
     mut in_turret: ResMut<InMesMap<TurretRotation>>,
     mut in_cannon: ResMut<InMesMap<CannonRotation>>,
@@ -505,16 +585,32 @@ pub fn process_in_raw_message(
     mut tank_body_data_query: Query<(&mut Transform, &PlayerData, &mut MesState<TankBodyData>, &TankEntityes), Without<ControlMove>>,
     mut spawn_tank_data: ResMut<NewTanksData>,
     mut output: ResMut<OutGameMessages<GameMessage>>,
+    mut inputs: ResMut<InputBuffer>,
+    headless: Option<Res<Headless>>,
     //  from_server: Res<Arc<Mutex<mpsc::Receiver<NetEvent>>>>,
     //  to_server: ResMut<mpsc::Sender<NetMessage>>,
     //   time: Res<Time>,
 ) {
     //    log::info!("net handle_conn_events start");
-    'raw_data: for (player, raw_mes) in raw.data.iter() {
-        if GameMessage::DataRequest == *raw_mes {
+    // A DataRequest which comes before our tank exists is answered in a later frame.
+    let mut deferred = Vec::new();
+    let messages: Vec<_> = raw
+        .data
+        .drain()
+        .flat_map(|(player, queue)| queue.into_iter().map(move |mes| (player, mes)))
+        .collect();
+
+    'raw_data: for (player, InMes { data: raw_mes, age }) in messages {
+        if GameMessage::DataRequest == raw_mes {
+            if headless.is_some() {
+                // the dedicated server has no tank to describe
+                continue 'raw_data;
+            }
+
             if player_tank_body_query.is_empty() {
                 log::info!("process_in_raw_message DataRequest: no player tank data!");
-                return;
+                deferred.push((player, InMes { data: raw_mes, age }));
+                continue 'raw_data;
             }
 
             log::info!("process_in_raw_message DataRequest send tank data");
@@ -527,7 +623,7 @@ pub fn process_in_raw_message(
             //           println!( "process_in_raw_message InitData player:{:?}  pos:{:?}  angle:{:?}", player, data.pos, data.angle);
 
             for (transform, exist_player, mut mess_state, entityes) in tank_body_data_query.iter_mut() {
-                if exist_player.handle == *player { // tank for player is already spawned
+                if exist_player.handle == player { // tank for player is already spawned
                     
                     let new_transform = Transform::from_matrix(data.matrix);
 
@@ -569,7 +665,7 @@ pub fn process_in_raw_message(
             let transform = Transform::from_matrix(data.matrix);
 
             spawn_tank_data.vector.push(NewTank {
-                handle: *player,
+                handle: player,
                 pos: Vec2 {
                     x: transform.translation.x,
                     y: transform.translation.z,
@@ -578,38 +674,45 @@ pub fn process_in_raw_message(
             });
         } else if let GameMessage::BodyMove(data) = raw_mes {
             //                   log::info!("Network handle_conn_events TankBodyOutData");
-            in_body.data.insert(*player, *data);
+            in_body.data.insert(player, InMes { data, age });
         } else if let GameMessage::TurretRotate(data) = raw_mes {
             //                   log::info!("Network handle_conn_events TankTurretOutData");
-            in_turret.data.insert(*player, *data);
+            in_turret.data.insert(player, InMes { data, age });
         } else if let GameMessage::CannonRotate(data) = raw_mes {
             //                   log::info!("Network handle_conn_events TankCannonOutData");
-            in_cannon.data.insert(*player, *data);
+            in_cannon.data.insert(player, InMes { data, age });
         } else if let GameMessage::Shot(data) = raw_mes {
             //                   log::info!("Network handle_conn_events TankShotOutData");
-            in_shot.data.push((*player, *data));
+            in_shot.data.push((player, InMes { data, age }));
         } else if let GameMessage::Explosion(data) = raw_mes {
             //                   log::info!("Network handle_conn_events ExplosionData");
-            in_explosion.data.push((*player, *data));
+            in_explosion.data.push((player, InMes { data, age }));
+        } else if let GameMessage::Correction(data) = raw_mes {
+            // only corrections for our tank get here
+            inputs.receive(data);
         }
     }
 
-    raw.data.clear();
+    for (player, mes) in deferred {
+        raw.data.entry(player).or_default().push_back(mes);
+    }
     //   log::info!("net handle_conn_events end");
 }
 
 pub fn process_in_mes_map<T>(
     time: Res<Time>,
     mut input: ResMut<InMesMap<T>>,
-    mut query: Query<(&mut MesState<T>, &PlayerData)>,
+    mut query: Query<(&mut MesState<T>, &mut Snapshots<T>, &PlayerData)>,
     //    mut output: ResMut<OutGameMessages<GameMessage>>,
 ) where
-    T: 'static + Serialize + DeserializeOwned + Default + Debug + Component + PartialEq + Copy,
+    T: 'static + Serialize + DeserializeOwned + Default + Debug + Component + PartialEq + Copy + Interpolate,
 {
-    for (mut state, player) in query.iter_mut() {
-        if let Some(data) = input.data.get_mut(&player.handle) {
-            state.data = *data;
-            state.time = time.elapsed_seconds();
+    for (mut state, mut snapshots, player) in query.iter_mut() {
+        if let Some(mes) = input.data.get(&player.handle) {
+            // Backdate the state to when it was sent.
+            state.data = mes.data;
+            state.time = time.elapsed_seconds() - mes.age;
+            snapshots.push(state.time, state.data);
             //           log::info!("process_in_mes_map data:{:?}", data);
         }
     }
@@ -621,20 +724,22 @@ pub fn process_in_mes_map<T>(
 pub fn process_in_mes_tank_body(
     time: Res<Time>,
     mut input: ResMut<InMesMap<TankBodyData>>,
-    mut query: Query<(&mut MesState<TankBodyData>, &PlayerData)>,
+    mut query: Query<(&mut MesState<TankBodyData>, &mut Snapshots<TankBodyData>, &PlayerData)>,
     //    mut output: ResMut<OutGameMessages<GameMessage>>,
     mut spawn_tank_data: ResMut<NewTanksData>,
 ) {
-    for (mut state, player) in query.iter_mut() {
-        if let Some(data) = input.data.get_mut(&player.handle) {
-            state.data = *data;
-            state.time = time.elapsed_seconds();
+    for (mut state, mut snapshots, player) in query.iter_mut() {
+        if let Some(mes) = input.data.get(&player.handle) {
+            // Backdate the state to when it was sent.
+            state.data = mes.data;
+            state.time = time.elapsed_seconds() - mes.age;
+            snapshots.push(state.time, state.data);
             //            log::info!("process_in_mes_tank_body data:{:?}", data);
         }
     }
 
-    'input_cicle: for (input_player, data) in input.data.iter() {
-        for (mut _state, query_player) in query.iter() {
+    'input_cicle: for (input_player, InMes { data, .. }) in input.data.iter() {
+        for (_state, _snapshots, query_player) in query.iter() {
             if *input_player == query_player.handle {
                 continue 'input_cicle;
             }
@@ -667,7 +772,8 @@ fn send_out<T>(
 pub fn set_player_control(commands: &mut Commands, entityes: &TankEntityes) {
     commands
         .entity(entityes.body)
-        .insert(ControlMove::default());
+        .insert(ControlMove::default())
+        .insert(Health::default());
     commands
         .entity(entityes.turret)
         .insert(ControlTurret::default());
@@ -697,15 +803,20 @@ pub fn set_network_control(
                 angle,
                 linvel: Vec2::ZERO,
                 angvel: 0.,
+                input_seq: 0,
             },
             time: 0.,
-        });
+        })
+        .insert(Snapshots::<TankBodyData>::default())
+        .insert(Health::default());
     commands
         .entity(entityes.turret)
-        .insert(MesState::<TurretRotation>::default());
+        .insert(MesState::<TurretRotation>::default())
+        .insert(Snapshots::<TurretRotation>::default());
     commands
         .entity(entityes.cannon)
-        .insert(MesState::<CannonRotation>::default());
+        .insert(MesState::<CannonRotation>::default())
+        .insert(Snapshots::<CannonRotation>::default());
     commands
         .entity(entityes.fire_point)
         .insert(MesState::<ShotData>::default());
@@ -0,0 +1,48 @@
+use bevy::app::PluginGroupBuilder;
+use bevy::asset::AssetPlugin;
+use bevy::gltf::GltfPlugin;
+use bevy::hierarchy::HierarchyPlugin;
+use bevy::input::InputPlugin;
+use bevy::prelude::*;
+use bevy::render::primitives::Aabb;
+use bevy::scene::ScenePlugin;
+use bevy::transform::TransformPlugin;
+
+/// Engine plugins of the dedicated server, added on top of `MinimalPlugins`:
+/// no window, renderer or audio, the scenes are still loaded for the colliders.
+pub struct HeadlessPlugins;
+
+impl PluginGroup for HeadlessPlugins {
+    fn build(self) -> PluginGroupBuilder {
+        PluginGroupBuilder::start::<Self>()
+            .add(TransformPlugin)
+            .add(HierarchyPlugin)
+            .add(InputPlugin)
+            .add(WindowPlugin {
+                add_primary_window: false,
+                exit_on_all_closed: false,
+                ..default()
+            })
+            .add(AssetPlugin::default())
+            .add(HeadlessAssetsPlugin)
+            .add(ScenePlugin)
+            .add(GltfPlugin)
+    }
+}
+
+/// Assets and components which the render and pbr plugins usually register,
+/// the gltf loader creates them and the scene spawner needs their reflection.
+struct HeadlessAssetsPlugin;
+
+impl Plugin for HeadlessAssetsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Mesh>()
+            .add_asset::<Image>()
+            .add_asset::<StandardMaterial>()
+            .register_type::<Handle<Mesh>>()
+            .register_type::<Handle<StandardMaterial>>()
+            .register_type::<Visibility>()
+            .register_type::<ComputedVisibility>()
+            .register_type::<Aabb>();
+    }
+}
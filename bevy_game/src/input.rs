@@ -90,6 +90,8 @@ where
 {
     keys: HashMap<T, Vec<InputAction>>,
     states: HashMap<T, KeyState>,
+    /// Input is ignored while disabled, e.g. while the chat has the keyboard.
+    enabled: bool,
 }
 
 impl<T> Default for GameControl<T>
@@ -109,6 +111,7 @@ where
         Self {
             keys: HashMap::new(),
             states: HashMap::new(),
+            enabled: true,
         }
     }
 
@@ -135,6 +138,15 @@ where
         //        log::info!("add_action end {:?}", self.keys);
     }
 
+    /// Disabled control releases every key until it is enabled again.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
     pub fn get_key_state(&self, name: T) -> Option<&KeyState> {
         self.states.get(&name)
     }
@@ -214,6 +226,16 @@ where
         for (name, actions) in &self.keys {
 
             let mut key_state = KeyState::default();
+            if !self.enabled {
+                // Keys held when the control was disabled are released once.
+                if let Some(old_state) = self.states.get(name) {
+                    key_state.just_released = old_state.pressed || old_state.just_pressed;
+                    key_state.time = old_state.time;
+                }
+                self.states.insert(name.clone(), key_state);
+                continue;
+            }
+
             let old_time = if let Some(old_state) = self.states.get(name) {
                 old_state.time
             } else {
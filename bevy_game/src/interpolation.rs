@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::{Component, Resource};
+
+/// Most snapshots kept per remote object, about 2 seconds of state messages.
+const SNAPSHOTS_LEN: usize = 64;
+
+/// State of a remote object which can be rendered between two received states.
+pub trait Interpolate: Copy {
+    /// State at `t` from 0 at `self` to 1 at `next`.
+    fn interpolate(&self, next: &Self, t: f32) -> Self;
+    /// State `delta_time` seconds after `self`.
+    fn extrapolate(&self, delta_time: f32) -> Self;
+}
+
+/// Remote objects are rendered `delay` seconds in the past, so there are
+/// usually two received states around the render time.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct Interpolation {
+    /// Seconds between the newest state and the rendered one.
+    pub delay: f32,
+    /// Longest time a state is extrapolated when packets are late, sec.
+    pub max_extrapolation: f32,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Self {
+            delay: 0.1,
+            max_extrapolation: 0.25,
+        }
+    }
+}
+
+impl Interpolation {
+    pub fn render_time(&self, time: f32) -> f32 {
+        time - self.delay
+    }
+}
+
+/// Received states of a remote object, stamped with `Time::elapsed_seconds`
+/// backdated to when they were sent.
+#[derive(Component, Debug)]
+pub struct Snapshots<T> {
+    buffer: VecDeque<(f32, T)>,
+}
+
+impl<T> Default for Snapshots<T> {
+    fn default() -> Self {
+        Self {
+            buffer: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Interpolate> Snapshots<T> {
+    /// Keep the states ordered by time, the gossip mesh may reorder them.
+    pub fn push(&mut self, time: f32, data: T) {
+        let index = self.buffer.partition_point(|(stamp, _)| *stamp <= time);
+        self.buffer.insert(index, (time, data));
+
+        if self.buffer.len() > SNAPSHOTS_LEN {
+            self.buffer.pop_front();
+        }
+    }
+
+    /// State at `render_time`, the newest state is extrapolated for at most
+    /// `max_extrapolation` seconds. States no longer needed are dropped.
+    pub fn sample(&mut self, render_time: f32, max_extrapolation: f32) -> Option<T> {
+        // The last state before the render time is kept to interpolate from.
+        while self.buffer.len() > 1 && self.buffer[1].0 <= render_time {
+            self.buffer.pop_front();
+        }
+
+        let (time, data) = *self.buffer.front()?;
+
+        if render_time <= time {
+            return Some(data);
+        }
+
+        match self.buffer.get(1) {
+            Some((next_time, next)) => {
+                let t = (render_time - time) / (next_time - time);
+                Some(data.interpolate(next, t))
+            }
+            None => Some(data.extrapolate((render_time - time).min(max_extrapolation))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Interpolate for f32 {
+        fn interpolate(&self, next: &Self, t: f32) -> Self {
+            self + (next - self) * t
+        }
+
+        fn extrapolate(&self, delta_time: f32) -> Self {
+            self + delta_time
+        }
+    }
+
+    #[test]
+    fn test_sample_interpolates_and_limits_extrapolation() {
+        let mut snapshots = Snapshots::<f32>::default();
+        assert_eq!(snapshots.sample(1., 0.25), None);
+
+        snapshots.push(1., 10.);
+        snapshots.push(2., 20.);
+        snapshots.push(1.5, 12.);
+
+        assert_eq!(snapshots.sample(0.5, 0.25), Some(10.));
+        assert_eq!(snapshots.sample(1.25, 0.25), Some(11.));
+        assert_eq!(snapshots.sample(1.75, 0.25), Some(16.));
+        assert!((snapshots.sample(2.1, 0.25).unwrap() - 20.1).abs() < 1e-4);
+        assert_eq!(snapshots.sample(5., 0.25), Some(20.25));
+        assert_eq!(snapshots.buffer.len(), 1);
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(dead_code, clippy::too_many_arguments, clippy::type_complexity)]
+
 mod audio;
 mod loading;
 mod menu;
@@ -14,15 +16,19 @@ mod player;
 //mod log_plugin;
 mod camera;
 mod ballistics;
+mod interpolation;
 mod test;
 mod utils;
+mod headless;
 
 //use crate::input::InputPlugin;
 use crate::audio::InternalAudioPlugin;
-use crate::loading::LoadingPlugin;
-use crate::menu::MenuPlugin;
+use crate::loading::{LoadingPlugin, ServerLoadingPlugin};
+use crate::menu::{MenuData, MenuPlugin};
 use crate::game::GamePlugin;
 use crate::test::TestPlugin;
+pub use crate::headless::HeadlessPlugins;
+pub use crate::network::Opts;
 //use crate::network::NetPlugin;
 //use crate::tank::TankPlugin;
 //use crate::player::PlayerPlugin;
@@ -32,10 +38,9 @@ use crate::test::TestPlugin;
 
 use bevy::app::App;
 use bevy::app::Plugin;
+use bevy::ecs::system::Resource;
 
-#[cfg(debug_assertions)]
 //use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
-use bevy::prelude::*;
 //use matchbox_net::NetPlugin;
 
 
@@ -52,6 +57,10 @@ pub enum AppState {
     Test,
 }
 
+/// Present in the dedicated server, which runs without window, audio and a tank of its own.
+#[derive(Resource, Default)]
+pub struct Headless;
+
 pub struct AplicationPlugin;
 
 impl Plugin for AplicationPlugin {
@@ -70,3 +79,16 @@ impl Plugin for AplicationPlugin {
         }
     }
 }
+
+/// Game of the dedicated server, add it after `MinimalPlugins` and `HeadlessPlugins`.
+pub struct ServerPlugin;
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Headless)
+            .insert_resource(MenuData::network())
+            .add_state(AppState::Loading)
+            .add_plugin(ServerLoadingPlugin)
+            .add_plugin(GamePlugin);
+    }
+}
@@ -21,6 +21,19 @@ impl Plugin for LoadingPlugin {
     }
 }
 
+pub struct ServerLoadingPlugin;
+
+/// Loads only the models the dedicated server needs and goes straight to connecting,
+/// fonts need the text plugin which the server doesn't add.
+impl Plugin for ServerLoadingPlugin {
+    fn build(&self, app: &mut App) {
+        LoadingState::new(AppState::Loading)
+            .with_collection::<ModelAssets>()
+            .continue_to_state(AppState::Connecting)
+            .build(app);
+    }
+}
+
 // the following asset collections will be loaded during the State `AppState::Loading`
 // when done loading, they will be inserted as resources (see https://github.com/NiklasEi/bevy_asset_loader)
 
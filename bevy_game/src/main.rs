@@ -7,12 +7,13 @@ use bevy::DefaultPlugins;
 //use bevy_inspector_egui::WorldInspectorPlugin;
 use bevy_game::AplicationPlugin;
 use bevy::{
-    diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     prelude::*,
-    window::{CursorGrabMode, PresentMode},
+    window::PresentMode,
 };
 
 
+// keeps the runtime alive for the lifetime of the app
+#[allow(dead_code)]
 #[derive(Debug, Resource)]
 struct Wrapper<T>
 {
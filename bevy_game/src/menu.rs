@@ -7,9 +7,7 @@ use bevy::text::{Text, TextStyle};
 use iyes_loopless::prelude::ConditionSet;
 use iyes_loopless::prelude::IntoConditionalSystem;
 
-#[path = "cleanup.rs"] 
-mod cleanup;
-use cleanup::cleanup_system;
+use crate::cleanup::cleanup_system;
 
 
 
@@ -21,6 +19,10 @@ pub struct MenuClose;
 #[derive(Component)]
 pub struct MainMenu;
 
+/// Camera of the menu, kept for the connecting screen of an online game.
+#[derive(Component)]
+pub struct MenuCamera;
+
 #[derive(Component)]
 pub struct StartLocalButton;
 
@@ -72,6 +74,15 @@ impl Default for MenuData {
         }
     }
 }
+impl MenuData {
+    /// Online play without the menu, for the dedicated server.
+    pub fn network() -> Self {
+        Self {
+            state: MenuState::Network,
+        }
+    }
+}
+
 /// This plugin is responsible for the game menu (containing only one button...)
 /// The menu is only drawn during the State `AppState::Menu` and is removed when that state is exited
 impl Plugin for MenuPlugin {
@@ -118,7 +129,7 @@ fn setup_menu(
         ..Default::default()
     };
     
-    commands.spawn(Camera2dBundle::default());
+    commands.spawn(Camera2dBundle::default()).insert(MenuCamera);
 
     commands.spawn(NodeBundle {
         style: Style {
@@ -245,7 +256,6 @@ fn on_buttons_action<T: Component>(
     mut commands: Commands,
     query: Query<&Interaction, (Changed<Interaction>, With<Button>, With<T>)>,
     menu_query: Query <Entity, With<MainMenu>>,
-    camera_query: Query <Entity, With<Camera>>,
 ) -> bool {
     for interaction in query.iter() {
         if *interaction == Interaction::Clicked {
@@ -254,10 +264,6 @@ fn on_buttons_action<T: Component>(
                 commands.entity(main_menu).despawn_recursive();
             }
 
-            if let Ok(camera) = camera_query.get_single() {
-                commands.entity(camera).despawn_recursive();
-            }
-
             return true;
         }
     }
@@ -266,8 +272,11 @@ fn on_buttons_action<T: Component>(
 }
 
 fn start_local_game(
+    mut commands: Commands,
+    camera_query: Query<Entity, With<MenuCamera>>,
     mut app_state: ResMut<State<AppState>>,
     mut menu_data: ResMut<MenuData>,) {
+        cleanup_menu_camera(&mut commands, &camera_query);
         menu_data.state = MenuState::Local;
         app_state.replace(AppState::PreparePlaying).unwrap();
 }
@@ -279,12 +288,22 @@ fn start_net_game(
         app_state.replace(AppState::Connecting).unwrap();
 }
 fn start_test(
+    mut commands: Commands,
+    camera_query: Query<Entity, With<MenuCamera>>,
     mut app_state: ResMut<State<AppState>>,
     mut menu_data: ResMut<MenuData>,) {
+        cleanup_menu_camera(&mut commands, &camera_query);
         menu_data.state = MenuState::Test;
         app_state.replace(AppState::Test).unwrap();
 }
 
+/// The online game keeps the camera for the connecting screen, which removes it.
+fn cleanup_menu_camera(commands: &mut Commands, camera_query: &Query<Entity, With<MenuCamera>>) {
+    for camera in camera_query.iter() {
+        commands.entity(camera).despawn_recursive();
+    }
+}
+
 fn exit_system(mut exit: EventWriter<AppExit>) {
     exit.send(AppExit);
 }
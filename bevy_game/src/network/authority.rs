@@ -0,0 +1,476 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::RigidBody;
+use iyes_loopless::prelude::*;
+use peer::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::game::spawn_obstacle;
+use crate::menu::is_play_online;
+use crate::player::{LocalHandles, PlayerData, PlayerHandle};
+use crate::tank::{CorrectionData, TankBodyData, TankEntityes};
+use crate::AppState;
+
+use super::{record_poses, send_to_server, NetHandles, NetMessage, NetSender, Opts, PoseHistory, Wrapper};
+
+pub const MAX_HEALTH: f32 = 100.;
+
+/// Damage in the centre of an explosion per its force.
+const DAMAGE_PER_FORCE: f32 = 25.;
+
+/// Fastest move of a tank the host accepts, m/sec.
+const MAX_TANK_SPEED: f32 = 15.;
+
+/// Distance a reported place may exceed the fastest move by, m.
+const MOVE_SLACK: f32 = 1.;
+
+/// Least time between two shots of a tank, sec.
+const SHOT_INTERVAL: f32 = 0.3;
+
+/// Moved obstacles are sent by the host this often, sec.
+const OBSTACLES_SYNC_INTERVAL: f32 = 0.1;
+
+/// Most obstacles in one message.
+const OBSTACLES_PER_MESSAGE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorityMode {
+    /// Every peer decides the hits of its own shells.
+    Peers,
+    /// This peer decides shots, explosions, damage and obstacle physics of the match,
+    /// the tanks are still moved by their owners.
+    Host,
+    /// The host given by `--join-host` decides.
+    Client(PeerId),
+}
+
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Health {
+    pub value: f32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self { value: MAX_HEALTH }
+    }
+}
+
+/// Health of a tank after a hit, `target` is the peer id of the owner.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthData {
+    pub target: String,
+    pub health: f32,
+}
+
+/// Obstacle of the map, the ids are given by the peer which spawned the obstacles.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Obstacle {
+    pub id: u32,
+    pub size: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ObstacleData {
+    pub id: u32,
+    pub size: f32,
+    pub pos: Vec3,
+    pub rotation: Quat,
+}
+
+/// Explosion decided by this peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exploded {
+    pub pos: Vec3,
+    pub force: f32,
+    pub radius: f32,
+    /// Time of the tanks as the shooter saw them, none for the tanks as they are.
+    pub view_time: Option<f32>,
+}
+
+/// Last accepted place of a tank of another peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Track {
+    pos: Vec2,
+    angle: f32,
+    linvel: Vec2,
+    angvel: f32,
+    /// Input of the owner applied right after the place.
+    input_seq: u32,
+    time: f32,
+}
+
+impl Track {
+    fn new(data: &TankBodyData, time: f32) -> Self {
+        Self {
+            pos: data.pos,
+            angle: data.angle,
+            linvel: data.linvel,
+            angvel: data.angvel,
+            input_seq: data.input_seq,
+            time,
+        }
+    }
+}
+
+/// Which peer decides the results of the match, chosen by `--host` and `--join-host`.
+#[derive(Resource, Debug)]
+pub struct Authority {
+    pub mode: AuthorityMode,
+    tracks: HashMap<PlayerHandle, Track>,
+    last_shots: HashMap<PlayerHandle, f32>,
+    health: Vec<(PlayerHandle, f32)>,
+    obstacles: Vec<ObstacleData>,
+}
+
+impl Authority {
+    pub fn new(opts: &Opts) -> Self {
+        let mode = if opts.host {
+            AuthorityMode::Host
+        } else if let Some(host) = opts.join_host {
+            AuthorityMode::Client(host)
+        } else {
+            AuthorityMode::Peers
+        };
+
+        Self {
+            mode,
+            tracks: HashMap::new(),
+            last_shots: HashMap::new(),
+            health: Vec::new(),
+            obstacles: Vec::new(),
+        }
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.mode == AuthorityMode::Host
+    }
+
+    pub fn is_client(&self) -> bool {
+        matches!(self.mode, AuthorityMode::Client(_))
+    }
+
+    /// This peer decides whether the shell of `shooter` hit.
+    pub fn decides_hits(&self, shooter: PlayerHandle, local: PlayerHandle) -> bool {
+        match self.mode {
+            AuthorityMode::Peers => shooter == local,
+            AuthorityMode::Host => true,
+            AuthorityMode::Client(_) => false,
+        }
+    }
+
+    /// Clients get the obstacles from the host.
+    pub fn spawns_obstacles(&self) -> bool {
+        !self.is_client()
+    }
+
+    /// Clients take the results of the match only from the host, the host only from itself.
+    pub(crate) fn accepts_results_from(&self, peer_id: &PeerId) -> bool {
+        match self.mode {
+            AuthorityMode::Peers => true,
+            AuthorityMode::Host => false,
+            AuthorityMode::Client(host) => host == *peer_id,
+        }
+    }
+
+    /// Only the host given by `--join-host` is taken, any other peer could claim to be the host.
+    pub(crate) fn receive_host(&mut self, peer_id: PeerId) {
+        match self.mode {
+            AuthorityMode::Client(host) if host == peer_id => log::info!("authority host {} is here", peer_id),
+            _ => log::warn!("authority announcement of {} ignored in {:?}", peer_id, self.mode),
+        }
+    }
+
+    /// A shot faster than the reload is rejected.
+    pub fn accept_shot(&mut self, shooter: PlayerHandle, time: f32) -> bool {
+        if let Some(last) = self.last_shots.get(&shooter) {
+            if time - last < SHOT_INTERVAL {
+                return false;
+            }
+        }
+
+        self.last_shots.insert(shooter, time);
+        true
+    }
+
+    /// Speed check of a reported move, the host doesn't simulate the tanks of the clients.
+    /// A place out of reach of the last accepted one is answered with a correction to it,
+    /// acknowledging the input before the one which followed that place; moves within
+    /// reach are taken as the owner reported them.
+    pub(crate) fn check_move(
+        &mut self,
+        handle: PlayerHandle,
+        owner: &PeerId,
+        data: &TankBodyData,
+        time: f32,
+    ) -> Option<CorrectionData> {
+        let track = match self.tracks.get(&handle) {
+            Some(track) => *track,
+            None => {
+                self.tracks.insert(handle, Track::new(data, time));
+                return None;
+            }
+        };
+
+        let reach = MAX_TANK_SPEED * (time - track.time).max(0.) + MOVE_SLACK;
+        if data.pos.distance(track.pos) <= reach {
+            self.tracks.insert(handle, Track::new(data, time));
+            return None;
+        }
+
+        log::warn!("authority rejected move of {} by {}", owner, data.pos.distance(track.pos));
+        Some(CorrectionData {
+            target: owner.to_string(),
+            ack: track.input_seq.wrapping_sub(1),
+            pos: track.pos,
+            angle: track.angle,
+            linvel: track.linvel,
+            angvel: track.angvel,
+        })
+    }
+
+    pub(crate) fn receive_health(&mut self, handle: PlayerHandle, health: f32) {
+        self.health.push((handle, health));
+    }
+
+    pub(crate) fn receive_obstacles(&mut self, obstacles: Vec<ObstacleData>) {
+        self.obstacles.extend(obstacles);
+    }
+}
+
+pub struct AuthorityPlugin;
+
+/// Host mode: the peer started with `--host` decides the shots, explosions, damage and
+/// obstacles of the match, the peers started with `--join-host` show the results of the host.
+/// The movement of the tanks stays predicted by their owners, the host only checks
+/// the reported moves against the top speed of a tank.
+impl Plugin for AuthorityPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<Exploded>()
+            .init_resource::<PoseHistory>()
+            .add_system_set(
+                SystemSet::on_update(AppState::Connecting).with_system(announce_host.run_if(is_play_online)),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(announce_host.run_if(is_play_online))
+                    .with_system(record_poses.run_if(is_play_online).label("record_poses"))
+                    .with_system(apply_damage.run_if(is_play_online).after("record_poses"))
+                    .with_system(update_health.run_if(is_play_online))
+                    .with_system(sync_obstacles.run_if(is_play_online))
+                    .with_system(apply_obstacles.run_if(is_play_online)),
+            );
+    }
+}
+
+/// Tell every new player who the host is.
+fn announce_host(
+    authority: Res<Authority>,
+    handles: Res<NetHandles>,
+    to_server: Option<Res<Wrapper<NetSender>>>,
+    mut announced: Local<usize>,
+) {
+    let to_server = match to_server {
+        Some(to_server) if authority.is_host() => to_server,
+        _ => return,
+    };
+
+    if handles.handles.len() <= *announced {
+        return;
+    }
+
+    if send_to_server(&to_server.value, NetMessage::Authority).is_ok() {
+        *announced = handles.handles.len();
+    }
+}
+
+/// Peer id of the owner of the tank.
+fn owner_of(handle: PlayerHandle, local_handles: &LocalHandles, handles: &NetHandles, to_server: &NetSender) -> Option<PeerId> {
+    if local_handles.handles.first() == Some(&handle) {
+        return Some(to_server.local_peer_id());
+    }
+
+    handles
+        .handles
+        .iter()
+        .find(|(_, player)| **player == handle)
+        .map(|(peer_id, _)| *peer_id)
+}
+
+/// The host damages the tanks in the radius of its explosions and sends their health,
+/// the tanks of a remote shooter's explosion are taken where the shooter saw them.
+fn apply_damage(
+    mut events: EventReader<Exploded>,
+    authority: Res<Authority>,
+    history: Res<PoseHistory>,
+    handles: Res<NetHandles>,
+    local_handles: Res<LocalHandles>,
+    to_server: Res<Wrapper<NetSender>>,
+    mut query: Query<(&GlobalTransform, &PlayerData, &mut Health), With<TankEntityes>>,
+) {
+    for explosion in events.iter() {
+        if !authority.is_host() {
+            continue;
+        }
+
+        for (global_transform, player, mut health) in query.iter_mut() {
+            let pos = explosion
+                .view_time
+                .and_then(|time| history.pose_at(player.handle, time))
+                .map_or(global_transform.translation(), |(pos, _)| pos);
+
+            let distance = pos.distance(explosion.pos);
+            if distance >= explosion.radius || health.value <= 0. {
+                continue;
+            }
+
+            let damage = DAMAGE_PER_FORCE * explosion.force * (1. - distance / explosion.radius);
+            health.value = (health.value - damage).max(0.);
+            log::info!("authority tank {} health {}", player.handle, health.value);
+
+            let target = match owner_of(player.handle, &local_handles, &handles, &to_server.value) {
+                Some(peer_id) => peer_id.to_string(),
+                None => continue,
+            };
+
+            let mess = NetMessage::Health(HealthData { target, health: health.value });
+            if send_to_server(&to_server.value, mess).is_err() {
+                log::warn!("authority health of tank {} not sent", player.handle);
+            }
+        }
+    }
+}
+
+/// Clients take the health of the tanks from the host.
+fn update_health(
+    mut authority: ResMut<Authority>,
+    mut query: Query<(&PlayerData, &mut Health), With<TankEntityes>>,
+) {
+    for (handle, value) in std::mem::take(&mut authority.health) {
+        for (player, mut health) in query.iter_mut() {
+            if player.handle == handle {
+                health.value = value;
+                if value <= 0. {
+                    log::info!("authority tank {} destroyed", handle);
+                }
+            }
+        }
+    }
+}
+
+/// The host sends all obstacles to new players and the moved ones to everybody.
+#[allow(clippy::too_many_arguments)]
+fn sync_obstacles(
+    time: Res<Time>,
+    authority: Res<Authority>,
+    handles: Res<NetHandles>,
+    to_server: Res<Wrapper<NetSender>>,
+    all: Query<(&Obstacle, &Transform)>,
+    moved: Query<&Obstacle, Changed<Transform>>,
+    mut dirty: Local<HashSet<u32>>,
+    mut last_sync: Local<f32>,
+    mut announced: Local<usize>,
+) {
+    if !authority.is_host() {
+        return;
+    }
+
+    dirty.extend(moved.iter().map(|obstacle| obstacle.id));
+
+    let is_new_player = handles.handles.len() > *announced;
+    if !is_new_player && time.elapsed_seconds() - *last_sync < OBSTACLES_SYNC_INTERVAL {
+        return;
+    }
+
+    let obstacles: Vec<_> = all
+        .iter()
+        .filter(|(obstacle, _)| is_new_player || dirty.contains(&obstacle.id))
+        .map(|(obstacle, transform)| ObstacleData {
+            id: obstacle.id,
+            size: obstacle.size,
+            pos: transform.translation,
+            rotation: transform.rotation,
+        })
+        .collect();
+
+    for chunk in obstacles.chunks(OBSTACLES_PER_MESSAGE) {
+        if send_to_server(&to_server.value, NetMessage::Obstacles(chunk.to_vec())).is_err() {
+            log::warn!("authority obstacles not sent");
+            return;
+        }
+    }
+
+    dirty.clear();
+    *last_sync = time.elapsed_seconds();
+    *announced = handles.handles.len();
+}
+
+/// Clients show the obstacles of the host as kinematic bodies.
+fn apply_obstacles(
+    mut commands: Commands,
+    mut authority: ResMut<Authority>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut query: Query<(&Obstacle, &mut Transform)>,
+) {
+    if authority.obstacles.is_empty() {
+        return;
+    }
+
+    let mut received: HashMap<u32, ObstacleData> = std::mem::take(&mut authority.obstacles)
+        .into_iter()
+        .map(|data| (data.id, data))
+        .collect();
+
+    for (obstacle, mut transform) in query.iter_mut() {
+        if let Some(data) = received.remove(&obstacle.id) {
+            transform.translation = data.pos;
+            transform.rotation = data.rotation;
+        }
+    }
+
+    for data in received.into_values() {
+        spawn_obstacle(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            Obstacle { id: data.id, size: data.size },
+            Transform::from_translation(data.pos).with_rotation(data.rotation),
+            RigidBody::KinematicPositionBased,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_move_corrects_teleports() {
+        let mut authority = Authority {
+            mode: AuthorityMode::Host,
+            tracks: HashMap::new(),
+            last_shots: HashMap::new(),
+            health: Vec::new(),
+            obstacles: Vec::new(),
+        };
+        let owner = PeerId::random();
+        let mut data = TankBodyData::default();
+
+        assert_eq!(authority.check_move(1, &owner, &data, 0.), None);
+
+        data.pos = Vec2::new(10., 0.);
+        data.input_seq = 42;
+        assert_eq!(authority.check_move(1, &owner, &data, 1.), None);
+
+        data.pos = Vec2::new(100., 0.);
+        data.input_seq = 50;
+        let correction = authority.check_move(1, &owner, &data, 2.).unwrap();
+        assert_eq!((correction.ack, correction.pos), (41, Vec2::new(10., 0.)));
+        assert_eq!(correction.target, owner.to_string());
+
+        assert!(authority.accept_shot(1, 0.));
+        assert!(!authority.accept_shot(1, 0.1));
+        assert!(authority.accept_shot(1, 0.5));
+    }
+}
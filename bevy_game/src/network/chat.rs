@@ -0,0 +1,347 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+use common::{BlueError, BlueResult, Identity};
+use iyes_loopless::prelude::*;
+use peer::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::cleanup::cleanup_system;
+use crate::input::GameControl;
+use crate::loading::FontAssets;
+use crate::menu::is_play_online;
+use crate::player::{Actions, PlayerHandle};
+use crate::AppState;
+
+use super::{send_to_server, NetHandles, NetMessage, NetSender, Opts, Wrapper};
+
+/// Received messages kept in the history.
+const CHAT_HISTORY_LEN: usize = 50;
+
+/// Messages shown in the overlay.
+const CHAT_VISIBLE_LINES: usize = 8;
+
+/// Longest message a player can type, in characters.
+const CHAT_MAX_LEN: usize = 200;
+
+/// Chat identity of the sender, chosen by the player with `--name`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatProfile {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ChatText {
+    Public(String),
+    /// Text encrypted to the public key of the recipient, see `Identity::encrypt_for`.
+    Private { recipient: String, data: Vec<u8> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub sender: ChatProfile,
+    /// Match clock time of sending in milliseconds.
+    pub sent_at: u64,
+    pub text: ChatText,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatEntry {
+    pub sender: String,
+    pub sent_at: u64,
+    pub text: String,
+    pub private: bool,
+}
+
+impl ChatEntry {
+    fn line(&self) -> String {
+        let secs = self.sent_at / 1000;
+        let private = if self.private { " (private)" } else { "" };
+        format!("[{:02}:{:02}] {}{}: {}", secs / 60, secs % 60, self.sender, private, self.text)
+    }
+}
+
+/// Chat messages of the match, oldest first.
+#[derive(Resource, Default, Debug)]
+pub struct ChatHistory {
+    pub entries: VecDeque<ChatEntry>,
+    /// Latest chat name of every peer which sent a message, for `/w <name>`.
+    names: HashMap<PeerId, String>,
+}
+
+impl ChatHistory {
+    pub fn push(&mut self, entry: ChatEntry) {
+        if self.entries.len() >= CHAT_HISTORY_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Add a received message, private messages to other players are skipped.
+    ///
+    /// The name is chosen by the sender, so it's shown with the handle of
+    /// the signed `source` of the message.
+    pub fn receive(
+        &mut self,
+        identity: &Identity,
+        local_peer_id: PeerId,
+        source: PeerId,
+        handle: PlayerHandle,
+        msg: ChatMessage,
+    ) {
+        let (text, private) = match msg.text {
+            ChatText::Public(text) => (text, false),
+            ChatText::Private { recipient, .. } if recipient != local_peer_id.to_string() => return,
+            ChatText::Private { data, .. } => {
+                let text = identity
+                    .decrypt(&data)
+                    .and_then(|text| String::from_utf8(text).map_err(BlueError::remote_err));
+
+                match text {
+                    Ok(text) => (text, true),
+                    Err(e) => {
+                        log::warn!("private chat message from {} dropped: {}", source, e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        self.push(ChatEntry {
+            sender: format!("{}#{}", msg.sender.name, handle),
+            sent_at: msg.sent_at,
+            text,
+            private,
+        });
+        self.names.insert(source, msg.sender.name);
+    }
+
+    /// Peer of a player given by the handle shown in the chat or by the chat name.
+    fn find_player(&self, handles: &NetHandles, player: &str) -> BlueResult<PeerId> {
+        if let Ok(handle) = player.trim_start_matches('#').parse::<PlayerHandle>() {
+            return handles
+                .handles
+                .iter()
+                .find(|(_, h)| **h == handle)
+                .map(|(peer_id, _)| *peer_id)
+                .ok_or_else(|| BlueError::local_err(format!("unknown player {}", player)));
+        }
+
+        let mut named = self.names.iter().filter(|(_, name)| name.as_str() == player);
+        match (named.next(), named.next()) {
+            (Some((peer_id, _)), None) => Ok(*peer_id),
+            (Some(_), Some(_)) => Err(BlueError::local_err(format!("several players are named {}, use #<number>", player))),
+            (None, _) => Err(BlueError::local_err(format!("unknown player {}", player))),
+        }
+    }
+}
+
+/// Line the player is typing, the tank isn't driven meanwhile.
+#[derive(Resource, Default, Debug)]
+pub struct ChatInput {
+    pub typing: bool,
+    pub text: String,
+}
+
+#[derive(Component)]
+struct ChatOverlay;
+
+#[derive(Component)]
+struct ChatLog;
+
+#[derive(Component)]
+struct ChatPrompt;
+
+pub struct ChatPlugin;
+
+/// Chat overlay of an online match: Enter starts typing and sends,
+/// Escape cancels, `/w <player> <text>` sends a private message to a player
+/// given by the name or the `#<number>` shown next to it.
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<ChatHistory>()
+            .init_resource::<ChatInput>()
+            .add_system_set(
+                SystemSet::on_enter(AppState::Playing).with_system(setup_chat.run_if(is_play_online)),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(chat_input.run_if(is_play_online))
+                    .with_system(update_chat.run_if(is_play_online)),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::Playing).with_system(cleanup_system::<ChatOverlay>));
+    }
+}
+
+fn setup_chat(mut commands: Commands, font_assets: Res<FontAssets>) {
+    let style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 18.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+
+    commands.spawn(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                left: Val::Px(10.),
+                bottom: Val::Px(10.),
+                ..Default::default()
+            },
+            flex_direction: FlexDirection::Column,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .insert(ChatOverlay)
+    .with_children(|overlay| {
+        overlay.spawn(TextBundle {
+            text: Text::from_section("", style.clone()),
+            ..Default::default()
+        })
+        .insert(ChatLog);
+
+        overlay.spawn(TextBundle {
+            text: Text::from_section("", TextStyle {
+                color: Color::rgb(1.0, 0.9, 0.5),
+                ..style
+            }),
+            ..Default::default()
+        })
+        .insert(ChatPrompt);
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn chat_input(
+    keyboard: Res<Input<KeyCode>>,
+    mut chars: EventReader<ReceivedCharacter>,
+    mut input: ResMut<ChatInput>,
+    mut history: ResMut<ChatHistory>,
+    mut game_control: ResMut<GameControl<Actions>>,
+    to_server: Res<Wrapper<NetSender>>,
+    handles: Res<NetHandles>,
+    opts: Res<Opts>,
+) {
+    if !input.typing {
+        chars.clear();
+
+        if keyboard.just_pressed(KeyCode::Return) {
+            input.typing = true;
+            game_control.set_enabled(false);
+        }
+        return;
+    }
+
+    if keyboard.just_pressed(KeyCode::Escape) || keyboard.just_pressed(KeyCode::Return) {
+        let text = std::mem::take(&mut input.text);
+        input.typing = false;
+        game_control.set_enabled(true);
+        chars.clear();
+
+        if keyboard.just_pressed(KeyCode::Return) && !text.trim().is_empty() {
+            send_chat(&to_server.value, &handles, &opts, &mut history, text.trim());
+        }
+        return;
+    }
+
+    if keyboard.just_pressed(KeyCode::Back) {
+        input.text.pop();
+    }
+
+    for ev in chars.iter() {
+        if !ev.char.is_control() && input.text.chars().count() < CHAT_MAX_LEN {
+            input.text.push(ev.char);
+        }
+    }
+}
+
+/// Send the typed line and echo it to the history, gossipsub doesn't deliver our own messages.
+fn send_chat(to_server: &NetSender, handles: &NetHandles, opts: &Opts, history: &mut ChatHistory, line: &str) {
+    // the match clock counts microseconds
+    let sent_at = to_server.clock().now() / 1000;
+
+    let (text, private) = match line.strip_prefix("/w ") {
+        Some(rest) => match private_text(history, handles, rest) {
+            Ok(res) => res,
+            Err(e) => {
+                history.push(ChatEntry {
+                    sender: "chat".to_string(),
+                    sent_at,
+                    text: e.to_string(),
+                    private: false,
+                });
+                return;
+            }
+        },
+        None => (ChatText::Public(line.to_string()), line.to_string()),
+    };
+
+    let msg = ChatMessage {
+        sender: ChatProfile {
+            name: opts.name.clone(),
+        },
+        sent_at,
+        text,
+    };
+
+    let is_private = matches!(msg.text, ChatText::Private { .. });
+    if send_to_server(to_server, NetMessage::Chat(msg)).is_err() {
+        log::warn!("chat message dropped, the network queue is full");
+        return;
+    }
+
+    history.push(ChatEntry {
+        sender: opts.name.clone(),
+        sent_at,
+        text: private,
+        private: is_private,
+    });
+}
+
+/// Encrypt `<player> <text>` to the player, returns the message and the plain text.
+fn private_text(history: &ChatHistory, handles: &NetHandles, rest: &str) -> BlueResult<(ChatText, String)> {
+    let (player, text) = rest
+        .split_once(' ')
+        .ok_or_else(|| BlueError::local_err("usage: /w <player> <text>"))?;
+
+    let peer_id = history.find_player(handles, player)?;
+
+    let key = common::public_key_of(&peer_id)
+        .ok_or_else(|| BlueError::local_err(format!("no public key of player {}", player)))?;
+
+    let data = Identity::encrypt_for(&key, text.as_bytes())?;
+    let msg = ChatText::Private {
+        recipient: peer_id.to_string(),
+        data,
+    };
+
+    Ok((msg, text.to_string()))
+}
+
+fn update_chat(
+    history: Res<ChatHistory>,
+    input: Res<ChatInput>,
+    mut log_query: Query<&mut Text, (With<ChatLog>, Without<ChatPrompt>)>,
+    mut prompt_query: Query<&mut Text, (With<ChatPrompt>, Without<ChatLog>)>,
+) {
+    if history.is_changed() {
+        let skip = history.entries.len().saturating_sub(CHAT_VISIBLE_LINES);
+        let lines: Vec<String> = history.entries.iter().skip(skip).map(ChatEntry::line).collect();
+
+        for mut text in log_query.iter_mut() {
+            text.sections[0].value = lines.join("\n");
+        }
+    }
+
+    if input.is_changed() {
+        for mut text in prompt_query.iter_mut() {
+            text.sections[0].value = if input.typing {
+                format!("> {}_", input.text)
+            } else {
+                String::new()
+            };
+        }
+    }
+}
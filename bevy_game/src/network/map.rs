@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use bevy::prelude::*;
+use common::{BlueError, BlueResult};
+use iyes_loopless::prelude::*;
+use peer::{ContentHash, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::loading::ModelAssets;
+use crate::menu::is_play_online;
+use crate::AppState;
+
+use super::{send_to_server, Authority, NetHandles, NetMessage, NetSender, Opts, Wrapper};
+
+/// Root file of a map directory, it references the other files by relative paths.
+const MAP_SCENE: &str = "scene.gltf";
+
+/// File of a map, `path` is relative to the map directory with `/` separators.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MapFile {
+    pub path: String,
+    pub hash: ContentHash,
+}
+
+/// Content of a custom map, announced by the host to every new player.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MapManifest {
+    pub files: Vec<MapFile>,
+}
+
+impl MapManifest {
+    fn scene_hash(&self) -> Option<ContentHash> {
+        self.files.iter().find(|file| file.path == MAP_SCENE).map(|file| file.hash)
+    }
+
+    /// Remote paths must stay inside the map directory.
+    fn validate(&self) -> BlueResult<()> {
+        if self.scene_hash().is_none() {
+            return Err(BlueError::remote_err(format!("map has no {}", MAP_SCENE)));
+        }
+
+        for file in &self.files {
+            let safe = Path::new(&file.path)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+            if !safe || file.path.is_empty() {
+                return Err(BlueError::remote_err(format!("bad map file path {:?}", file.path)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapState {
+    /// The map shipped with the game.
+    BuiltIn,
+    /// The files of `--map` are shared once the network is up.
+    Sharing,
+    /// Waiting for the manifest of the host.
+    Waiting,
+    /// Files of the manifest are being fetched from the host.
+    Fetching,
+    /// Directory of the custom map.
+    Ready(PathBuf),
+    Failed(String),
+}
+
+/// Custom map shared by the host or fetched from it.
+#[derive(Resource, Debug)]
+pub struct MapTransfer {
+    pub state: MapState,
+    manifest: Option<MapManifest>,
+    fetched: HashMap<ContentHash, PathBuf>,
+}
+
+impl MapTransfer {
+    pub fn new(opts: &Opts) -> Self {
+        let state = if opts.map.is_some() {
+            MapState::Sharing
+        } else if opts.wait_map {
+            MapState::Waiting
+        } else {
+            MapState::BuiltIn
+        };
+
+        Self {
+            state,
+            manifest: None,
+            fetched: HashMap::new(),
+        }
+    }
+
+    /// Playing can start, the custom map is complete or isn't used.
+    pub fn is_ready(&self) -> bool {
+        matches!(self.state, MapState::BuiltIn | MapState::Ready(_))
+    }
+
+    /// Progress of the map for the connecting screen, none when there's nothing to wait for.
+    pub fn status_text(&self) -> Option<String> {
+        match &self.state {
+            MapState::Waiting => Some("Waiting for the map of the host...".to_string()),
+            MapState::Fetching => Some(format!(
+                "Fetching the map of the host, {} of {} files",
+                self.fetched.len(),
+                self.manifest.as_ref().map_or(0, |manifest| manifest.files.len()),
+            )),
+            MapState::Failed(e) => Some(format!("Map transfer failed: {}", e)),
+            MapState::BuiltIn | MapState::Sharing | MapState::Ready(_) => None,
+        }
+    }
+
+    /// Fetch the missing files of the host's map, cached files are reported at once.
+    /// Manifests of other peers than the host are ignored.
+    pub fn receive_manifest(&mut self, to_server: &NetSender, authority: &Authority, host: PeerId, manifest: MapManifest) {
+        if self.state != MapState::Waiting {
+            return;
+        }
+
+        if !authority.is_client() || !authority.accepts_results_from(&host) {
+            log::warn!("map manifest of {} ignored, it isn't the host", host);
+            return;
+        }
+
+        if let Err(e) = manifest.validate() {
+            log::warn!("map manifest of {} rejected: {}", host, e);
+            return;
+        }
+
+        log::info!("fetching map of {} files from {}", manifest.files.len(), host);
+        for file in &manifest.files {
+            to_server.fetch_asset(file.hash, host);
+        }
+
+        self.manifest = Some(manifest);
+        self.state = MapState::Fetching;
+    }
+
+    pub fn asset_ready(&mut self, hash: ContentHash, path: PathBuf) {
+        if self.state != MapState::Fetching {
+            return;
+        }
+
+        self.fetched.insert(hash, path);
+
+        let manifest = match &self.manifest {
+            Some(manifest) => manifest,
+            None => return,
+        };
+        if !manifest.files.iter().all(|file| self.fetched.contains_key(&file.hash)) {
+            return;
+        }
+
+        self.state = match self.assemble(manifest) {
+            Ok(dir) => {
+                log::info!("map assembled in {:?}", dir);
+                MapState::Ready(dir)
+            }
+            Err(e) => MapState::Failed(e.to_string()),
+        };
+    }
+
+    pub fn asset_failed(&mut self, hash: ContentHash, error: String) {
+        let is_map_file = self
+            .manifest
+            .as_ref()
+            .is_some_and(|manifest| manifest.files.iter().any(|file| file.hash == hash));
+
+        if self.state == MapState::Fetching && is_map_file {
+            log::error!("map file {} failed: {}", hash, error);
+            self.state = MapState::Failed(error);
+        }
+    }
+
+    /// Copy the cached files under their paths, so the scene finds its buffers and textures.
+    fn assemble(&self, manifest: &MapManifest) -> BlueResult<PathBuf> {
+        let scene = manifest.scene_hash().expect("validated manifest has a scene");
+        let dir = std::env::temp_dir().join("beyond-blue").join("maps").join(scene.to_string());
+
+        for file in &manifest.files {
+            let target = dir.join(&file.path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&self.fetched[&file.hash], target)?;
+        }
+
+        Ok(dir)
+    }
+
+    /// Load the terrain from the custom map.
+    pub fn apply(&self, asset_server: &AssetServer, model_assets: &mut ModelAssets) {
+        if let MapState::Ready(dir) = &self.state {
+            model_assets.terrain = asset_server.load(dir.join(MAP_SCENE));
+        }
+    }
+}
+
+pub struct MapPlugin;
+
+/// Custom maps: the host shares `--map <dir>` with the other players, a player
+/// started with `--wait-map` waits in `AppState::Connecting` until the host's map is fetched.
+impl Plugin for MapPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_system_set(
+                SystemSet::on_update(AppState::Connecting)
+                    .with_system(share_map.run_if(is_play_online))
+                    .with_system(announce_map.run_if(is_play_online)),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::Playing).with_system(announce_map.run_if(is_play_online)),
+            );
+    }
+}
+
+/// Provide the files of `--map` once the network is up.
+fn share_map(
+    opts: Res<Opts>,
+    to_server: Option<Res<Wrapper<NetSender>>>,
+    mut map: ResMut<MapTransfer>,
+) {
+    let (dir, to_server) = match (&opts.map, to_server) {
+        (Some(dir), Some(to_server)) if map.state == MapState::Sharing => (dir, to_server),
+        _ => return,
+    };
+
+    match provide_dir(&to_server.value, dir) {
+        Ok(manifest) => {
+            log::info!("sharing map {:?} of {} files", dir, manifest.files.len());
+            map.manifest = Some(manifest);
+            map.state = MapState::Ready(dir.clone());
+        }
+        Err(e) => {
+            log::error!("map {:?} can't be shared: {}", dir, e);
+            map.state = MapState::Failed(e.to_string());
+        }
+    }
+}
+
+fn provide_dir(to_server: &NetSender, dir: &Path) -> BlueResult<MapManifest> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+
+            let relative = path.strip_prefix(dir).map_err(BlueError::local_err)?;
+            let relative: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+            files.push(MapFile {
+                path: relative.join("/"),
+                hash: to_server.provide_asset(&path)?,
+            });
+        }
+    }
+
+    let manifest = MapManifest { files };
+    manifest.validate()?;
+    Ok(manifest)
+}
+
+/// Send the manifest of the shared map whenever a new player shows up.
+fn announce_map(
+    map: Res<MapTransfer>,
+    handles: Res<NetHandles>,
+    opts: Res<Opts>,
+    to_server: Option<Res<Wrapper<NetSender>>>,
+    mut announced: Local<usize>,
+) {
+    let (manifest, to_server) = match (&map.manifest, to_server) {
+        (Some(manifest), Some(to_server)) if opts.map.is_some() => (manifest, to_server),
+        _ => return,
+    };
+
+    if handles.handles.len() <= *announced {
+        return;
+    }
+
+    if send_to_server(&to_server.value, NetMessage::Map(manifest.clone())).is_ok() {
+        *announced = handles.handles.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(paths: &[&str]) -> MapManifest {
+        MapManifest {
+            files: paths
+                .iter()
+                .map(|path| MapFile {
+                    path: path.to_string(),
+                    hash: ContentHash::of(path.as_bytes()),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_paths_outside_map() {
+        assert!(manifest(&[MAP_SCENE, "textures/ground.png"]).validate().is_ok());
+
+        assert!(manifest(&["textures/ground.png"]).validate().is_err());
+        assert!(manifest(&[MAP_SCENE, "../ground.png"]).validate().is_err());
+        assert!(manifest(&[MAP_SCENE, "/etc/ground.png"]).validate().is_err());
+        assert!(manifest(&[MAP_SCENE, "textures/../../ground.png"]).validate().is_err());
+        assert!(manifest(&[MAP_SCENE, ""]).validate().is_err());
+    }
+
+    #[test]
+    fn test_assemble_restores_paths() {
+        let cache = std::env::temp_dir().join("beyond-blue").join("test-assemble");
+        fs::create_dir_all(&cache).unwrap();
+
+        let files = [(MAP_SCENE, "scene of the assemble test"), ("textures/ground.png", "ground")];
+        let mut transfer = MapTransfer {
+            state: MapState::Fetching,
+            manifest: None,
+            fetched: HashMap::new(),
+        };
+        let mut manifest = MapManifest { files: Vec::new() };
+
+        for (path, content) in files {
+            let hash = ContentHash::of(content.as_bytes());
+            let cached = cache.join(hash.to_string());
+            fs::write(&cached, content).unwrap();
+
+            transfer.fetched.insert(hash, cached);
+            manifest.files.push(MapFile { path: path.to_string(), hash });
+        }
+
+        let dir = transfer.assemble(&manifest).unwrap();
+        assert_eq!(fs::read_to_string(dir.join(MAP_SCENE)).unwrap(), "scene of the assemble test");
+        assert_eq!(fs::read_to_string(dir.join("textures").join("ground.png")).unwrap(), "ground");
+
+        _ = fs::remove_dir_all(dir);
+        _ = fs::remove_dir_all(cache);
+    }
+}
@@ -1,24 +1,49 @@
 use serde::{Deserialize, Serialize};
 use bevy::prelude::*;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
+use std::path::PathBuf;
+use std::mem::{discriminant, Discriminant};
+use std::time::Duration;
+use bevy::app::AppExit;
 use tokio::runtime::Runtime;
-use clap::{Parser, arg};
+use clap::Parser;
 use iyes_loopless::prelude::*;
 
-use peer::NetworkEvent;
-use common::BlueResult;
+use peer::{Channel, MessageKind, NetSimConfig, NetworkEvent, PeerHandle, PeerId, QueueStats, Session, WireCodec};
+use common::Identity;
 
+use crate::interpolation::Interpolation;
+use crate::loading::ModelAssets;
 use crate::menu::is_play_online;
-use crate::player::PlayerHandle;
-use crate::AppState;
+use crate::player::{LocalHandles, PlayerHandle};
+use crate::{AppState, Headless};
 
 mod ping;
 pub use ping::*;
 
+mod status;
+pub use status::*;
+
+mod chat;
+pub use chat::*;
+
+mod map;
+pub use map::*;
+
+mod authority;
+pub use authority::*;
+
+mod rollback;
+pub use rollback::*;
+
+mod rewind;
+pub use rewind::*;
+
+mod delta;
+pub use delta::*;
+
 use crate::game::{GameMessage, OutGameMessages};
-use crate::game::InMesMap;
+use crate::game::{InMes, InMesQueue};
 
 
 #[derive(Parser, Debug, Resource)]
@@ -27,6 +52,91 @@ pub struct Opts {
     /// The listening address
     #[arg(long)] 
     relay_address: url::Url,
+
+    /// Match session to join, peers of different sessions don't see each other
+    #[arg(long, default_value = "default")]
+    session: String,
+
+    /// Name shown to other players in the chat
+    #[arg(long, default_value = "Player")]
+    name: String,
+
+    /// Directory of a custom map with scene.gltf, shared by the host with the other players
+    #[arg(long, requires = "host")]
+    map: Option<PathBuf>,
+
+    /// Wait for the custom map of the host instead of playing the built-in one
+    #[arg(long, requires = "join_host")]
+    wait_map: bool,
+
+    /// Decide shots, explosions, damage and obstacles of the match for all players
+    #[arg(long, conflicts_with = "join_host")]
+    host: bool,
+
+    /// Take the results of the match from the player with this peer id, the host prints it on start
+    #[arg(long, value_name = "PEER_ID")]
+    join_host: Option<PeerId>,
+
+    /// Exchange inputs and resimulate the tanks when an input arrives late, instead of sending their state
+    #[arg(long, conflicts_with_all = ["host", "join_host"])]
+    rollback: bool,
+
+    /// Ticks the local input is applied later in rollback mode, fewer resimulations for more latency
+    #[arg(long, default_value_t = 2)]
+    input_delay: u32,
+
+    /// Most network events handled in one frame, the rest wait for the next frame
+    #[arg(long, default_value_t = 256)]
+    net_budget: usize,
+
+    /// Delay of the rendered state of remote tanks in milliseconds
+    #[arg(long, default_value_t = 100)]
+    interp_delay: u64,
+
+    /// Longest extrapolation of remote tanks when packets are late, in milliseconds
+    #[arg(long, default_value_t = 250)]
+    max_extrapolation: u64,
+
+    /// Encoding of outgoing messages: msgpack, bincode or json
+    #[arg(long, default_value = "msgpack")]
+    codec: WireCodec,
+
+    /// Simulated one-way latency in milliseconds, for testing
+    #[arg(long, default_value_t = 0)]
+    sim_latency: u64,
+
+    /// Simulated random extra latency in milliseconds, for testing
+    #[arg(long, default_value_t = 0)]
+    sim_jitter: u64,
+
+    /// Simulated share of lost frames from 0 to 1, for testing
+    #[arg(long, default_value_t = 0.)]
+    sim_loss: f64,
+
+    /// Simulated bandwidth in bytes per second, for testing
+    #[arg(long)]
+    sim_bandwidth: Option<u64>,
+}
+
+impl Opts {
+    fn netsim(&self) -> Option<NetSimConfig> {
+        let netsim = NetSimConfig {
+            latency: Duration::from_millis(self.sim_latency),
+            jitter: Duration::from_millis(self.sim_jitter),
+            loss: self.sim_loss,
+            bandwidth: self.sim_bandwidth,
+            seed: None,
+        };
+
+        netsim.is_enabled().then_some(netsim)
+    }
+
+    fn interpolation(&self) -> Interpolation {
+        Interpolation {
+            delay: Duration::from_millis(self.interp_delay).as_secs_f32(),
+            max_extrapolation: Duration::from_millis(self.max_extrapolation).as_secs_f32(),
+        }
+    }
 }
 
 #[derive(Debug, Resource)]
@@ -41,17 +151,158 @@ pub struct Wrapper<T>
 #[repr(C)]
 #[derive(Serialize, Deserialize, Resource, Debug, Clone)]
 pub enum NetMessage {
-    Ping(u64, u64),
-    Pong(u64, u64),
     GameData(GameMessage),
+    Chat(ChatMessage),
+    Map(MapManifest),
+    /// The sender is the host of the match.
+    Authority,
+    Health(HealthData),
+    Obstacles(Vec<ObstacleData>),
+    RollbackInputs(RollbackInputs),
+    RollbackChecksum(RollbackChecksum),
+    /// Body, turret and cannon of the sender's tank.
+    TankState(PackedTank),
+    StateAck(StateAck),
+}
+
+impl NetMessage {
+    pub fn channel(&self) -> Channel {
+        match self {
+            NetMessage::GameData(data) if data.is_state() => Channel::State,
+            NetMessage::TankState(_) | NetMessage::StateAck(_) => Channel::State,
+            NetMessage::Chat(_) => Channel::Chat,
+            _ => Channel::Events,
+        }
+    }
+
+    /// Queued state with the same key is replaced by the newer one.
+    fn state_key(&self) -> u64 {
+        match self {
+            NetMessage::TankState(_) | NetMessage::GameData(GameMessage::BodyMove(_)) => 0,
+            NetMessage::GameData(GameMessage::TurretRotate(_)) => 1,
+            NetMessage::GameData(GameMessage::CannonRotate(_)) => 2,
+            // a newer ack to the same peer covers the queued one
+            NetMessage::StateAck(ack) => 3 + ack.target as u64,
+            _ => u64::MAX,
+        }
+    }
+}
+
+impl MessageKind for NetMessage {
+    fn kind(&self) -> &'static str {
+        match self {
+            NetMessage::GameData(data) => data.kind(),
+            NetMessage::Chat(_) => "Chat",
+            NetMessage::Map(_) => "Map",
+            NetMessage::Authority => "Authority",
+            NetMessage::Health(_) => "Health",
+            NetMessage::Obstacles(_) => "Obstacles",
+            NetMessage::RollbackInputs(_) => "RollbackInputs",
+            NetMessage::RollbackChecksum(_) => "RollbackChecksum",
+            NetMessage::TankState(_) => "TankState",
+            NetMessage::StateAck(_) => "StateAck",
+        }
+    }
 }
 
 pub type NetEvent = NetworkEvent<NetMessage>;
 
+/// How often the traffic counters are logged, in seconds.
+const STATS_LOG_INTERVAL: f32 = 10.;
+
+/// Older messages are aged by this limit, a larger age is a clock glitch.
+const MAX_MESSAGE_AGE: f32 = 1.;
+pub type NetSender = PeerHandle<NetMessage>;
+
+/// Queue the message for the network task, an event is handed back if the queue is full.
+pub fn send_to_server(to_server: &NetSender, msg: NetMessage) -> Result<(), NetMessage> {
+    match msg.channel() {
+        Channel::State => {
+            to_server.send_state(msg.state_key(), msg);
+            Ok(())
+        }
+        channel => to_server.send(channel, msg),
+    }
+}
+
+/// Latest sequence number of every kind of state message per sender.
+/// State which arrives after a newer one of the same kind is stale.
+#[derive(Resource, Default)]
+pub struct StateSeq {
+    data: HashMap<(PlayerHandle, Discriminant<GameMessage>), u64>,
+}
+
+impl StateSeq {
+    fn is_stale(&mut self, handle: PlayerHandle, seq: u64, mess: &NetMessage) -> bool {
+        let data = match mess {
+            NetMessage::GameData(data) if mess.channel() == Channel::State => data,
+            _ => return false,
+        };
+
+        let last = self.data.entry((handle, discriminant(data))).or_insert(seq);
+        if seq < *last {
+            return true;
+        }
+
+        *last = seq;
+        false
+    }
+}
+
+/// Events of the network task handled per frame and the depth of its queue.
+#[derive(Resource, Debug)]
+pub struct NetInbox {
+    /// Most events handled in one frame.
+    pub budget: usize,
+    /// Events handled in the last frame.
+    pub handled: usize,
+    /// Events left in the queue after the last frame.
+    pub pending: usize,
+    /// Deepest queue since the last `reset_max`.
+    pub max_pending: usize,
+}
+
+impl NetInbox {
+    fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            handled: 0,
+            pending: 0,
+            max_pending: 0,
+        }
+    }
+
+    fn record(&mut self, handled: usize, pending: usize) {
+        self.handled = handled;
+        self.pending = pending;
+        self.max_pending = self.max_pending.max(self.pending);
+    }
+
+    pub fn reset_max(&mut self) {
+        self.max_pending = self.pending;
+    }
+}
+
 #[derive(Resource)]
 pub struct NetHandles {
     last_handle: usize,
-    pub handles: HashMap<String, PlayerHandle>,
+    pub handles: HashMap<PeerId, PlayerHandle>,
+}
+
+impl NetHandles {
+    /// Handle of the peer, the flag is set when the peer is seen for the first time.
+    fn get_or_insert(&mut self, peer_id: &PeerId) -> (PlayerHandle, bool) {
+        if let Some(handle) = self.handles.get(peer_id) {
+            return (*handle, false);
+        }
+
+        let new_handle = self.last_handle + 1;
+        assert!(new_handle < usize::MAX);
+        self.handles.insert(*peer_id, new_handle);
+        self.last_handle = new_handle;
+
+        (new_handle, true)
+    }
 }
 
 pub struct NetPlugin;
@@ -59,22 +310,45 @@ pub struct NetPlugin;
 impl Plugin for NetPlugin {
     fn build(&self, app: &mut App) {
         // DefaultPlugins will use window descriptor
-        let opts = Opts::parse();
+        // options inserted before the plugin take precedence over the command line
+        let opts = app.world.remove_resource::<Opts>().unwrap_or_else(Opts::parse);
+
+        if !app.world.contains_resource::<Wrapper<Runtime>>() {
+            app.insert_resource(Wrapper{
+                value: tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()},
+            );
+        }
 
         let before_system_set = SystemSet::on_update(AppState::Playing)
             .with_system(handle_conn_events.run_if(is_play_online));
 
         let after_system_set = SystemSet::on_update(AppState::Playing)
                 .with_system(send_out.run_if(is_play_online))
+                .with_system(send_state_acks.run_if(is_play_online))
+                .with_system(log_network_stats.run_if(is_play_online))
                 .with_system(update_ping.run_if(is_play_online));
 
         app
+            .insert_resource( MapTransfer::new(&opts) )
+            .insert_resource( Authority::new(&opts) )
+            .insert_resource( Rollback::new(&opts) )
+            .insert_resource( NetInbox::new(opts.net_budget) )
+            .insert_resource( opts.interpolation() )
             .insert_resource( opts )
             .insert_resource( PingList::default() )
             .insert_resource( NetHandles{handles: HashMap::new(), last_handle: 0} )
+            .init_resource::<StateSeq>()
+            .init_resource::<TankStates>()
+            .add_plugin(MapPlugin)
+            .add_plugin(AuthorityPlugin)
+            .add_plugin(RollbackPlugin)
             .add_system_set(
                 SystemSet::on_enter(AppState::Connecting).with_system(setup_network.label("net_setup")),
             )
+            .add_system_set(SystemSet::on_update(AppState::Playing).with_system(update_map_bounds))
             .add_system_set(
                 SystemSet::on_update(AppState::Connecting)
                 .with_system(check_network.run_if(is_play_online))
@@ -87,8 +361,18 @@ impl Plugin for NetPlugin {
             )*/
             .add_system_set_to_stage(CoreStage::PreUpdate, before_system_set)
             .add_system_set_to_stage(CoreStage::PostUpdate, after_system_set)
+            .add_system_to_stage(CoreStage::Last, shutdown_network)
             ;
 
+        if app.world.contains_resource::<Headless>() {
+            // nothing is drawn, the events still update the status and the chat
+            app.init_resource::<NetStatus>()
+                .init_resource::<ChatHistory>();
+        } else {
+            app.add_plugin(StatusPlugin)
+                .add_plugin(ChatPlugin);
+        }
+
         log::info!("net init plugin");
     }
 }
@@ -100,37 +384,59 @@ fn setup_network(
 ) {
     log::info!("setup_network start");
 
-    let (local_in, local_out) = mpsc::channel(32);
-    let (remote_in, remote_out) = mpsc::channel(32);
-
     let relay_address = opts.relay_address.clone();
-    runtime.value.spawn(async move {
-        let id = common::Identity::from_file("nothing".into());
-
-        tokio::spawn(async move {
-            let res = peer::Swarm::new_with_default_transport(id.get_key())
-                .await?
-                .spawn::<NetMessage>(relay_address, remote_in, local_out)
-                .await;
-
-            log::info!("Game swarm result: {:?}", res);
-
-            BlueResult::Ok(())
-        });
-    });
-
-    commands.insert_resource(Wrapper{value: local_in});
-    commands.insert_resource(Wrapper{value: Arc::new(Mutex::new(remote_out))});
+    let session = Session::new(opts.session.clone());
+    let config = peer::Config {
+        codec: opts.codec,
+        netsim: opts.netsim(),
+        ..Default::default()
+    };
+    let id = common::Identity::from_file("nothing".into());
+
+    // Only the transport is created here, connecting runs in the network task.
+    let res = runtime.value.block_on(
+        PeerHandle::<NetMessage>::start(id.get_key(), config, relay_address, session)
+    );
+
+    match res {
+        Ok(handle) => {
+            log::info!("local peer id: {}", handle.local_peer_id());
+            if opts.host {
+                log::info!("hosting the match, players join with --join-host {}", handle.local_peer_id());
+            }
+            commands.insert_resource(Wrapper{value: handle});
+            // Private chat messages are decrypted with the key of the peer.
+            commands.insert_resource(Wrapper{value: id});
+        }
+        Err(e) => log::error!("Game swarm start failed: {:?}", e),
+    }
 
     log::info!("setup_network end");
 }
 
+/// Stop the network task when the app exits.
+fn shutdown_network(
+    mut exit: EventReader<AppExit>,
+    handle: Option<Res<Wrapper<NetSender>>>,
+) {
+    if exit.iter().next().is_some() {
+        if let Some(handle) = handle {
+            handle.value.shutdown();
+        }
+    }
+}
+
 fn check_network(
     ping: Res<PingList>,
+    map: Res<MapTransfer>,
+    asset_server: Res<AssetServer>,
+    mut model_assets: ResMut<ModelAssets>,
     mut app_state: ResMut<State<AppState>>,
 ) {
 //    log::info!("net check_network start");
-    if ping.is_connected() {
+    // A custom map must be complete before the terrain is created.
+    if ping.is_connected() && map.is_ready() {
+        map.apply(&asset_server, &mut model_assets);
         app_state.replace(AppState::PreparePlaying).unwrap();
         log::info!("check_network ok, set AppState::PreparePlaying");
     }
@@ -140,28 +446,40 @@ fn check_network(
 pub fn handle_conn_events(
     mut ping: ResMut<PingList>,
     mut handles: ResMut<NetHandles>,    
-    mut in_mess: ResMut<InMesMap<GameMessage>>,
-    from_server: Res<Wrapper<Arc<Mutex<mpsc::Receiver<NetEvent>>>>>, 
- //   from_server: Res<Arc<Mutex<mpsc::Receiver<NetEvent>>>>,
-    to_server: ResMut<Wrapper<mpsc::Sender<NetMessage>>>, 
- //   to_server: ResMut<mpsc::Sender<NetMessage>>,
+    mut state_seq: ResMut<StateSeq>,
+    mut in_mess: ResMut<InMesQueue<GameMessage>>,
+    mut output: ResMut<OutGameMessages<GameMessage>>,
+    mut status: ResMut<NetStatus>,
+    mut chat: ResMut<ChatHistory>,
+    mut map: ResMut<MapTransfer>,
+    mut inbox: ResMut<NetInbox>,
+    mut authority: ResMut<Authority>,
+    mut rollback: ResMut<Rollback>,
+    mut tank_states: ResMut<TankStates>,
     time: Res<Time>,
+    local_handles: Res<LocalHandles>,
+    identity: Res<Wrapper<Identity>>,
+    to_server: ResMut<Wrapper<NetSender>>, 
+ //   to_server: ResMut<mpsc::Sender<NetMessage>>,
 ) {
  //   log::info!("net handle_conn_events start");
 
-    // The operation can't be blocking inside the bevy system.
-    if let Ok(msg) = from_server.value.lock().unwrap().try_recv() {
+    // The operation can't be blocking inside the bevy system,
+    // so the queue is drained up to the budget and the rest waits a frame.
+    let mut handled = 0;
+    while handled < inbox.budget {
+        let msg = match to_server.value.try_recv() {
+            Some(msg) => msg,
+            None => break,
+        };
+        handled += 1;
+
         match msg {
             peer::NetworkEvent::NewConnection(peer_id) => {
 //                log::info!("handle_conn_events msg: NewConnection");
 
-                if handles.handles.get(&peer_id).is_none() {
-                    let new_handle = handles.last_handle + 1; 
-                    assert!(new_handle < usize::MAX);       
-                    handles.handles.insert(peer_id.clone(), new_handle);   
-                    handles.last_handle = new_handle;  
-             
-                    let _res = to_server.value.try_send(NetMessage::GameData(GameMessage::DataRequest));
+                if handles.get_or_insert(&peer_id).1 {
+                    request_data(&to_server.value, &mut output);
                 }
 
                 if !ping.is_connected() {
@@ -169,41 +487,250 @@ pub fn handle_conn_events(
                 }
             },
 
-            peer::NetworkEvent::Event(peer_id, mess) => {   
+            peer::NetworkEvent::Event(header, mess) => {   
 //                log::info!("handle_conn_events msg: Event");                
 
-                let handle = handles.handles.get(&peer_id).unwrap().clone();
-
-                if let NetMessage::Ping(id, temp) = mess {
-//                    log::info!("handle_conn_events Ping id:{:?}", id);  
-                    ping.check_collision_id(id);
-                    let _res = to_server.value.try_send(NetMessage::Pong(id, temp));
-                } else if let NetMessage::Pong(id, _) = mess {
-//                    log::info!("handle_conn_events Pong id:{:?}", id);   
-                    ping.receive_pong(id, handle, time.elapsed_seconds());
-                } else if let NetMessage::GameData(data) = mess {
- //                   log::info!("handle_conn_events {:?}", data.clone());
-                    in_mess.data.insert(handle, data);
+                // Peers behind other mesh members never open a connection to us,
+                // so the first message is the first time we see them.
+                let (handle, is_new) = handles.get_or_insert(&header.source);
+                if is_new {
+                    request_data(&to_server.value, &mut output);
+                }
+
+                // The combined tank state is split back into the state messages of the tank.
+                let messes = match mess {
+                    NetMessage::TankState(packed) => tank_states
+                        .decode(handle, &packed)
+                        .into_iter()
+                        .map(NetMessage::GameData)
+                        .collect(),
+                    NetMessage::StateAck(ack) => {
+                        if ack.target == short_id(&to_server.value.local_peer_id()) {
+                            tank_states.receive_ack(handle, &ack, time.elapsed_seconds());
+                        }
+                        continue;
+                    }
+                    mess => vec![mess],
+                };
+
+                for mess in messes {
+                    if state_seq.is_stale(handle, header.seq, &mess) {
+                        continue;
+                    }
+
+                    match &mess {
+                        // in rollback mode the tanks of the other players are simulated from their inputs
+                        NetMessage::GameData(data) if data.is_state() && rollback.is_enabled() => continue,
+                        NetMessage::GameData(GameMessage::Explosion(_) | GameMessage::Correction(_))
+                            if !authority.accepts_results_from(&header.source) => continue,
+                        NetMessage::GameData(GameMessage::Correction(correction))
+                            if correction.target != to_server.value.local_peer_id().to_string() => continue,
+                        NetMessage::GameData(GameMessage::BodyMove(data)) if authority.is_host() => {
+                            let time = time.elapsed_seconds();
+                            if let Some(correction) = authority.check_move(handle, &header.source, data, time) {
+                                output.data.push(GameMessage::Correction(correction));
+                                continue;
+                            }
+                        }
+                        _ => {}
+                    }
+
+                    if let NetMessage::GameData(data) = mess {
+     //                   log::info!("handle_conn_events {:?}", data.clone());
+                        // Until the clocks are synchronized the one-way latency is the best guess.
+                        let clock = to_server.value.clock();
+                        let age = if clock.is_synced() {
+                            clock.age(header.sent_at).as_secs_f32().min(MAX_MESSAGE_AGE)
+                        } else {
+                            ping.get_time(handle)
+                        };
+
+                        in_mess.push(handle, InMes { data, age });
+                    } else if let NetMessage::Chat(msg) = mess {
+                        chat.receive(&identity.value, to_server.value.local_peer_id(), header.source, handle, msg);
+                    } else if let NetMessage::Map(manifest) = mess {
+                        map.receive_manifest(&to_server.value, &authority, header.source, manifest);
+                    } else if let NetMessage::Authority = mess {
+                        authority.receive_host(header.source);
+                    } else if let NetMessage::Health(data) = mess {
+                        if authority.is_client() && authority.accepts_results_from(&header.source) {
+                            let target = if data.target == to_server.value.local_peer_id().to_string() {
+                                local_handles.handles.first().copied()
+                            } else {
+                                data.target.parse::<PeerId>().ok().and_then(|peer_id| handles.handles.get(&peer_id).copied())
+                            };
+
+                            if let Some(target) = target {
+                                authority.receive_health(target, data.health);
+                            }
+                        }
+                    } else if let NetMessage::Obstacles(obstacles) = mess {
+                        if authority.is_client() && authority.accepts_results_from(&header.source) {
+                            authority.receive_obstacles(obstacles);
+                        }
+                    } else if let NetMessage::RollbackInputs(inputs) = mess {
+                        if rollback.is_enabled() {
+                            rollback.receive_inputs(handle, inputs);
+                        }
+                    } else if let NetMessage::RollbackChecksum(checksum) = mess {
+                        if rollback.is_enabled() {
+                            rollback.receive_checksum(handle, checksum);
+                        }
+                    }
                 }
             },
+
+            peer::NetworkEvent::Disconnected(peer_id) => {
+                log::info!("handle_conn_events peer disconnected: {}", peer_id);
+            },
+
+            peer::NetworkEvent::Reachability(reachability) => {
+                log::info!("handle_conn_events reachability: {:?}", reachability);
+                status.reachability = reachability;
+            },
+
+            peer::NetworkEvent::Banned(peer_id, reason) => {
+                log::warn!("handle_conn_events peer banned: {} {:?}", peer_id, reason);
+            },
+
+            peer::NetworkEvent::AssetReady(hash, path) => {
+                map.asset_ready(hash, path);
+            },
+
+            peer::NetworkEvent::AssetFailed(hash, error) => {
+                map.asset_failed(hash, error);
+            },
         }
     }
+
+    inbox.record(handled, to_server.value.events_len());
  //   log::info!("net handle_conn_events end");
 }
 
+/// Ask the peers for their tanks, a request which didn't fit the queue is retried by `send_out`.
+fn request_data(to_server: &NetSender, output: &mut OutGameMessages<GameMessage>) {
+    if let Err(NetMessage::GameData(mess)) = send_to_server(to_server, NetMessage::GameData(GameMessage::DataRequest)) {
+        output.data.push(mess);
+    }
+}
+
 fn send_out(
+    time: Res<Time>,
+    handles: Res<NetHandles>,
+    rollback: Res<Rollback>,
+    mut tank_states: ResMut<TankStates>,
     mut output: ResMut<OutGameMessages<GameMessage>>,
-    to_server: ResMut<Wrapper<mpsc::Sender<NetMessage>>>, 
- //   to_server: ResMut<mpsc::Sender<NetMessage>>,
+    to_server: ResMut<Wrapper<NetSender>>, 
+    mut last_stats: Local<QueueStats>,
 ) {
-    if output.is_changed() {
+    // Messages the queue didn't accept stay in the output for the next frame.
+    if !output.data.is_empty() {
+        let mut rejected = Vec::new();
 
         for mess in output.data.drain(0..) {
  //           log::info!("send_out {:?}", mess.clone());
-            let _res = to_server.value.try_send(NetMessage::GameData(mess));
+            // the tank state goes out packed in one message below
+            if tank_states.take(&mess) {
+                continue;
+            }
+
+            if let Err(NetMessage::GameData(mess)) = send_to_server(&to_server.value, NetMessage::GameData(mess)) {
+                rejected.push(mess);
+            }
         }
 
-        output.data.clear();
+        output.data = rejected;
+    }
+
+    // in rollback mode the other peers simulate our tank from its tick inputs
+    let players: Vec<_> = handles.handles.values().copied().collect();
+    if rollback.is_enabled() {
+        tank_states.skip();
+    } else if let Some(packed) = tank_states.encode(&players, time.elapsed_seconds()) {
+        if send_to_server(&to_server.value, NetMessage::TankState(packed)).is_err() {
+            log::warn!("send_out tank state dropped");
+        }
+    }
+
+    let stats = to_server.value.queue_stats();
+    if stats != *last_stats {
+        log::warn!("network queue overflow: {:?}, queued: {}", stats, to_server.value.queue_len());
+        *last_stats = stats;
+    }
+}
+
+/// Acknowledge the tank states received from the other peers, so they can delta against them.
+fn send_state_acks(
+    time: Res<Time>,
+    handles: Res<NetHandles>,
+    mut tank_states: ResMut<TankStates>,
+    to_server: Res<Wrapper<NetSender>>,
+    mut last_ack: Local<f32>,
+) {
+    if time.elapsed_seconds() - *last_ack < ACK_INTERVAL {
+        return;
+    }
+    *last_ack = time.elapsed_seconds();
+
+    for (handle, seq, received) in tank_states.take_acks() {
+        let peer_id = handles.handles.iter().find(|(_, h)| **h == handle).map(|(peer_id, _)| peer_id);
+        if let Some(peer_id) = peer_id {
+            let ack = StateAck { target: short_id(peer_id), seq, received };
+            if send_to_server(&to_server.value, NetMessage::StateAck(ack)).is_err() {
+                log::warn!("send_state_acks dropped ack for player {}", handle);
+            }
+        }
     }
 }
 
+/// Log the traffic of the last interval per channel and peer.
+fn log_network_stats(
+    time: Res<Time>,
+    handles: Res<NetHandles>,
+    ping: Res<PingList>,
+    mut inbox: ResMut<NetInbox>,
+    to_server: Res<Wrapper<NetSender>>,
+    mut last_log: Local<f32>,
+) {
+    if time.elapsed_seconds() - *last_log < STATS_LOG_INTERVAL {
+        return;
+    }
+
+    let interval = time.elapsed_seconds() - *last_log;
+    *last_log = time.elapsed_seconds();
+
+    let stats = to_server.value.stats();
+    to_server.value.reset_stats();
+
+    for (channel, traffic) in &stats.sent {
+        log::info!("sent {:?}: {:.0} B/s, {:?}", channel, traffic.bytes as f32 / interval, traffic);
+    }
+
+    for (channel, traffic) in &stats.received {
+        log::info!("received {:?}: {:.0} B/s, {:?}", channel, traffic.bytes as f32 / interval, traffic);
+    }
+
+    for (kind, messages) in &stats.sent_kinds {
+        log::info!("sent {}: {:.1} msg/s", kind, *messages as f32 / interval);
+    }
+
+    for (kind, messages) in &stats.received_kinds {
+        log::info!("received {}: {:.1} msg/s", kind, *messages as f32 / interval);
+    }
+
+    for (peer_id, traffic) in &stats.received_from {
+        log::info!("received from player {:?}: {:?}", handles.handles.get(peer_id), traffic);
+    }
+
+    for handle in handles.handles.values() {
+        if let Some(link) = ping.get(*handle).filter(|link| link.is_measured()) {
+            log::info!(
+                "link to player {}: rtt {:.0} ms, jitter {:.0} ms, loss {:.0}%",
+                handle, link.rtt * 1000., link.jitter * 1000., link.loss * 100.
+            );
+        }
+    }
+
+    log::info!("incoming queue: {} pending, {} at most, budget {} per frame", inbox.pending, inbox.max_pending, inbox.budget);
+    inbox.reset_max();
+}
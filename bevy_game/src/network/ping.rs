@@ -1,136 +1,119 @@
 use std::collections::HashMap;
 
-use bevy::{prelude::{ResMut, Res, Resource}, time::Time};
-use tokio::sync::mpsc;
+use bevy::prelude::{Res, ResMut, Resource};
+use peer::LinkQuality;
 
-use super::{NetMessage, Wrapper};
+use super::{NetHandles, NetSender, Wrapper};
 
 
 const PING_DEFAULT_VALUE: f32 = 0.05; // sec
-//const PING_CONNECT_TIME: f64 = 1.; // sec
-const PING_SEND_TIME: f32 = 3.; // sec
-const PING_WAIT_TIME: f32 = 10.; // sec
-
-#[derive(PartialEq)]
-enum PingState {
-    None,
-    Send(f32),
-    Wait(f32),    
-}
 
+/// Link estimates of a player, from the pings of the network task.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Ping {
-    time: Option<f32>,
+    /// Round trip time, sec.
+    pub rtt: f32,
+    /// Deviation of the round trips, sec.
+    pub jitter: f32,
+    /// Share of lost pings from 0 to 1.
+    pub loss: f32,
+    measured: bool,
 }
 
 impl Ping {
-    pub fn update_time(&mut self, new_time: f32) {
-        let tmp_time = (self.time.unwrap_or(PING_DEFAULT_VALUE)*4. + new_time)/5.;        
-        log::info!("Network Ping receive, old:{:?}, new:{:?}, res:{:?}", self.time, new_time, tmp_time );
-        self.time = Some(tmp_time as f32);
+    fn update(&mut self, link: &LinkQuality) {
+        self.rtt = link.rtt.as_secs_f32();
+        self.jitter = link.jitter.as_secs_f32();
+        self.loss = link.loss as f32;
+        self.measured = link.samples > 0;
     }
 
+    /// One-way latency to the player.
     pub fn get_time(&self) -> f32 {
-        self.time.unwrap_or(PING_DEFAULT_VALUE)
-    }
-    pub fn is_connected(&self) -> bool {
-        self.time.is_some()
+        if self.measured {
+            self.rtt * 0.5
+        } else {
+            PING_DEFAULT_VALUE
+        }
     }
-}
 
-impl Default for Ping {  
-        fn default() -> Self {
-        Self { 
-            time: None, 
-        }
-    } 
+    pub fn is_measured(&self) -> bool {
+        self.measured
+    }
 }
 
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub struct PingList {
-    id: u64,
-    temp: u64, //for changing message
-    state: PingState,
+    connected: bool,
     data: HashMap<usize, Ping>,
 }
 
-impl Default for PingList {  
-    fn default() -> Self {
-        Self { 
-            id: rand::random::<u64>(), 
-            temp: 0,
-            state: PingState::None,//PingState::Connect(PING_CONNECT_TIME*((0.5 + rand::random::<f32>()/f32::MAX) as f64)), 
-            data: HashMap::new(),
-        }
-    } 
-}
-
 impl PingList {
-    pub(crate) fn insert(&mut self, new_handle: usize, ping: Ping) {
-        self.data.insert(new_handle, ping);   
-    }
-    pub(crate) fn receive_pong(&mut self, pong_id: u64, handle: usize, receive_time: f32) {
-        if pong_id != self.id {
-            return;
-        }
-
-        if let PingState::Send(send_time) = self.state {
-            if let Some(ping) = self.data.get_mut(&handle) {
-                ping.update_time((receive_time - send_time)*0.5);           
-            }
-        }
-    }
-    fn update(&mut self, to_server: ResMut<Wrapper<mpsc::Sender<NetMessage>>>, current_time: f32) {
-        if let PingState::None = self.state {
-            return;
-        } else if let PingState::Wait(wait_time) = self.state {
-            if wait_time + PING_WAIT_TIME <= current_time {
-                let _res = to_server.value.try_send(NetMessage::Ping(self.id, self.temp));
-  //              log::info!("Network PingList update Send ping res:{:?}", _res);        
-                self.state = PingState::Send(current_time);
-                self.temp += 1;
-            }
-        } else if let PingState::Send(send_time) = self.state {
-            if send_time + PING_SEND_TIME <= current_time {
-  //              log::info!("Network PingList update set Wait");        
-                self.state = PingState::Wait(current_time);
-            }
-        }
-    }
-
+    /// The first peer is connected.
     pub fn start(&mut self) {
-        if !self.is_connected() {
-            self.state = PingState::Wait(PING_WAIT_TIME*(0.5 + rand::random::<f32>()/f32::MAX));
-        }
+        self.connected = true;
     }
 
-
+    /// One-way latency to the player, a default guess for players reached
+    /// only through the gossip mesh.
     pub fn get_time(&self, handle: usize) -> f32 {
-        if let Some(ping) = self.data.get(&handle) { 
-   //         log::info!("Network PingList get_time time:{:?}", ping.get_time()); 
-            return ping.get_time();
-        }
-        PING_DEFAULT_VALUE
+        self.data.get(&handle).map_or(PING_DEFAULT_VALUE, Ping::get_time)
     }
 
-    pub fn is_connected(&self) -> bool {
-        if let PingState::None = self.state {
-            return false;
-        }
+    pub fn get(&self, handle: usize) -> Option<&Ping> {
+        self.data.get(&handle)
+    }
 
-        true
+    pub fn is_connected(&self) -> bool {
+        self.connected
     }
 
-    pub(crate) fn check_collision_id(&mut self, in_id: u64) {
-        if in_id == self.id { 
-            self.id = rand::random::<u64>();
+    /// Take the current links, players without one left or are reached only through the mesh.
+    fn update(&mut self, links: HashMap<usize, LinkQuality>) {
+        self.data.retain(|handle, _| links.contains_key(handle));
+
+        for (handle, link) in links {
+            self.data.entry(handle).or_default().update(&link);
         }
     }
 }
 
 pub(crate) fn update_ping(
     mut ping: ResMut<PingList>,
-//    to_server: ResMut<mpsc::Sender<NetMessage>>,
-    to_server: ResMut<Wrapper<mpsc::Sender<NetMessage>>>, 
-    time: Res<Time>,) {
-        ping.update(to_server, time.into_inner().elapsed_seconds());
-}
\ No newline at end of file
+    handles: Res<NetHandles>,
+    to_server: Res<Wrapper<NetSender>>,
+) {
+    let links = handles
+        .handles
+        .iter()
+        .filter_map(|(peer_id, handle)| Some((*handle, to_server.value.link_quality(peer_id)?)))
+        .collect();
+
+    ping.update(links);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_players_without_link_are_removed() {
+        let link = LinkQuality {
+            rtt: Duration::from_millis(200),
+            samples: 1,
+            ..Default::default()
+        };
+
+        let mut ping = PingList::default();
+        ping.update(HashMap::from([(1, link), (2, link)]));
+        assert_eq!(ping.get_time(2), 0.1);
+        assert!(ping.get(2).is_some());
+
+        ping.update(HashMap::from([(1, link)]));
+        assert!(ping.get(1).is_some());
+        assert!(ping.get(2).is_none());
+        assert_eq!(ping.get_time(2), PING_DEFAULT_VALUE);
+    }
+}
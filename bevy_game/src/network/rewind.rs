@@ -0,0 +1,206 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+
+use crate::interpolation::Interpolation;
+use crate::player::{LocalHandles, PlayerData, PlayerHandle};
+use crate::tank::TankEntityes;
+
+use super::Authority;
+
+/// Poses older than this are dropped, sec.
+const HISTORY_TIME: f32 = 1.;
+
+/// Farthest the targets are rewound for a shooter, a slower link is not compensated more, sec.
+pub const MAX_REWIND: f32 = 0.5;
+
+/// Box around the body and the wheels of a tank, half sizes in m.
+const TANK_HALF_SIZE: Vec3 = Vec3::new(0.8, 0.5, 0.9);
+
+/// Place of a tank at a time of this peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pose {
+    time: f32,
+    pos: Vec3,
+    rotation: Quat,
+}
+
+/// Recent places of the tanks, so the authority can test a shot against
+/// the targets where the shooter saw them.
+#[derive(Resource, Debug, Default)]
+pub struct PoseHistory {
+    poses: HashMap<PlayerHandle, VecDeque<Pose>>,
+}
+
+impl PoseHistory {
+    pub fn record(&mut self, handle: PlayerHandle, time: f32, pos: Vec3, rotation: Quat) {
+        let poses = self.poses.entry(handle).or_default();
+        if poses.back().is_some_and(|last| last.time >= time) {
+            return;
+        }
+
+        poses.push_back(Pose { time, pos, rotation });
+        while poses.front().is_some_and(|first| first.time < time - HISTORY_TIME) {
+            poses.pop_front();
+        }
+    }
+
+    /// Place of the tank at `time`, the nearest kept one outside of the history.
+    pub fn pose_at(&self, handle: PlayerHandle, time: f32) -> Option<(Vec3, Quat)> {
+        let poses = self.poses.get(&handle)?;
+        let index = poses.partition_point(|pose| pose.time <= time);
+
+        let pose = match (index.checked_sub(1).and_then(|i| poses.get(i)), poses.get(index)) {
+            (Some(prev), Some(next)) => {
+                let t = (time - prev.time) / (next.time - prev.time);
+                Pose {
+                    time,
+                    pos: prev.pos.lerp(next.pos, t),
+                    rotation: prev.rotation.slerp(next.rotation, t),
+                }
+            }
+            (Some(pose), None) | (None, Some(pose)) => *pose,
+            (None, None) => return None,
+        };
+
+        Some((pose.pos, pose.rotation))
+    }
+
+    /// How far the targets are rewound for a shot which arrived `age` after it was fired
+    /// by a shooter who renders the remote tanks `delay` in the past.
+    pub fn rewind(age: f32, delay: f32) -> f32 {
+        (age + delay).clamp(0., MAX_REWIND)
+    }
+
+    /// First tank but the shooter's hit by the shell moving from `from` to `to`,
+    /// with the tanks at their places at `time`.
+    pub fn hit_test(&self, from: Vec3, to: Vec3, radius: f32, time: f32, shooter: PlayerHandle) -> Option<(PlayerHandle, Vec3)> {
+        self.poses
+            .keys()
+            .filter(|handle| **handle != shooter)
+            .filter_map(|handle| {
+                let (pos, rotation) = self.pose_at(*handle, time)?;
+                let inverse = rotation.inverse();
+                let t = segment_box(
+                    inverse * (from - pos),
+                    inverse * (to - pos),
+                    TANK_HALF_SIZE + Vec3::splat(radius),
+                )?;
+
+                Some((*handle, t))
+            })
+            .min_by(|(_, t1), (_, t2)| t1.total_cmp(t2))
+            .map(|(handle, t)| (handle, from.lerp(to, t)))
+    }
+}
+
+/// Part of the segment `a`..`b` where it enters the box around the origin, none for a miss.
+fn segment_box(a: Vec3, b: Vec3, half_size: Vec3) -> Option<f32> {
+    let dir = b - a;
+    let mut t_min = 0f32;
+    let mut t_max = 1f32;
+
+    for i in 0..3 {
+        if dir[i].abs() < f32::EPSILON {
+            if a[i].abs() > half_size[i] {
+                return None;
+            }
+            continue;
+        }
+
+        let t1 = (-half_size[i] - a[i]) / dir[i];
+        let t2 = (half_size[i] - a[i]) / dir[i];
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+
+        if t_min > t_max {
+            return None;
+        }
+    }
+
+    Some(t_min)
+}
+
+/// The host keeps the places of the tanks as it shows them: its own tank now,
+/// the remote ones at the interpolated time they're rendered at.
+pub(crate) fn record_poses(
+    time: Res<Time>,
+    authority: Res<Authority>,
+    interpolation: Res<Interpolation>,
+    local_handles: Res<LocalHandles>,
+    mut history: ResMut<PoseHistory>,
+    query: Query<(&GlobalTransform, &PlayerData), With<TankEntityes>>,
+) {
+    if !authority.is_host() {
+        return;
+    }
+
+    let now = time.elapsed_seconds();
+
+    for (global_transform, player) in query.iter() {
+        let (_scale, rotation, translation) = global_transform.to_scale_rotation_translation();
+        let time = if local_handles.handles.contains(&player.handle) {
+            now
+        } else {
+            interpolation.render_time(now)
+        };
+
+        history.record(player.handle, time, translation, rotation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_test_uses_rewound_pose() {
+        let mut history = PoseHistory::default();
+        history.record(1, 1., Vec3::ZERO, Quat::IDENTITY);
+        history.record(1, 2., Vec3::new(10., 0., 0.), Quat::IDENTITY);
+        history.record(2, 1., Vec3::new(0., 0., 20.), Quat::IDENTITY);
+
+        assert_eq!(history.pose_at(1, 1.5).unwrap().0, Vec3::new(5., 0., 0.));
+        assert_eq!(history.pose_at(1, 3.).unwrap().0, Vec3::new(10., 0., 0.));
+
+        // the shell crosses the place the target had at time 1
+        let from = Vec3::new(0., 0., -5.);
+        let to = Vec3::new(0., 0., 5.);
+        let (handle, point) = history.hit_test(from, to, 0.1, 1., 2).unwrap();
+        assert_eq!(handle, 1);
+        assert!((point.z + 1.).abs() < 1e-4);
+
+        assert_eq!(history.hit_test(from, to, 0.1, 2., 2), None);
+        assert_eq!(history.hit_test(from, to, 0.1, 1., 1), None);
+    }
+
+    #[test]
+    fn test_shooters_hit_target_where_they_saw_it() {
+        // the target drives along x at 10 m/s
+        let mut history = PoseHistory::default();
+        for i in 0..=10 {
+            let time = 10. + i as f32 * 0.1;
+            history.record(1, time, Vec3::new((time - 10.) * 10., 0., 0.), Quat::IDENTITY);
+        }
+
+        let now = 11.;
+        // a near shooter saw the target 0.15 sec ago, a far one 0.35 sec ago
+        let near = PoseHistory::rewind(0.05, 0.1);
+        let far = PoseHistory::rewind(0.25, 0.1);
+        assert!((near - 0.15).abs() < 1e-4);
+        assert!((far - 0.35).abs() < 1e-4);
+
+        let shell = |x: f32| (Vec3::new(x, 0., -5.), Vec3::new(x, 0., 5.));
+
+        let (from, to) = shell(8.5);
+        assert_eq!(history.hit_test(from, to, 0.1, now - near, 2).map(|(handle, _)| handle), Some(1));
+        assert_eq!(history.hit_test(from, to, 0.1, now - far, 3), None);
+
+        let (from, to) = shell(6.5);
+        assert_eq!(history.hit_test(from, to, 0.1, now - far, 3).map(|(handle, _)| handle), Some(1));
+        assert_eq!(history.hit_test(from, to, 0.1, now - near, 2), None);
+
+        // a very slow link isn't compensated past the limit
+        assert_eq!(PoseHistory::rewind(2., 0.1), MAX_REWIND);
+    }
+}
@@ -0,0 +1,843 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+use bevy_rapier3d::plugin::PhysicsStages;
+use bevy_rapier3d::prelude::*;
+use iyes_loopless::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::player::{ControlCannon, ControlFire, ControlMove, ControlTurret, LocalHandles, PlayerData, PlayerHandle};
+use crate::tank::{spawn_shell, update_body_moving, wheel_movement, TankEntityes, TankShotData, WheelData};
+use crate::utils::*;
+use crate::AppState;
+
+use super::{send_to_server, Health, NetMessage, NetSender, Opts, Wrapper};
+
+/// Simulation ticks per second of the match clock.
+const TICKS_PER_SECOND: u64 = 60;
+const TICK_TIME: f32 = 1. / TICKS_PER_SECOND as f32;
+
+/// Most ticks run ahead of the slowest player's inputs.
+const MAX_PREDICTION: u32 = 8;
+
+/// Most ticks simulated in one frame after a stall, resimulated ticks not counted.
+const MAX_TICKS_PER_FRAME: u32 = 4;
+
+/// States kept to roll back to, a later input is too old to be applied.
+const SNAPSHOTS_LEN: usize = 64;
+
+/// Inputs kept per player, about 4 seconds.
+const INPUTS_LEN: usize = 256;
+
+/// Each message repeats this many last inputs for the lost ones.
+const INPUTS_PER_MESSAGE: usize = 8;
+
+/// Confirmed ticks are compared with the other players this often.
+const CHECKSUM_INTERVAL: u32 = 30;
+
+/// Checksums kept to compare with late ones of the other players.
+const CHECKSUMS_LEN: usize = 16;
+
+/// Resolution of the state in the checksum, a smaller drift is no desync, m and rad.
+const CHECKSUM_PRECISION: f32 = 0.01;
+
+/// Input of a player for one tick.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct TickInput {
+    pub movement: Vec2,
+    pub turret: f32,
+    pub cannon: f32,
+    /// A shell is fired in the tick, it's never predicted.
+    pub fire: bool,
+    /// Time the fire key was held, the shell is faster for a longer one, sec.
+    pub charge: f32,
+}
+
+/// Inputs of the ticks from `tick` on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RollbackInputs {
+    pub tick: u32,
+    pub inputs: Vec<TickInput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RollbackChecksum {
+    pub tick: u32,
+    pub checksum: u64,
+}
+
+/// Time the movement keys of a tank are held, it's a part of the simulated state.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Ramp {
+    movement: Vec2,
+    linear_ticks: u32,
+    angular_ticks: u32,
+}
+
+impl Ramp {
+    fn update(&mut self, movement: Vec2) -> Option<Vec2> {
+        if movement.y != self.movement.y {
+            self.linear_ticks = 0;
+        }
+        if movement.x != self.movement.x {
+            self.angular_ticks = 0;
+        }
+
+        self.movement = movement;
+        self.linear_ticks += 1;
+        self.angular_ticks += 1;
+
+        wheel_movement(
+            movement,
+            self.linear_ticks as f32 * TICK_TIME,
+            self.angular_ticks as f32 * TICK_TIME,
+        )
+    }
+}
+
+/// Shell fired by an input in rollback mode.
+#[derive(Component, Debug)]
+struct RollbackShell {
+    tick: u32,
+}
+
+#[derive(Debug, Clone)]
+struct BodyState {
+    entity: Entity,
+    transform: Transform,
+    velocity: Option<Velocity>,
+}
+
+/// State before a tick.
+#[derive(Debug, Clone)]
+struct Snapshot {
+    tick: u32,
+    /// Tanks, obstacles and the shells in flight.
+    bodies: Vec<BodyState>,
+    health: Vec<(Entity, Health)>,
+    ramps: HashMap<PlayerHandle, Ramp>,
+}
+
+/// Rollback mode, chosen by `--rollback`: the players exchange their inputs, every peer
+/// simulates all tanks at fixed ticks of the match clock and resimulates from the tick
+/// of an input which arrived after its prediction.
+#[derive(Resource, Debug)]
+pub struct Rollback {
+    enabled: bool,
+    input_delay: u32,
+    /// Tick being simulated.
+    tick: u32,
+    /// First tick not simulated yet.
+    present: Option<u32>,
+    /// Earliest tick simulated with a wrong prediction.
+    rollback_to: Option<u32>,
+    restore: Option<u32>,
+    local: Option<PlayerHandle>,
+    local_input: TickInput,
+    last_sent: Option<u32>,
+    inputs: HashMap<PlayerHandle, VecDeque<(u32, TickInput)>>,
+    /// Last tick of every player up to which no input is missing.
+    confirmed: HashMap<PlayerHandle, u32>,
+    ramps: HashMap<PlayerHandle, Ramp>,
+    snapshots: VecDeque<Snapshot>,
+    checksums: VecDeque<(u32, u64)>,
+    remote_checksums: Vec<(PlayerHandle, RollbackChecksum)>,
+    out_checksums: Vec<RollbackChecksum>,
+    /// Number of resimulations.
+    pub rollbacks: u32,
+    /// Number of checksums which differed from the ones of the other players.
+    pub desyncs: u32,
+}
+
+impl Rollback {
+    pub fn new(opts: &Opts) -> Self {
+        Self::with(opts.rollback, opts.input_delay)
+    }
+
+    fn with(enabled: bool, input_delay: u32) -> Self {
+        Self {
+            enabled,
+            input_delay,
+            tick: 0,
+            present: None,
+            rollback_to: None,
+            restore: None,
+            local: None,
+            local_input: TickInput::default(),
+            last_sent: None,
+            inputs: HashMap::new(),
+            confirmed: HashMap::new(),
+            ramps: HashMap::new(),
+            snapshots: VecDeque::new(),
+            checksums: VecDeque::new(),
+            remote_checksums: Vec::new(),
+            out_checksums: Vec::new(),
+            rollbacks: 0,
+            desyncs: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Input of the player at the tick, the last known one is repeated as the prediction
+    /// without its shot.
+    fn input(&self, handle: PlayerHandle, tick: u32) -> TickInput {
+        let known = self
+            .inputs
+            .get(&handle)
+            .and_then(|inputs| inputs.iter().rev().find(|(input_tick, _)| *input_tick <= tick));
+
+        match known {
+            Some((input_tick, input)) if *input_tick == tick => *input,
+            Some((_, input)) => TickInput {
+                fire: false,
+                ..*input
+            },
+            None => TickInput::default(),
+        }
+    }
+
+    fn insert_input(&mut self, handle: PlayerHandle, tick: u32, input: TickInput) {
+        let predicted = self.input(handle, tick);
+
+        let inputs = self.inputs.entry(handle).or_default();
+        let index = inputs.partition_point(|(input_tick, _)| *input_tick < tick);
+        if inputs.get(index).is_some_and(|(input_tick, _)| *input_tick == tick) {
+            return;
+        }
+
+        inputs.insert(index, (tick, input));
+        if inputs.len() > INPUTS_LEN {
+            inputs.pop_front();
+        }
+
+        // the first input of a player starts the confirmed ticks
+        let mut confirmed = self.confirmed.get(&handle).copied();
+        for (input_tick, _) in inputs.iter() {
+            match confirmed {
+                None => confirmed = Some(*input_tick),
+                Some(last) if *input_tick <= last => {}
+                Some(last) if *input_tick == last + 1 => confirmed = Some(*input_tick),
+                Some(_) => break,
+            }
+        }
+        if let Some(confirmed) = confirmed {
+            self.confirmed.insert(handle, confirmed);
+        }
+
+        let simulated = self.present.is_some_and(|present| tick < present);
+        if simulated && predicted != input {
+            self.rollback_to = Some(self.rollback_to.map_or(tick, |rollback_to| rollback_to.min(tick)));
+        }
+    }
+
+    pub(crate) fn receive_inputs(&mut self, handle: PlayerHandle, inputs: RollbackInputs) {
+        for (i, input) in inputs.inputs.into_iter().enumerate() {
+            self.insert_input(handle, inputs.tick.wrapping_add(i as u32), input);
+        }
+    }
+
+    pub(crate) fn receive_checksum(&mut self, handle: PlayerHandle, checksum: RollbackChecksum) {
+        let own = self.checksums.iter().find(|(tick, _)| *tick == checksum.tick).map(|(_, own)| *own);
+        match own {
+            Some(own) => self.compare_checksum(handle, checksum, own),
+            None => self.remote_checksums.push((handle, checksum)),
+        }
+    }
+
+    fn compare_checksum(&mut self, handle: PlayerHandle, checksum: RollbackChecksum, own: u64) {
+        if checksum.checksum != own {
+            self.desyncs += 1;
+            log::error!("rollback desync with player {} at tick {}", handle, checksum.tick);
+        }
+    }
+
+    /// Checksum of a confirmed tick, a resimulation of the tick replaces the earlier one.
+    fn record_checksum(&mut self, tick: u32, checksum: u64) {
+        if let Some(own) = self.checksums.iter_mut().find(|(own_tick, _)| *own_tick == tick) {
+            own.1 = checksum;
+            return;
+        }
+
+        self.checksums.push_back((tick, checksum));
+        if self.checksums.len() > CHECKSUMS_LEN {
+            self.checksums.pop_front();
+        }
+
+        for (handle, remote) in std::mem::take(&mut self.remote_checksums) {
+            if remote.tick == tick {
+                self.compare_checksum(handle, remote, checksum);
+            } else if remote.tick > tick {
+                self.remote_checksums.push((handle, remote));
+            }
+        }
+
+        if tick.is_multiple_of(CHECKSUM_INTERVAL) {
+            self.out_checksums.push(RollbackChecksum { tick, checksum });
+        }
+    }
+
+    /// Latest tick with all inputs of the other players, a player who stopped
+    /// sending them holds it back until the prediction limit stops the ticks.
+    fn confirmed(&self) -> Option<u32> {
+        self.present?;
+
+        self.confirmed
+            .iter()
+            .filter(|(handle, _)| Some(**handle) != self.local)
+            .map(|(_, tick)| *tick)
+            .min()
+    }
+
+    /// Number of ticks to run this frame to reach the match clock at `target`,
+    /// starting with the resimulation of the mispredicted ones.
+    fn plan(&mut self, target: u32) -> u32 {
+        let present = *self.present.get_or_insert(target);
+
+        let mut start = present;
+        if let Some(tick) = self.rollback_to.take() {
+            if self.snapshots.iter().any(|snapshot| snapshot.tick == tick) {
+                start = tick;
+                self.restore = Some(tick);
+                self.rollbacks += 1;
+            } else {
+                log::warn!("rollback to tick {} is older than the snapshots", tick);
+            }
+        }
+
+        let limit = self
+            .confirmed()
+            .map_or(target, |confirmed| target.min(confirmed.saturating_add(MAX_PREDICTION)));
+        let end = limit.clamp(present, present + MAX_TICKS_PER_FRAME);
+
+        self.tick = start;
+        end - start
+    }
+
+    /// The local input of a new tick is applied `input_delay` ticks later.
+    fn add_local_input(&mut self, handle: PlayerHandle) {
+        if self.present.is_some_and(|present| self.tick < present) {
+            return;
+        }
+
+        self.local = Some(handle);
+        let tick = self.tick + self.input_delay;
+        self.insert_input(handle, tick, self.local_input);
+        // one shell per press of the fire key
+        self.local_input.fire = false;
+    }
+
+    /// Last local inputs, if there are new ones.
+    fn take_outgoing(&mut self) -> Option<RollbackInputs> {
+        let inputs = self.inputs.get(&self.local?)?;
+        let (last, _) = *inputs.back()?;
+        if self.last_sent == Some(last) {
+            return None;
+        }
+
+        self.last_sent = Some(last);
+
+        let skip = inputs.len().saturating_sub(INPUTS_PER_MESSAGE);
+        let tick = inputs[skip].0;
+        Some(RollbackInputs {
+            tick,
+            inputs: inputs.iter().skip(skip).map(|(_, input)| *input).collect(),
+        })
+    }
+
+    fn take_checksums(&mut self) -> Vec<RollbackChecksum> {
+        std::mem::take(&mut self.out_checksums)
+    }
+
+    fn save(&mut self, snapshot: Snapshot) {
+        while self.snapshots.back().is_some_and(|last| last.tick >= snapshot.tick) {
+            self.snapshots.pop_back();
+        }
+
+        self.snapshots.push_back(snapshot);
+        if self.snapshots.len() > SNAPSHOTS_LEN {
+            self.snapshots.pop_front();
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::with(self.enabled, self.input_delay);
+    }
+}
+
+/// Systems of one tick, run by `run_rollback` as many times as the frame needs.
+#[derive(Resource)]
+struct RollbackSchedule(Schedule);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+enum RollbackStage {
+    Restore,
+    Input,
+    Motors,
+    Finish,
+}
+
+pub struct RollbackPlugin;
+
+/// The tick simulation runs the physics of the rapier plugin itself,
+/// the regular physics step is paused while playing in rollback mode.
+impl Plugin for RollbackPlugin {
+    fn build(&self, app: &mut App) {
+        let mut schedule = Schedule::default();
+        schedule
+            .add_stage(RollbackStage::Restore, SystemStage::single_threaded().with_system(restore_state))
+            .add_stage(
+                RollbackStage::Input,
+                SystemStage::single_threaded()
+                    .with_system(save_state)
+                    .with_system(apply_inputs.after(save_state))
+                    .with_system(fire_shells.after(apply_inputs)),
+            )
+            .add_stage(RollbackStage::Motors, SystemStage::single_threaded().with_system(update_body_moving));
+
+        for stage in [
+            PhysicsStages::SyncBackend,
+            PhysicsStages::StepSimulation,
+            PhysicsStages::Writeback,
+        ] {
+            schedule.add_stage(
+                stage.clone(),
+                SystemStage::parallel().with_system_set(RapierPhysicsPlugin::<NoUserData>::get_systems(stage)),
+            );
+        }
+
+        schedule.add_stage(RollbackStage::Finish, SystemStage::single_threaded().with_system(finish_tick));
+
+        app.insert_resource(RollbackSchedule(schedule))
+            .add_system_set(
+                SystemSet::on_enter(AppState::Playing).with_system(setup_rollback.run_if(is_rollback)),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(collect_local_input.run_if(is_rollback).before("run_rollback"))
+                    .with_system(run_rollback.label("run_rollback"))
+                    .with_system(send_rollback.run_if(is_rollback).after("run_rollback")),
+            )
+            .add_system_set(
+                SystemSet::on_exit(AppState::Playing).with_system(cleanup_rollback.run_if(is_rollback)),
+            );
+    }
+}
+
+fn is_rollback(rollback: Res<Rollback>) -> bool {
+    rollback.is_enabled()
+}
+
+pub fn is_not_rollback(rollback: Res<Rollback>) -> bool {
+    !rollback.is_enabled()
+}
+
+fn setup_rollback(mut config: ResMut<RapierConfiguration>) {
+    config.timestep_mode = TimestepMode::Fixed {
+        dt: TICK_TIME,
+        substeps: 1,
+    };
+    config.physics_pipeline_active = false;
+}
+
+fn cleanup_rollback(mut config: ResMut<RapierConfiguration>, mut rollback: ResMut<Rollback>) {
+    config.physics_pipeline_active = true;
+    log::info!("rollback {} resimulations, {} desyncs", rollback.rollbacks, rollback.desyncs);
+    rollback.reset();
+}
+
+/// Input of the local tank for the next new tick, a shot waits for the tick.
+fn collect_local_input(
+    mut rollback: ResMut<Rollback>,
+    move_query: Query<&ControlMove>,
+    turret_query: Query<&Transform, With<ControlTurret>>,
+    cannon_query: Query<&Transform, With<ControlCannon>>,
+    mut fire_query: Query<&mut ControlFire>,
+) {
+    let (control, turret, cannon, mut fire) = match (
+        move_query.get_single(),
+        turret_query.get_single(),
+        cannon_query.get_single(),
+        fire_query.get_single_mut(),
+    ) {
+        (Ok(control), Ok(turret), Ok(cannon), Ok(fire)) => (control, turret, cannon, fire),
+        _ => return,
+    };
+
+    let mut input = TickInput {
+        movement: control.movement,
+        turret: get_angle_y(&turret.rotation),
+        cannon: cannon.rotation.to_euler(EulerRot::XYZ).0,
+        fire: rollback.local_input.fire,
+        charge: rollback.local_input.charge,
+    };
+
+    if fire.is_shot {
+        fire.is_shot = false;
+        input.fire = true;
+        input.charge = fire.time;
+    }
+
+    rollback.local_input = input;
+}
+
+/// Run the ticks of this frame on the match clock, there are no ticks until the clock is synchronized.
+fn run_rollback(world: &mut World) {
+    if !world.resource::<Rollback>().is_enabled() {
+        return;
+    }
+
+    let clock = match world.get_resource::<Wrapper<NetSender>>() {
+        Some(to_server) if to_server.value.clock().is_synced() => to_server.value.clock().now(),
+        _ => return,
+    };
+    let target = (clock * TICKS_PER_SECOND / 1_000_000) as u32;
+
+    let ticks = world.resource_mut::<Rollback>().plan(target);
+    if ticks == 0 {
+        return;
+    }
+
+    world.resource_mut::<RapierConfiguration>().physics_pipeline_active = true;
+    world.resource_scope(|world, mut schedule: Mut<RollbackSchedule>| {
+        for _ in 0..ticks {
+            schedule.0.run(world);
+        }
+    });
+    world.resource_mut::<RapierConfiguration>().physics_pipeline_active = false;
+}
+
+/// Put the bodies and the health back to the snapshot of the tick to resimulate.
+/// Shells fired since are despawned, the inputs fire them again; shells which exploded
+/// since stay gone, their explosions were shown and sent already.
+fn restore_state(
+    mut commands: Commands,
+    mut rollback: ResMut<Rollback>,
+    mut query: Query<(&mut Transform, &mut GlobalTransform, Option<&mut Velocity>)>,
+    mut health_query: Query<&mut Health>,
+    shell_query: Query<(Entity, &RollbackShell)>,
+) {
+    if rollback.restore != Some(rollback.tick) {
+        return;
+    }
+
+    rollback.restore = None;
+
+    let tick = rollback.tick;
+    let snapshot = match rollback.snapshots.iter().find(|snapshot| snapshot.tick == tick) {
+        Some(snapshot) => snapshot.clone(),
+        None => return,
+    };
+
+    for body in &snapshot.bodies {
+        if let Ok((mut transform, mut global_transform, velocity)) = query.get_mut(body.entity) {
+            *transform = body.transform;
+            *global_transform = GlobalTransform::from(body.transform);
+
+            if let (Some(mut velocity), Some(saved)) = (velocity, body.velocity) {
+                *velocity = saved;
+            }
+        }
+    }
+
+    for (entity, saved) in &snapshot.health {
+        if let Ok(mut health) = health_query.get_mut(*entity) {
+            *health = *saved;
+        }
+    }
+
+    for (entity, shell) in shell_query.iter() {
+        if shell.tick >= tick {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+
+    rollback.ramps = snapshot.ramps;
+}
+
+fn save_state(
+    mut rollback: ResMut<Rollback>,
+    query: Query<(Entity, &Transform, Option<&Velocity>, &RigidBody)>,
+    health_query: Query<(Entity, &Health)>,
+) {
+    let bodies = query
+        .iter()
+        .filter(|(_, _, _, body)| **body != RigidBody::Fixed)
+        .map(|(entity, transform, velocity, _)| BodyState {
+            entity,
+            transform: *transform,
+            velocity: velocity.copied(),
+        })
+        .collect();
+
+    let snapshot = Snapshot {
+        tick: rollback.tick,
+        bodies,
+        health: health_query.iter().map(|(entity, health)| (entity, *health)).collect(),
+        ramps: rollback.ramps.clone(),
+    };
+
+    rollback.save(snapshot);
+}
+
+/// Drive every tank by its player's input of the tick.
+fn apply_inputs(
+    mut rollback: ResMut<Rollback>,
+    local_handles: Res<LocalHandles>,
+    tank_query: Query<(&PlayerData, &TankEntityes)>,
+    control_query: Query<(), With<ControlTurret>>,
+    mut wheel_query: Query<&mut WheelData>,
+    mut transform_query: Query<&mut Transform, With<PlayerData>>,
+) {
+    if let Some(handle) = local_handles.handles.first() {
+        rollback.add_local_input(*handle);
+    }
+
+    let tick = rollback.tick;
+
+    for (player, entityes) in tank_query.iter() {
+        let input = rollback.input(player.handle, tick);
+        let movement = rollback.ramps.entry(player.handle).or_default().update(input.movement);
+
+        for wheel in &entityes.wheels {
+            if let Ok(mut wheel_data) = wheel_query.get_mut(*wheel) {
+                wheel_data.movement = movement;
+            }
+        }
+
+        // the local turret and cannon are turned by the player, they don't affect the physics
+        if control_query.contains(entityes.turret) {
+            continue;
+        }
+
+        if let Ok(mut transform) = transform_query.get_mut(entityes.turret) {
+            transform.rotation = set_angle_y(input.turret);
+        }
+
+        if let Ok(mut transform) = transform_query.get_mut(entityes.cannon) {
+            transform.rotation = Quat::from_axis_angle(Vec3::X, input.cannon);
+        }
+    }
+}
+
+/// Fire the shells of the tick's inputs from the cannons turned as in the inputs,
+/// the local cannon is turned by the player between the ticks.
+fn fire_shells(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    rollback: Res<Rollback>,
+    tank_query: Query<(&PlayerData, &TankEntityes)>,
+    shot_query: Query<&TankShotData>,
+    transform_query: Query<(&Transform, Option<&Parent>)>,
+) {
+    let tick = rollback.tick;
+
+    for (player, entityes) in tank_query.iter() {
+        let input = rollback.input(player.handle, tick);
+        if !input.fire {
+            continue;
+        }
+
+        let (data, muzzle) = match (shot_query.get(entityes.fire_point), muzzle(entityes, &input, &transform_query)) {
+            (Ok(data), Some(muzzle)) => (data, muzzle),
+            _ => continue,
+        };
+
+        let vel = muzzle.forward() * data.shot_speed(input.charge);
+        let shell = spawn_shell(&mut commands, &mut meshes, &mut materials, data, player.handle, muzzle.translation, vel);
+        commands.entity(shell).insert(RollbackShell { tick });
+    }
+}
+
+/// Place of the fire point from the local transforms, the global ones are a frame old.
+fn muzzle(entityes: &TankEntityes, input: &TickInput, query: &Query<(&Transform, Option<&Parent>)>) -> Option<Transform> {
+    let mut entity = entityes.fire_point;
+    let mut muzzle = Transform::IDENTITY;
+
+    loop {
+        let (transform, parent) = query.get(entity).ok()?;
+
+        let mut local = *transform;
+        if entity == entityes.turret {
+            local.rotation = set_angle_y(input.turret);
+        } else if entity == entityes.cannon {
+            local.rotation = Quat::from_axis_angle(Vec3::X, input.cannon);
+        }
+        muzzle = local.mul_transform(muzzle);
+
+        match parent {
+            Some(parent) => entity = parent.get(),
+            None => return Some(muzzle),
+        }
+    }
+}
+
+/// Checksum of the tanks after a tick with the inputs of all players.
+fn finish_tick(mut rollback: ResMut<Rollback>, query: Query<&Transform, With<TankEntityes>>) {
+    let tick = rollback.tick;
+
+    if rollback.confirmed().is_none_or(|confirmed| tick <= confirmed) {
+        // the handles differ between the peers, so the tanks are summed in any order
+        let checksum = query.iter().fold(0u64, |checksum, transform| {
+            checksum.wrapping_add(tank_checksum(transform.translation, get_angle_y(&transform.rotation)))
+        });
+
+        rollback.record_checksum(tick, checksum);
+    }
+
+    rollback.tick += 1;
+    rollback.present = rollback.present.map(|present| present.max(rollback.tick));
+}
+
+fn tank_checksum(pos: Vec3, angle: f32) -> u64 {
+    let quantize = |value: f32| (value / CHECKSUM_PRECISION).round() as i32 as u32 as u64;
+
+    // FNV-1a, the same on every peer
+    [quantize(pos.x), quantize(pos.y), quantize(pos.z), quantize(angle)]
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, value| (hash ^ value).wrapping_mul(0x100000001b3))
+}
+
+fn send_rollback(mut rollback: ResMut<Rollback>, to_server: Option<Res<Wrapper<NetSender>>>) {
+    let to_server = match to_server {
+        Some(to_server) => to_server,
+        None => return,
+    };
+
+    if let Some(inputs) = rollback.take_outgoing() {
+        if send_to_server(&to_server.value, NetMessage::RollbackInputs(inputs)).is_err() {
+            // the next message repeats them
+            log::warn!("rollback inputs not sent");
+        }
+    }
+
+    for checksum in rollback.take_checksums() {
+        if send_to_server(&to_server.value, NetMessage::RollbackChecksum(checksum)).is_err() {
+            log::warn!("rollback checksum of tick {} not sent", checksum.tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORWARD: TickInput = TickInput {
+        movement: Vec2::Y,
+        turret: 0.,
+        cannon: 0.,
+        fire: false,
+        charge: 0.,
+    };
+
+    fn snapshot(tick: u32) -> Snapshot {
+        Snapshot {
+            tick,
+            bodies: Vec::new(),
+            health: Vec::new(),
+            ramps: HashMap::new(),
+        }
+    }
+
+    fn inputs(tick: u32, inputs: &[TickInput]) -> RollbackInputs {
+        RollbackInputs {
+            tick,
+            inputs: inputs.to_vec(),
+        }
+    }
+
+    /// Rollback which simulated up to tick 12 with the input of tick 10 of player 1.
+    fn simulated() -> Rollback {
+        let mut rollback = Rollback::with(true, 0);
+        rollback.receive_inputs(1, inputs(10, &[FORWARD]));
+        assert_eq!(rollback.plan(12), 0);
+        rollback.save(snapshot(11));
+        rollback
+    }
+
+    #[test]
+    fn test_predicted_input_does_not_roll_back() {
+        let mut rollback = simulated();
+
+        rollback.receive_inputs(1, inputs(11, &[FORWARD]));
+
+        assert_eq!(rollback.plan(12), 0);
+        assert_eq!(rollback.rollbacks, 0);
+    }
+
+    #[test]
+    fn test_late_input_rolls_back() {
+        let mut rollback = simulated();
+
+        rollback.receive_inputs(1, inputs(11, &[TickInput::default()]));
+
+        assert_eq!(rollback.plan(12), 1);
+        assert_eq!(rollback.rollbacks, 1);
+    }
+
+    #[test]
+    fn test_shot_is_not_predicted() {
+        let mut rollback = simulated();
+        let fire = TickInput {
+            fire: true,
+            charge: 0.5,
+            ..FORWARD
+        };
+
+        rollback.receive_inputs(1, inputs(11, &[fire]));
+
+        assert!(rollback.input(1, 11).fire);
+        assert!(!rollback.input(1, 12).fire);
+        assert_eq!(rollback.input(1, 12).movement, Vec2::Y);
+        assert_eq!(rollback.plan(12), 1);
+    }
+
+    #[test]
+    fn test_missing_input_holds_back_confirmed_tick() {
+        let mut rollback = simulated();
+
+        rollback.receive_inputs(1, inputs(12, &[FORWARD]));
+        assert_eq!(rollback.confirmed(), Some(10));
+
+        rollback.receive_inputs(1, inputs(11, &[FORWARD]));
+        assert_eq!(rollback.confirmed(), Some(12));
+    }
+
+    #[test]
+    fn test_silent_player_holds_back_confirmed_tick() {
+        let mut rollback = simulated();
+
+        rollback.receive_inputs(2, inputs(10, &[FORWARD; 200]));
+
+        assert_eq!(rollback.confirmed(), Some(10));
+    }
+
+    #[test]
+    fn test_different_checksum_is_desync() {
+        let mut rollback = Rollback::with(true, 0);
+
+        rollback.record_checksum(30, 1);
+        rollback.receive_checksum(1, RollbackChecksum { tick: 30, checksum: 1 });
+        assert_eq!(rollback.desyncs, 0);
+
+        rollback.receive_checksum(2, RollbackChecksum { tick: 60, checksum: 2 });
+        rollback.record_checksum(60, 3);
+        assert_eq!(rollback.desyncs, 1);
+        assert_eq!(rollback.take_checksums().len(), 2);
+    }
+
+    #[test]
+    fn test_resimulated_checksum_replaces_old_one() {
+        let mut rollback = Rollback::with(true, 0);
+
+        rollback.record_checksum(30, 1);
+        rollback.record_checksum(30, 2);
+        rollback.receive_checksum(1, RollbackChecksum { tick: 30, checksum: 2 });
+
+        assert_eq!(rollback.desyncs, 0);
+        assert_eq!(rollback.take_checksums().len(), 1);
+    }
+}
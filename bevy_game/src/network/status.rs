@@ -0,0 +1,182 @@
+use bevy::prelude::*;
+use peer::Reachability;
+
+use iyes_loopless::prelude::*;
+
+use crate::cleanup::cleanup_system;
+use crate::loading::FontAssets;
+use crate::menu::{is_play_online, MenuCamera};
+use crate::AppState;
+
+use super::MapTransfer;
+
+/// Network state shown to the player.
+#[derive(Resource, Debug)]
+pub struct NetStatus {
+    pub reachability: Reachability,
+}
+
+impl Default for NetStatus {
+    fn default() -> Self {
+        Self {
+            reachability: Reachability::Unknown,
+        }
+    }
+}
+
+impl NetStatus {
+    fn reachability_text(&self) -> String {
+        match &self.reachability {
+            Reachability::Public(addr) => format!("Reachable directly at {}", addr),
+            Reachability::Private => "Behind NAT, connections go through the relay".to_string(),
+            Reachability::Unknown => "Checking NAT...".to_string(),
+        }
+    }
+}
+
+#[derive(Component)]
+struct StatusScreen;
+
+#[derive(Component)]
+struct ReachabilityText;
+
+/// Progress or failure of the host's map, a failed transfer keeps the player here.
+#[derive(Component)]
+struct MapText;
+
+/// Reachability in the corner of an online match, the first NAT probe
+/// often finishes after the connecting screen is gone.
+#[derive(Component)]
+struct StatusOverlay;
+
+pub struct StatusPlugin;
+
+/// Connection screen, drawn during `AppState::Connecting`, and the reachability during the match.
+impl Plugin for StatusPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<NetStatus>()
+            .add_system_set(SystemSet::on_enter(AppState::Connecting).with_system(setup_status))
+            .add_system_set(
+                SystemSet::on_update(AppState::Connecting)
+                    .with_system(update_status)
+                    .with_system(update_map_status),
+            )
+            .add_system_set(
+                SystemSet::on_exit(AppState::Connecting)
+                    .with_system(cleanup_system::<StatusScreen>)
+                    .with_system(cleanup_system::<MenuCamera>),
+            )
+            .add_system_set(
+                SystemSet::on_enter(AppState::Playing).with_system(setup_status_overlay.run_if(is_play_online)),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::Playing).with_system(update_status.run_if(is_play_online)),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::Playing).with_system(cleanup_system::<StatusOverlay>));
+    }
+}
+
+fn setup_status(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    status: Res<NetStatus>,
+    map: Res<MapTransfer>,
+) {
+    let title_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 30.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+
+    let status_style = TextStyle {
+        font_size: 20.0,
+        color: Color::rgb(0.7, 0.7, 0.7),
+        ..title_style.clone()
+    };
+
+    commands.spawn(NodeBundle {
+        style: Style {
+            size: Size::new(Val::Auto, Val::Auto),
+            margin: UiRect::all(Val::Auto),
+            align_self: AlignSelf::Center,
+            flex_direction: FlexDirection::Column,
+            align_items: AlignItems::Center,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .insert(StatusScreen)
+    .with_children(|screen| {
+        screen.spawn(TextBundle {
+            text: Text::from_section("Connecting...", title_style),
+            ..Default::default()
+        });
+
+        screen.spawn(TextBundle {
+            text: Text::from_section(status.reachability_text(), status_style.clone()),
+            ..Default::default()
+        })
+        .insert(ReachabilityText);
+
+        screen.spawn(TextBundle {
+            text: Text::from_section(map.status_text().unwrap_or_default(), status_style),
+            ..Default::default()
+        })
+        .insert(MapText);
+    });
+}
+
+fn setup_status_overlay(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    status: Res<NetStatus>,
+) {
+    let style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 16.0,
+        color: Color::rgb(0.7, 0.7, 0.7),
+    };
+
+    commands.spawn(TextBundle {
+        text: Text::from_section(status.reachability_text(), style),
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                right: Val::Px(10.),
+                top: Val::Px(10.),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .insert(StatusOverlay)
+    .insert(ReachabilityText);
+}
+
+fn update_status(
+    status: Res<NetStatus>,
+    mut query: Query<&mut Text, With<ReachabilityText>>,
+) {
+    if !status.is_changed() {
+        return;
+    }
+
+    for mut text in query.iter_mut() {
+        text.sections[0].value = status.reachability_text();
+    }
+}
+
+fn update_map_status(
+    map: Res<MapTransfer>,
+    mut query: Query<&mut Text, With<MapText>>,
+) {
+    if !map.is_changed() {
+        return;
+    }
+
+    for mut text in query.iter_mut() {
+        text.sections[0].value = map.status_text().unwrap_or_default();
+    }
+}
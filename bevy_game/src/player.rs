@@ -55,9 +55,10 @@ pub struct ControlCannon {
     pub speed: f32,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default)]
 #[repr(u16)]
 pub enum Actions {
+    #[default]
     Up,
     Down,
     Left,
@@ -74,12 +75,6 @@ pub enum Actions {
 unsafe impl Send for Actions {}
 unsafe impl Sync for Actions {}
 
-impl Default for Actions {
-    fn default() -> Self {
-        Actions::Up
-    }
-}
-
 impl TryFrom<u16> for Actions {
     type Error = &'static str;
     fn try_from(code: u16) -> Result<Self, Self::Error> {
@@ -1,12 +1,13 @@
 use crate::explosion::add_explosion;
 use crate::game::{COLLISION_MISSILE, COLLISION_UNIT, COLLISION_ENVIRONMENT, COLLISION_TERRAIN};
 use crate::game::GameMessage;
-use crate::game::InMesVec;
+use crate::game::{InMes, InMesVec};
 use crate::game::OutGameMessages;
 use crate::menu::is_play_offline;
 use crate::menu::is_play_online;
-use crate::network::PingList;
+use crate::network::{Authority, Exploded, PoseHistory};
 use crate::player::*;
+use crate::tank::TankShotData;
 use crate::terrain::get_pos_on_ground;
 use crate::AppState;
 use bevy::prelude::shape::UVSphere;
@@ -26,6 +27,24 @@ pub struct ShotData {
     pub pos: Vec3,
     pub vel: Vec3,
     pub radius: f32,
+    /// Delay the shooter renders the remote tanks with, sec.
+    pub delay: f32,
+}
+
+/// Place of a remote shell at its last lag-compensated hit test on the host.
+#[derive(Component, Debug)]
+pub struct ShotTrace {
+    pos: Vec3,
+    radius: f32,
+    /// The targets are tested this far in the past during the whole flight, sec.
+    rewind: f32,
+}
+
+impl ShotTrace {
+    /// Time of the targets the shooter sees while the shell is flying.
+    fn view_time(&self, now: f32) -> f32 {
+        now - self.rewind
+    }
 }
 
 #[derive(Component, Resource)]
@@ -33,6 +52,8 @@ pub struct ShotExplosionData {
     timer: Timer,
     pub explosion_radius: f32,
     pub explosion_force: f32,
+    /// The shell hit already, it's despawned at the end of the stage.
+    exploded: bool,
 }
 
 impl ShotExplosionData {
@@ -41,6 +62,7 @@ impl ShotExplosionData {
             timer: Timer::new(Duration::from_secs_f32(live_max_time), TimerMode::Once),
             explosion_radius: 10.*explosion_force.powf(0.5),
             explosion_force,
+            exploded: false,
         }
     }
 }
@@ -57,8 +79,9 @@ impl Plugin for ShotPlugin {
         */
         let update_system_set = SystemSet::on_update(AppState::Playing)
             .with_system(create_shot_from_net.run_if(is_play_online))
-            .with_system(process_shots_game_net.run_if(is_play_online))
-            .with_system(handle_explosion_events_net.run_if(is_play_online))            
+            .with_system(process_shots_game_net.run_if(is_play_online).after("lagged_hits_net"))
+            .with_system(process_lagged_hits_net.run_if(is_play_online).label("lagged_hits_net").before("explosion_events_net"))
+            .with_system(handle_explosion_events_net.run_if(is_play_online).label("explosion_events_net"))            
             .with_system(process_shots_game_local.run_if(is_play_offline))
             .with_system(handle_explosion_events_local.run_if(is_play_offline));
         //   let after_system_set = SystemSet::on_update(AppState::Playing)
@@ -76,25 +99,32 @@ impl Plugin for ShotPlugin {
 
 pub fn create_shot_from_net(
     mut commands: Commands,
+    time: Res<Time>,
+    mut authority: ResMut<Authority>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    ping: Res<PingList>,
     mut input: ResMut<InMesVec<ShotData>>,
 ) {
-    for (player, data) in input.data.iter_mut() {
+    for (player, InMes { data, age }) in input.data.iter_mut() {
         if !data.is_shot {
             continue;
         }
 
         data.is_shot = false;
 
-        //TODO add compensation of ping: delta pos:shot_action.vel*ping.get_time(player.handle)
-        //apply gravity to velosity
-        let shot_pos = data.pos + data.vel * ping.get_time(*player);
-        let shot_vel = data.vel - Vec3::Y * 9.8 * ping.get_time(*player);
+        // the host explodes the shells of the other players too, but only shots after the reload
+        if authority.is_host() && !authority.accept_shot(*player, time.elapsed_seconds() - *age) {
+            log::warn!("Shot create_shot_from_net rejected shot of player:{}", player);
+            continue;
+        }
+
+        // move the shot along its flight by the time passed since it was fired
+        let age = *age;
+        let shot_pos = data.pos + data.vel * age - Vec3::Y * 4.9 * age * age;
+        let shot_vel = data.vel - Vec3::Y * 9.8 * age;
 
-        commands
-            .spawn_bundle(PbrBundle {
+        let shot = commands
+            .spawn(PbrBundle {
                 mesh: meshes.add(Mesh::from(UVSphere {
                     radius: data.radius,
                     sectors: 8,
@@ -112,10 +142,10 @@ pub fn create_shot_from_net(
                 ..default()
             })
 //            .insert(ShotExplosionData::new(data.shot_live_max_time, data.explosion_force))
-            .insert(PlayerData{handle: player.clone()})
+            .insert(PlayerData{handle: *player})
             .insert(bevy_rapier3d::prelude::RigidBody::Dynamic)
             .insert(bevy_rapier3d::prelude::Collider::ball(data.radius))
-            //                .insert_bundle(collider)
+            //                .insert(collider)
             .insert(bevy_rapier3d::prelude::ActiveEvents::COLLISION_EVENTS)
             .insert(Restitution::coefficient(0.01))
             .insert(Friction::coefficient(1.0))
@@ -135,23 +165,105 @@ pub fn create_shot_from_net(
             ))
             .insert(bevy_rapier3d::prelude::ActiveHooks::FILTER_CONTACT_PAIRS)
 //          .insert(CustomFilterTag::GroupShot)
-            ;
+            .id();
+
+        if authority.is_host() {
+            let shot_data = TankShotData::init();
+            commands
+                .entity(shot)
+                .insert(ShotExplosionData::new(shot_data.shot_live_max_time, shot_data.explosion_force))
+                // the flight before the shot arrived is tested on the first frame
+                .insert(ShotTrace {
+                    pos: data.pos,
+                    radius: data.radius,
+                    rewind: PoseHistory::rewind(age, data.delay),
+                });
+        }
+    }
+}
+
+/// Explosion of a shell decided by this peer, shown here and sent to the other players.
+fn explode_shot(
+    commands: &mut Commands,
+    rapier_context: &Res<RapierContext>,
+    output: &mut OutGameMessages<GameMessage>,
+    exploded: &mut EventWriter<Exploded>,
+    explosion: Exploded,
+    shooter: PlayerHandle,
+) {
+    log::info!("Shot explode_shot pos:{:?}", explosion.pos);
+    add_explosion(
+        commands,
+        explosion.pos,
+        explosion.force,
+        explosion.radius,
+        shooter,
+        rapier_context,
+    );
+
+    output.data.push(GameMessage::from(ExplosionData {
+        pos: explosion.pos,
+        force: explosion.force,
+        radius: explosion.radius,
+    }));
+    exploded.send(explosion);
+}
+
+/// The host tests the shells of the other players against the tanks where the shooters saw them,
+/// a hit there explodes the shell even if the tank has moved on here.
+fn process_lagged_hits_net(
+    mut commands: Commands,
+    time: Res<Time>,
+    history: Res<PoseHistory>,
+    rapier_context: Res<RapierContext>,
+    mut query: Query<(Entity, &Transform, &mut ShotExplosionData, &PlayerData, &mut ShotTrace)>,
+    mut output: ResMut<OutGameMessages<GameMessage>>,
+    mut exploded: EventWriter<Exploded>,
+) {
+    for (entity, transform, mut shot_data, player, mut trace) in query.iter_mut() {
+        if shot_data.exploded {
+            continue;
+        }
+
+        let pos = transform.translation;
+        let view_time = trace.view_time(time.elapsed_seconds());
+
+        match history.hit_test(trace.pos, pos, trace.radius, view_time, player.handle) {
+            Some((target, hit_pos)) => {
+                log::info!("Shot process_lagged_hits_net player:{} hit tank:{}", player.handle, target);
+                let explosion = Exploded {
+                    pos: hit_pos,
+                    force: shot_data.explosion_force,
+                    radius: shot_data.explosion_radius,
+                    view_time: Some(view_time),
+                };
+
+                explode_shot(&mut commands, &rapier_context, &mut output, &mut exploded, explosion, player.handle);
+                shot_data.exploded = true;
+                commands.entity(entity).despawn_recursive();
+            }
+            None => trace.pos = pos,
+        }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_explosion_events_net(
     mut commands: Commands,
+    time: Res<Time>,
     //    mut meshes: ResMut<Assets<Mesh>>,
     //    mut materials: ResMut<Assets<StandardMaterial>>,
     local_handles: Res<LocalHandles>,
+    authority: Res<Authority>,
     mut events: EventReader<bevy_rapier3d::prelude::CollisionEvent>,
-    query: Query<(&GlobalTransform, Entity, &ShotExplosionData, &PlayerData)>,
+    mut query: Query<(&GlobalTransform, Entity, &mut ShotExplosionData, &PlayerData, Option<&ShotTrace>)>,
     mut output: ResMut<OutGameMessages<GameMessage>>,
+    mut exploded: EventWriter<Exploded>,
     rapier_context: Res<RapierContext>,
 ) {
     for event in events.iter() {
         if let bevy_rapier3d::prelude::CollisionEvent::Started(e1, e2, _f) = event {
-            for (global_transform, entity, shot_data, player) in query.iter() {
+            for (global_transform, entity, mut shot_data, player, trace) in query.iter_mut() {
                 /*           match event {
                                 bevy_rapier3d::prelude::CollisionEvent::Started(e1, e2, f)
                                 | bevy_rapier3d::prelude::CollisionEvent::Stopped(e1, e2, f)
@@ -160,32 +272,25 @@ fn handle_explosion_events_net(
                         //                continue;
                         //            }
                 */
-                if e1 == &entity || e2 == &entity {
+                if (e1 == &entity || e2 == &entity) && !shot_data.exploded {
                     //                println!("handle_explosion_events  translation: {:?}", global_transform.translation());
-                    if *local_handles.handles.first().unwrap() == player.handle {
+                    if authority.decides_hits(player.handle, *local_handles.handles.first().unwrap()) {
                         let pos = Vec3::new(
                             global_transform.translation().x,
                             global_transform.translation().y + 0.1,
                             global_transform.translation().z,
                         );
 
-                        log::info!("Shot handle_explosion_events pos:{:?}", pos);
-                        add_explosion(
-                            &mut commands,
-                            pos,
-                            shot_data.explosion_force,
-                            shot_data.explosion_radius,
-                            player.handle,
-                            &rapier_context,
-                        );
-
-                        output.data.push(GameMessage::from(ExplosionData {
+                        let explosion = Exploded {
                             pos,
                             force: shot_data.explosion_force,
                             radius: shot_data.explosion_radius,
-                        }));
+                            view_time: trace.map(|trace| trace.view_time(time.elapsed_seconds())),
+                        };
+                        explode_shot(&mut commands, &rapier_context, &mut output, &mut exploded, explosion, player.handle);
                     }
 
+                    shot_data.exploded = true;
                     commands.entity(entity).despawn_recursive();
                 }
             }
@@ -240,7 +345,7 @@ fn handle_explosion_events_local(
 fn process_shots_game_local(
     mut commands: Commands,
     time: Res<Time>,
-    rapier_context: Res<RapierContext>,
+    _rapier_context: Res<RapierContext>,
     mut query: Query<(
         Entity,
         &GlobalTransform,
@@ -251,7 +356,7 @@ fn process_shots_game_local(
 ) {
     //info!("remove_shots");
 
-    for (entity, global_transform, mut shot_data, collider, player) in query.iter_mut() {
+    for (entity, _global_transform, mut shot_data, _collider, _player) in query.iter_mut() {
         // timers gotta be ticked, to work
         shot_data.timer.tick(time.delta());
 
@@ -313,22 +418,30 @@ fn process_shots_game_local(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_shots_game_net(
     mut commands: Commands,
     time: Res<Time>,
     rapier_context: Res<RapierContext>,
     local_handles: Res<LocalHandles>,
+    authority: Res<Authority>,
     mut query: Query<(
         Entity,
         &GlobalTransform,
         &mut ShotExplosionData,
         &PlayerData,
+        Option<&ShotTrace>,
     )>,
     mut output: ResMut<OutGameMessages<GameMessage>>,
+    mut exploded: EventWriter<Exploded>,
 ) {
     //info!("remove_shots");
 
-    for (entity, global_transform, mut shot_data, player) in query.iter_mut() {
+    for (entity, global_transform, mut shot_data, player, trace) in query.iter_mut() {
+        if shot_data.exploded {
+            continue;
+        }
+
         // timers gotta be ticked, to work
         shot_data.timer.tick(time.delta());
 
@@ -357,24 +470,17 @@ fn process_shots_game_net(
             pos.y += 0.1;
             //            println!("remove_shots get_pos_on_ground pos: {:?}  translation: {:?}", pos, global_transform.translation());
 
-            if *local_handles.handles.first().unwrap() == player.handle {
-                log::info!("Shot remove_shots pos:{:?}", pos);
-                add_explosion(
-                    &mut commands,
-                    pos,
-                    shot_data.explosion_force,
-                    shot_data.explosion_radius,
-                    player.handle,
-                    &rapier_context,
-                );
-
-                output.data.push(GameMessage::from(ExplosionData {
+            if authority.decides_hits(player.handle, *local_handles.handles.first().unwrap()) {
+                let explosion = Exploded {
                     pos,
                     force: shot_data.explosion_force,
                     radius: shot_data.explosion_radius,
-                }));
+                    view_time: trace.map(|trace| trace.view_time(time.elapsed_seconds())),
+                };
+                explode_shot(&mut commands, &rapier_context, &mut output, &mut exploded, explosion, player.handle);
             }
 
+            shot_data.exploded = true;
             commands.entity(entity).despawn_recursive();
 
             continue;
@@ -387,3 +493,55 @@ fn process_shots_game_net(
         // commands.entity(entity).despawn_recursive();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+    use bevy_rapier3d::rapier::geometry::CollisionEventFlags;
+    use clap::Parser;
+
+    use super::*;
+    use crate::network::Opts;
+
+    #[test]
+    fn test_rewound_hit_and_collision_explode_once() {
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(RapierContext::default());
+        world.insert_resource(LocalHandles::default());
+        world.insert_resource(OutGameMessages::<GameMessage>::default());
+        world.insert_resource(Authority::new(&Opts::parse_from(["bb", "--relay-address", "http://127.0.0.1:1", "--host"])));
+        world.init_resource::<Events<Exploded>>();
+        world.init_resource::<Events<CollisionEvent>>();
+
+        // the tank of player 1 is where the shell of player 0 is now
+        let mut history = PoseHistory::default();
+        history.record(1, 0., Vec3::ZERO, Quat::IDENTITY);
+        world.insert_resource(history);
+
+        let shell = world
+            .spawn((
+                Transform::default(),
+                GlobalTransform::default(),
+                ShotExplosionData::new(10., 1.),
+                PlayerData { handle: 0 },
+                ShotTrace { pos: Vec3::new(-2., 0., 0.), radius: 0.1, rewind: 0. },
+            ))
+            .id();
+        let tank = world.spawn_empty().id();
+
+        world
+            .resource_mut::<Events<CollisionEvent>>()
+            .send(CollisionEvent::Started(shell, tank, CollisionEventFlags::empty()));
+
+        let mut stage = SystemStage::single_threaded()
+            .with_system(process_lagged_hits_net)
+            .with_system(handle_explosion_events_net.after(process_lagged_hits_net))
+            .with_system(process_shots_game_net.after(process_lagged_hits_net));
+        stage.run(&mut world);
+
+        assert_eq!(world.resource::<Events<Exploded>>().len(), 1);
+        assert_eq!(world.resource::<OutGameMessages<GameMessage>>().data.len(), 1);
+        assert!(world.get_entity(shell).is_none());
+    }
+}
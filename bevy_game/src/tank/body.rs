@@ -4,9 +4,9 @@ use serde::{Deserialize, Serialize};
 
 //use super::{TankLastPos, TankMoveTarget};
 use crate::game::*;
-use crate::network::PingList;
+use crate::interpolation::{Interpolate, Interpolation, Snapshots};
 use crate::player::{ControlMove, PlayerData};
-use crate::tank::{TankEntityes, WheelData};
+use crate::tank::{InputBuffer, InputFrame, TankEntityes, WheelData};
 use crate::utils::*;
 
 
@@ -27,11 +27,13 @@ pub struct Data {
     pub angle: f32,
     pub linvel: Vec2,
     pub angvel: f32,
+    /// Local input applied right after this state, for the corrections of the authority.
+    pub input_seq: u32,
 }
 
 impl Data {
     pub fn is_moved(&self) -> bool {
-        return self.movement.length_squared() > VEL_EPSILON_QRT 
+        self.movement.length_squared() > VEL_EPSILON_QRT 
                || self.linvel.length_squared() > VEL_EPSILON_QRT 
                || self.angvel.abs() > ANGLE_SPEED_EPSILON
     }
@@ -51,278 +53,114 @@ impl Data {
     }
 }
 
+impl Interpolate for Data {
+    fn interpolate(&self, next: &Self, t: f32) -> Self {
+        let near = if t < 0.5 { self } else { next };
+
+        Self {
+            movement: near.movement,
+            delta_time_linear: near.delta_time_linear,
+            delta_time_angular: near.delta_time_angular,
+            pos: self.pos.lerp(next.pos, t),
+            angle: normalize_angle(self.angle + delta_angle(next.angle, self.angle) * t),
+            linvel: self.linvel.lerp(next.linvel, t),
+            angvel: self.angvel + (next.angvel - self.angvel) * t,
+            input_seq: near.input_seq,
+        }
+    }
+
+    fn extrapolate(&self, delta_time: f32) -> Self {
+        Self {
+            pos: self.pos + self.linvel * delta_time,
+            angle: normalize_angle(self.angle + self.angvel * delta_time),
+            ..*self
+        }
+    }
+}
+
+/// Place remote tanks at the interpolated state, the velocity is kept for the physics between frames.
 pub fn update_body_position_from_net(
-    mut commands: Commands,
     time: Res<Time>,
-    ping: Res<PingList>,
+    interpolation: Res<Interpolation>,
     mut data_query: Query<(
-        &GlobalTransform,
         &mut Velocity,
-        //       ChangeTrackers<State<Message<Data>>>,
-        &MesState<Data>,
-        //      &mut ExternalImpulse,
-        &mut ExternalForce,
+        &mut Snapshots<Data>,
         &mut Sleeping,
         &TankEntityes,
-        &PlayerData,
-    )>,
+    ), With<PlayerData>>,
+    mut transforms_query: Query<&mut Transform, With<PlayerData>>,
     mut wheel_data_query: Query<&mut WheelData>,
-//    mut tank_move_target_query: Query<&mut Transform, (With<TankMoveTarget>, Without<TankLastPos>)>,
-//    mut tank_last_pos_query: Query<&mut Transform, (With<TankLastPos>, Without<TankMoveTarget>)>,
 ) {
-    for (
-        global_transform,
-        _vel,
-        state,
-        /*tank_control_data, mut forces, mut impulse,*/
-        mut _force,
-        mut sleeping,
-        entityes,
-        player,
-    ) in data_query.iter_mut()
-    {
-        let (_scale, rotation, translation) = global_transform.to_scale_rotation_translation();
-        let data = state.data;
-
-  /*       if let Ok(mut target_transform) = tank_last_pos_query.get_single_mut() {
-            target_transform.translation =
-                Vec3::new(state.data.pos.x, translation.y, state.data.pos.y);
-        }
-*/
-        let mut movement = Vec2::ZERO;
-
-        // if stop only correct pos and angle
-        if data.movement.length_squared() < VEL_EPSILON_QRT {
-            let mut net_pos = v2_3(data.pos + data.linvel * 0.15);
-            net_pos.y = translation.y;
-            let delta_pos = net_pos - translation;
-
-            let net_angle = data.angle + data.angvel * 0.15;
-            let delta_angle = delta_angle(net_angle, get_angle_y(&rotation));
-
-            if delta_pos.length_squared() > POS_EPSILON_QRT 
-                || delta_angle.abs() > ANGLE_EPSILON {
-
-                let shift_data = crate::tank::TankShift {
-                    rotation: delta_angle,
-                    velosity: delta_pos,
-                    time: 1.,
-                };
-    
-                commands.entity(entityes.body).insert(shift_data.clone());
-            } else {
-                commands
-                    .entity(entityes.body)
-                    .remove::<crate::tank::TankShift>();
-            }
-        } else {
-            //try to compensate the ping delay 
-            let state_delta_time_data = (time.elapsed_seconds() - state.time) as f32;// + ping.get_time(player.handle) * 0.7;
-            let ping_time = ping.get_time(player.handle);
-            let delta_time_linear = data.get_delta_time_linear() + state_delta_time_data + ping_time;
-            let delta_time_angular = data.get_delta_time_angular() + state_delta_time_data + ping_time;//).min(START_DELAY);
-            let max_time_delay = (START_DELAY - ping_time).max(0.01);
-
-            let move_y = data.movement.y
-            * delta_time_linear.min(max_time_delay)
-            * WHEEL_SPEED_MAX
-            / max_time_delay;
-
-            let move_x = data.movement.x
-                * delta_time_angular.min(max_time_delay)
-                * WHEEL_SPEED_MAX
-                / max_time_delay;
-
-            movement = Vec2::new(move_x, move_y);
-
-
-            if (data.movement.y.abs() > VEL_EPSILON && data.get_delta_time_linear() >= START_DELAY) 
-            || (data.movement.x.abs() > VEL_EPSILON && data.get_delta_time_angular() >= START_DELAY) {
-                let mut net_pos = v2_3(data.pos + data.linvel * ping_time);
-                net_pos.y = translation.y;
-                let delta_pos = net_pos - translation;
-
-                let net_angle = data.angle + data.angvel * ping_time;
-                let delta_angle = delta_angle(net_angle, get_angle_y(&rotation));
-
-                if delta_pos.length_squared() > POS_EPSILON_QRT 
-                    || delta_angle.abs() > ANGLE_EPSILON {
-
-                    let shift_data = crate::tank::TankShift {
-                        rotation: delta_angle,
-                        velosity: delta_pos,
-                        time: 1.,
-                    };
-        
-                    commands.entity(entityes.body).insert(shift_data.clone());
-                } else {
-                    commands
-                        .entity(entityes.body)
-                        .remove::<crate::tank::TankShift>();
-                }
-
-                /*if let Ok(mut target_transform) = tank_move_target_query.get_single_mut() {
-                    target_transform.translation =
-                        Vec3::new(current_net_pos.x, translation.y, current_net_pos.y);
-                }*/
-            }
-
-        }
-
+    let render_time = interpolation.render_time(time.elapsed_seconds());
 
-/*
-        if delta_time_linear > START_DELAY {
-            let delta_pos_delay = 
-        }
-
-        if delta_time_angular > START_DELAY {
-
-        }     
-
-        let linear_speed1 = -data.movement.x * ANGULAR_SPEED_MAX;
-        let linear_speed2 = -data.movement.x * ANGULAR_SPEED_MAX;
-
-        let rotation_speed1 = -data.movement.x * ANGULAR_SPEED_MAX;
-        let rotation_speed2 = -data.movement.x * ANGULAR_SPEED_MAX;
-
-        let extr_angle = normalize_angle(data.angle + rotation_speed * delta_time);
-
-        let extr_dir = Quat::from_axis_angle(Vec3::Y, extr_angle);
-
-        let current_net_pos = data.pos + data.vel * delta_time;
-
-        let extr_time = 2.0;
-
-        let mut extr_net_pos = v2_3(current_net_pos + data.vel * extr_time);
-        extr_net_pos.y = translation.y;
-
-        let delta_pos = extr_net_pos - translation;
-
-        let local_delta_pos = dir_to_local(&extr_dir, &delta_pos);
-
-        let delta_vel = v2_3(data.vel) - vel.linvel;
-        let local_delta_vel = dir_to_local(&extr_dir, &delta_vel);
+    for (mut vel, mut snapshots, mut sleeping, entityes) in data_query.iter_mut() {
+        let data = match snapshots.sample(render_time, interpolation.max_extrapolation) {
+            Some(data) => data,
+            None => continue,
+        };
 
-        if delta_pos
-            .dot(Transform::from_rotation(extr_dir).forward())
-            .abs()
-            > 0.8
-        {
-            let y_coorection_value = 1. * local_delta_pos.z;
+        if let Ok(mut transform) = transforms_query.get_mut(entityes.body) {
+            let old = *transform;
 
-            force.force = 15.
-                * global_transform.back()
-                * y_coorection_value.abs().min(2.)
-                * y_coorection_value.signum()
-                * time.delta_seconds();
+            transform.translation = v2_3(data.pos);
+            transform.translation.y = old.translation.y;
+            transform.rotation = set_angle_y(data.angle);
+            let new = *transform;
 
-            movement.y += 0.5 * y_coorection_value.abs().min(0.5) * y_coorection_value.signum();
+            // the wheels follow the body, the joints keep them in place
+            for entity in entityes.axles.iter().chain(&entityes.wheels) {
+                if let Ok(mut transform) = transforms_query.get_mut(*entity) {
+                    move_with_body(&mut transform, &old, &new);
+                }
+            }
         }
 
-        //        movement.y = 0.;
-
-        //      let extr_angle = data.angle - data.movement.x * 0.03 * delta_time;
-
-        let delta_angle = delta_angle(extr_angle, get_angle_y(&rotation));
-
-        let vel_x_value = -0.4 * local_delta_vel.x.abs().min(0.4) * local_delta_vel.x.signum();
-
-        let pos_x_value = -2.0 * local_delta_pos.x.abs().min(0.2) * local_delta_pos.x.signum();
-
-        let target_angle = normalize_angle(extr_angle + vel_x_value + pos_x_value);
-
-        log::info!(
-            "extr_angle:{}, angle_value:{}, vel_value:{}, pos_value:{}, target_angle:{}",
-            extr_angle,
-            delta_angle,
-            vel_x_value,
-            pos_x_value,
-            target_angle
-        );
-
-        movement.x -= vel_x_value + pos_x_value + delta_angle; // + mov_value;
+        vel.linvel.x = data.linvel.x;
+        vel.linvel.z = data.linvel.y;
+        vel.angvel.y = data.angvel;
 
-        if local_delta_pos.x.abs() > POS_EPSILON_QRT {
-            let correction_pos = if movement.y != 0. {
-                Vec3::new(local_delta_pos.x, 0., local_delta_pos.y / 3.)
-            } else {
-                Vec3::new(local_delta_pos.x, 0., local_delta_pos.y)
-            };
+        // the wheels only turn, the position comes from the snapshots
+        let movement = wheel_movement(data.movement, data.get_delta_time_linear(), data.get_delta_time_angular());
 
-            let correction_pos = dir_to_global(&extr_dir, &correction_pos);
+        sleeping.linear_threshold = 2.;
+        sleeping.angular_threshold = 10.;
+        sleeping.sleeping = movement.is_none();
 
-            let correction_rot = if movement.x != 0. {
-                delta_angle / 3.
-            } else {
-                delta_angle
-            };
-
-            let shift_data = crate::tank::TankShift {
-                rotation: correction_rot / extr_time,
-                velosity: correction_pos / extr_time,
-                time: 1.,
-            };
-
-            commands.entity(entityes.body).insert(shift_data.clone());
-        } else {
-            commands
-                .entity(entityes.body)
-                .remove::<crate::tank::TankShift>();
-        }
-
-        if let Ok(mut target_transform) = tank_move_target_query.get_single_mut() {
-            target_transform.translation =
-                Vec3::new(current_net_pos.x, translation.y, current_net_pos.y);
+        for wheel in &entityes.wheels {
+            if let Ok(mut wheel_data) = wheel_data_query.get_component_mut::<WheelData>(*wheel) {
+                wheel_data.movement = movement;
+            }
         }
-*/
-       /*
-                net_vel 0 current_vel -1  local 1    mult  1
-
-        data.vel -2.4935524 current_vel -1.7 delta_vel: 0.7641309 local_delta_vel:0.88171524
-         vel_mult:0.25
-
-         delta_pos: 1.4875035
-         extr_net_pos.x:-5.74324 translation.x:-7.2307434 local_delta_pos.x:3.3352304
-         pos_mult:0.09,
-
-         data.movement.x:0
-         mov_mult:-0
-
-        data.angle:0.0059203263  calculated_angle:0.34592032 current:0.35462776
-
-
-        data.angle:-0.48985457
-
-        extr_net_pos.x:11.34357 translation.x:9.625186 local_delta_pos.x:1.7202746 data.movement.x:0 vel_mult:0.25, pos_mult:-0.09, mov_mult:-0, angle:-0.32985458 current:0.0005708073
-         data.vel: 0.50456065  delta_vel:0.5120836  local_delta_vel:0.5126514
-         delta_pos: 1.7183838 local_delta_pos:1.7202746  angle:-0.32985458
+    }
+}
 
-         old_angle: 0.0005708073  target_angle: -0.32985458 delta_angle: -0.33042538  torque: -27.583492
-        */
-        //        log::info!("update_body_position delta_pos: {}; tmp_impulse: {}; current_dir: {}; from_net.dir: {}; torque: {}", delta_pos, tmp_impulse, current_body_dir, data.angle, torque);
+/// Move a part attached to the body as the body moved from `old` to `new`,
+/// it turns about the body origin with the body.
+pub fn move_with_body(part: &mut Transform, old: &Transform, new: &Transform) {
+    let rotation = new.rotation * old.rotation.inverse();
 
-        let wheel_data_movement = if movement.length_squared() > 0.1 {
-            sleeping.linear_threshold = 2.;
-            sleeping.angular_threshold = 10.;
-            sleeping.sleeping = false;
+    part.translation = new.translation + rotation * (part.translation - old.translation);
+    part.rotation = (rotation * part.rotation).normalize();
+}
 
-            Some(movement)
-        } else {
-            sleeping.linear_threshold = 2.;
-            sleeping.angular_threshold = 10.;
-            sleeping.sleeping = true;
-            //          sleeping.default();
-            None
-        };
+/// Speed of the wheels after the movement keys were held for these times, none for a standing tank.
+pub fn wheel_movement(movement: Vec2, time_linear: f32, time_angular: f32) -> Option<Vec2> {
+    let move_y = movement.y * time_linear.min(START_DELAY) * WHEEL_SPEED_MAX / START_DELAY;
+    let move_x = movement.x * time_angular.min(START_DELAY) * WHEEL_SPEED_MAX / START_DELAY;
 
-        for wheel in &entityes.wheels {
-            if let Ok(mut wheel_data) = wheel_data_query.get_component_mut::<WheelData>(*wheel) {
-                wheel_data.movement = wheel_data_movement.clone();
+    if move_y.abs() > VEL_EPSILON || move_x.abs() > VEL_EPSILON {
+        Some(Vec2::new(move_x, move_y))
+    } else {
+        None
+    }
+}
 
-                //           println!("player prep_wheel_input, ok");
-            }
-        }
-        //       }
+/// Speed of the wheels of this frame, released keys slow the wheels down in `STOP_DELAY`.
+pub fn brake_wheels(old: Option<Vec2>, new: Option<Vec2>, delta_time: f32) -> Option<Vec2> {
+    match (old, new) {
+        (_, Some(new)) => Some(new),
+        (Some(old), None) if old.length_squared() > VEL_EPSILON_QRT => Some(old * (1. - delta_time / STOP_DELAY)),
+        _ => None,
     }
 }
 
@@ -342,6 +180,7 @@ pub fn update_player_body_control(
     //        &mut ExternalForce,
     mut out_data_state: ResMut<OutMessageState<Data>>,
     mut output: ResMut<OutGameMessages<GameMessage>>,
+    mut inputs: ResMut<InputBuffer>,
     mut wheel_data_query: Query<&mut WheelData>,
 ) {
     if query.is_empty() {
@@ -366,24 +205,20 @@ pub fn update_player_body_control(
     let new_linvel = Vec2::new(vel.linvel.x, vel.linvel.z);
     let new_angvel = vel.angvel.y;
 
-    let delta_time_linear = (time.elapsed_seconds() - control.time_linear) as f32;
-    let delta_time_angular = (time.elapsed_seconds() - control.time_angular) as f32;
+    let delta_time_linear = time.elapsed_seconds() - control.time_linear;
+    let delta_time_angular = time.elapsed_seconds() - control.time_angular;
 
-    let move_y = control.movement.y
-        * delta_time_linear.min(START_DELAY)
-        * WHEEL_SPEED_MAX
-        / START_DELAY;
+    let input_seq = inputs.record(InputFrame {
+        pos: new_pos,
+        angle: new_dir,
+        movement: control.movement,
+        time_linear: delta_time_linear,
+        time_angular: delta_time_angular,
+        delta_time: time.delta_seconds(),
+        ..Default::default()
+    });
 
-    let move_x = control.movement.x
-        * delta_time_angular.min(START_DELAY)
-        * WHEEL_SPEED_MAX
-        / START_DELAY;
-
-    let wheel_data_movement = if move_y.abs() > VEL_EPSILON || move_x.abs() > VEL_EPSILON {
-        Some(Vec2::new(move_x, move_y))
-    } else {
-        None
-    };
+    let wheel_data_movement = wheel_movement(control.movement, delta_time_linear, delta_time_angular);
 
     let is_moved = wheel_data_movement.is_some()
         || vel.linvel.length_squared() >= VEL_EPSILON_QRT
@@ -409,6 +244,7 @@ pub fn update_player_body_control(
         out_data_state.old_data.angle = new_dir;
         out_data_state.old_data.linvel = Vec2::new(vel.linvel.x, vel.linvel.z);
         out_data_state.old_data.angvel = vel.angvel.y;
+        out_data_state.old_data.input_seq = input_seq;
 
         output.data.push(GameMessage::from(out_data_state.old_data));
         out_data_state.delta_time = 0.;
@@ -429,19 +265,32 @@ pub fn update_player_body_control(
 
     for wheel in &entityes.wheels {
         if let Ok(mut wheel_data) = wheel_data_query.get_component_mut::<WheelData>(*wheel) {
-            if wheel_data.movement != wheel_data_movement {
-                if wheel_data_movement.is_some() {
-                    wheel_data.movement = wheel_data_movement.clone();
-                } else if let Some(mut movement) = wheel_data.movement {
-                    if movement.length_squared() > VEL_EPSILON_QRT {
-                        movement = movement * (1. - time.delta_seconds()/STOP_DELAY);                        
-                        wheel_data.movement = Some(movement);
-                    } else {
-                        wheel_data.movement = wheel_data_movement;
-                    }
-                }
+            let movement = brake_wheels(wheel_data.movement, wheel_data_movement, time.delta_seconds());
+            if wheel_data.movement != movement {
+                wheel_data.movement = movement;
                 //           println!("player prep_wheel_input, ok");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    #[test]
+    fn test_parts_turn_with_body() {
+        let old = Transform::from_xyz(1., 0., 1.);
+        let new = Transform::from_xyz(2., 0., 1.).with_rotation(set_angle_y(FRAC_PI_2));
+
+        // a wheel on the right side of the body
+        let mut wheel = Transform::from_xyz(2., -0.5, 1.);
+        move_with_body(&mut wheel, &old, &new);
+
+        let expected = new.translation + new.rotation * Vec3::new(1., -0.5, 0.);
+        assert!(wheel.translation.abs_diff_eq(expected, 1e-5));
+        assert!(wheel.rotation.abs_diff_eq(new.rotation, 1e-5));
+    }
+}
@@ -1,4 +1,3 @@
-use std::collections::LinkedList;
 use bevy::prelude::*;
 use bevy_rapier3d::{prelude::*, rapier::prelude::JointAxis};
 //use bevy_rapier3d::{prelude::*, rapier::{prelude::*, self}};
@@ -83,9 +82,9 @@ impl VehicleConfig {
 
 pub fn create_body(
     body: Entity,
-    mut commands: &mut Commands,
+    commands: &mut Commands,
     body_pos: Vec3,
-    body_angle: f32,
+    _body_angle: f32,
     vehicle_cfg: VehicleConfig,
 //    collision_groups: CollisionGroups,
 //    solver_groups: SolverGroups,
@@ -99,7 +98,7 @@ pub fn create_body(
     add_components_to_body(
         body,
         vehicle_cfg.body_half_size,
-        &mut commands,
+        commands,
         CollisionGroups::new(
             unsafe { Group::from_bits_unchecked(COLLISION_UNIT)},
             unsafe { Group::from_bits_unchecked(COLLISION_ALL)},
@@ -137,7 +136,7 @@ pub fn create_body(
             &vehicle_cfg,
             wheel_collision_group,
             wheel_solver_group,
-            &mut commands,
+            commands,
         );
 
         axles.push(axle);
@@ -157,7 +156,7 @@ pub fn create_body(
             &vehicle_cfg,
             wheel_collision_group,
             wheel_solver_group,
-            &mut commands,
+            commands,
         );
 
         axles.push(axle);
@@ -181,7 +180,7 @@ pub fn create_body(
             &vehicle_cfg,
             wheel_collision_group,
             wheel_solver_group,
-            &mut commands,
+            commands,
         );
 
         axles.push(axle);
@@ -205,7 +204,7 @@ pub fn create_body(
             &vehicle_cfg,
             wheel_collision_group,
             wheel_solver_group,
-            &mut commands,
+            commands,
         );
 
         axles.push(axle);
@@ -225,7 +224,7 @@ pub fn create_body(
             &vehicle_cfg,
             wheel_collision_group,
             wheel_solver_group,
-            &mut commands,
+            commands,
         );
 
         axles.push(axle);
@@ -249,7 +248,7 @@ pub fn create_body(
             &vehicle_cfg,
             wheel_collision_group,
             wheel_solver_group,
-            &mut commands,
+            commands,
         );
 
         axles.push(axle);
@@ -304,7 +303,7 @@ fn add_components_to_body(
         })
         //		.insert(ColliderDebugRender::default())
         .insert(NameComponent {
-            name: format!("Body"),
+            name: "Body".to_string(),
         })
         .insert(collision_groups)
         .insert(solver_groups)
@@ -332,7 +331,7 @@ fn spawn_attached_wheel(
     vehicle_cfg: &VehicleConfig,
     collision_groups: CollisionGroups,
     solver_groups: SolverGroups,
-    mut commands: &mut Commands,
+    commands: &mut Commands,
 ) -> (Entity, Entity) {
     let wheel_offset = Vec3::X * vehicle_cfg.wheel_offset * main_offset.x.signum();
     let axle_pos = body_pos + main_offset;
@@ -342,13 +341,13 @@ fn spawn_attached_wheel(
         vehicle_cfg.axle_half_size,
 //        collision_groups,
 //        solver_groups,
-        &mut commands,
+        commands,
     );
 
     let mut anchor1 = main_offset;
     let mut anchor2 = Vec3::ZERO;
 
-    spawn_axle_joint(body, axle, anchor1, anchor2, &mut commands);
+    spawn_axle_joint(body, axle, anchor1, anchor2, commands);
 
 
     let wheel_pos = axle_pos + wheel_offset;
@@ -364,7 +363,7 @@ fn spawn_attached_wheel(
         friction,
         collision_groups,
         solver_groups,
-        &mut commands,
+        commands,
     );
 
     anchor1 = wheel_offset;
@@ -379,7 +378,7 @@ fn spawn_attached_wheel(
         wheel,
         anchor1,
         anchor2,
-        &mut commands,
+        commands,
     );
 
     //	(axle_joint, wheel_joint, wheel)
@@ -405,7 +404,7 @@ fn spawn_axle(
     };
 
     commands
-        .spawn_bundle(bundle)
+        .spawn(bundle)
         .insert(RigidBody::Dynamic)
         //		.insert(LockedAxes::ROTATION_LOCKED | LockedAxes::TRANSLATION_LOCKED_X | LockedAxes::TRANSLATION_LOCKED_Z )
         //		.insert(Collider::cuboid(half_size, half_size*3., half_size))
@@ -462,7 +461,7 @@ fn spawn_wheel(
         .insert(NameComponent {
             name: format!("{} Wheel", prefix),
         })
-        .insert_bundle(bundle)
+        .insert(bundle)
         .insert(RigidBody::Dynamic)
         .with_children(|parent| {
             parent
@@ -602,8 +601,6 @@ pub fn update_body_moving(
                 //			joint.data.set_motor_velocity(JointAxis::AngX, 0., 200.);
                 //			joint.data.set_limits(JointAxis::AngX, [f32::MIN, f32::MAX]);
             }
-        } else {
-            
         }
     }
 }
@@ -6,7 +6,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::utils::*;
 use crate::game::*;
-use crate::network::PingList;
+use crate::interpolation::{Interpolate, Interpolation, Snapshots};
 //use crate::network::PingList;
 use crate::player::{ControlCannon, PlayerData};
 
@@ -17,42 +17,36 @@ pub struct Data {
     pub angle: f32,
 }
 
-pub fn update_cannon_rotation_from_net(
-    time: Res<Time>,
-    ping: Res<PingList>,
-    mut query: Query<(&mut Transform, &MesState<Data>, &PlayerData)>,
-) {
-    for (mut transform, state, player) in query.iter_mut() {
-        let data = state.data;
-        let old_angle = transform.rotation.to_euler(EulerRot::XYZ).0;
+/// Largest elevation and depression of the cannon, rad.
+const CANNON_ANGLE_MAX: f32 = 0.7;
 
-        let mut new_angle = if data.speed != 0. {
-            let delta_time = (time.elapsed_seconds() - state.time) as f32 + ping.get_time(player.handle)*0.5;
-            normalize_angle(data.angle + data.speed * delta_time)
-        } else {
-            data.angle
-        };
-
-
-/*        let mut new_angle = calc_angle(
-            data.angle,
-            old_angle,
-            data.speed,
-            ping.get_time(player.handle),
-        );
-*/
-        if new_angle < -0.7 {
-            new_angle = -0.7;
+impl Interpolate for Data {
+    fn interpolate(&self, next: &Self, t: f32) -> Self {
+        Self {
+            speed: self.speed + (next.speed - self.speed) * t,
+            angle: self.angle + (next.angle - self.angle) * t,
         }
+    }
 
-        if new_angle > 0.7 {
-            new_angle = 0.7;
+    fn extrapolate(&self, delta_time: f32) -> Self {
+        Self {
+            speed: self.speed,
+            angle: (self.angle + self.speed * delta_time).clamp(-CANNON_ANGLE_MAX, CANNON_ANGLE_MAX),
         }
+    }
+}
 
-        //         dbg![cross, dot, dot3, move_angle.angle_between(Game_transform.forward())];
+pub fn update_cannon_rotation_from_net(
+    time: Res<Time>,
+    interpolation: Res<Interpolation>,
+    mut query: Query<(&mut Transform, &mut Snapshots<Data>), With<PlayerData>>,
+) {
+    let render_time = interpolation.render_time(time.elapsed_seconds());
 
-        if new_angle != old_angle {
-            transform.rotation = Quat::from_axis_angle(Vec3::X, new_angle);
+    for (mut transform, mut snapshots) in query.iter_mut() {
+        if let Some(data) = snapshots.sample(render_time, interpolation.max_extrapolation) {
+            let angle = data.angle.clamp(-CANNON_ANGLE_MAX, CANNON_ANGLE_MAX);
+            transform.rotation = Quat::from_axis_angle(Vec3::X, angle);
         }
     }
 }
@@ -83,7 +77,7 @@ pub fn update_player_cannon_rotation(
 
     let rot_speed = 0.3 * PI * rotation;
     let old_angle = transform.rotation.to_euler(EulerRot::XYZ).0;
-    let new_angle = normalize_angle(old_angle + rot_speed * time.delta_seconds()).max(-0.7).min(0.7);
+    let new_angle = normalize_angle(old_angle + rot_speed * time.delta_seconds()).clamp(-CANNON_ANGLE_MAX, CANNON_ANGLE_MAX);
 
     transform.rotation = Quat::from_axis_angle(Vec3::X, new_angle);
 
@@ -173,7 +167,7 @@ pub fn update_cannon_shot(
         control.is_shot = false;
 
         commands
-            .spawn_bundle(PbrBundle {
+            .spawn(PbrBundle {
                 mesh: meshes.add(Mesh::from(UVSphere {
                     radius: 0.1,
                     sectors: 8,
@@ -194,7 +188,7 @@ pub fn update_cannon_shot(
             .insert(player.clone())
             .insert(bevy_rapier3d::prelude::RigidBody::Dynamic)
             .insert(bevy_rapier3d::prelude::Collider::ball(0.02))
-            //                .insert_bundle(collider)
+            //                .insert(collider)
             .insert(bevy_rapier3d::prelude::ActiveEvents::COLLISION_EVENTS)
             .insert(Restitution::coefficient(0.01))
             .insert(Friction::coefficient(1.0))
@@ -13,6 +13,7 @@ use crate::utils::*;
 use crate::{
     game::{set_network_control, set_player_control, MesState},
     menu::is_play_online,
+    network::is_not_rollback,
     player::LocalHandles,
     terrain::get_pos_on_ground,
     AppState,
@@ -21,17 +22,23 @@ use crate::{
 mod body;
 mod body_physics;
 mod cannon;
+mod prediction;
 mod shot;
 mod turret;
 
 use body::*;
 use body_physics::*;
 use cannon::*;
+use prediction::*;
 use shot::*;
 use turret::*;
 
 pub use body::Data as TankBodyData;
+pub use body::wheel_movement;
+pub use body_physics::{update_body_moving, WheelData};
 pub use cannon::Data as CannonRotation;
+pub use prediction::{CorrectionData, InputBuffer, InputFrame};
+pub use shot::spawn_shell;
 pub use turret::Data as TurretRotation;
 
 
@@ -45,7 +52,7 @@ pub struct TankShotData {
 }
 
 impl TankShotData {
-    fn init() -> Self {
+    pub fn init() -> Self {
         Self {
             radius: 0.1,
             shot_speed_min: 10.,
@@ -97,17 +104,11 @@ pub struct NewTank {
     pub angle: f32,
 }
 
-#[derive(Debug, Resource)]
+#[derive(Debug, Resource, Default)]
 pub struct NewTanksData {
     pub vector: Vec<NewTank>,
 }
 
-impl Default for NewTanksData {
-    fn default() -> Self {
-        Self { vector: vec![] }
-    }
-}
-
 pub struct TankPlugin;
 
 //#[derive(Component, Debug)]
@@ -122,7 +123,8 @@ impl Plugin for TankPlugin {
             .with_system(update_body_position_from_net.run_if(is_play_online))
             .with_system(update_turret_rotation_from_net.run_if(is_play_online))
             .with_system(update_cannon_rotation_from_net.run_if(is_play_online))
-            .with_system(update_player_body_control.before(update_body_moving))
+            .with_system(correct_player_body)
+            .with_system(update_player_body_control.after(correct_player_body).before(update_body_moving))
             .with_system(
                 update_body_moving
                     .after(update_player_body_control)
@@ -140,7 +142,8 @@ impl Plugin for TankPlugin {
                     .before(create_player_cannon_shot),
             )
             .with_system(update_cannon_debug_line.after(update_player_cannon_rotation))
-            .with_system(create_player_cannon_shot.after(update_player_cannon_rotation));
+            // in rollback mode the shots are a part of the tick inputs
+            .with_system(create_player_cannon_shot.run_if(is_not_rollback));
 
             let after_system_set = SystemSet::on_update(AppState::Playing)
             .with_system(tank_place)            
@@ -148,6 +151,9 @@ impl Plugin for TankPlugin {
 
 
         app.init_resource::<NewTanksData>()
+            .init_resource::<InputBuffer>()
+            .init_resource::<ReplaySchedule>()
+            .init_resource::<ReplayStep>()
             .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(setup))
             .add_system_set_to_stage(CoreStage::PreUpdate, before_system_set)
             .add_system_set_to_stage(CoreStage::PostUpdate, after_system_set)
@@ -164,9 +170,9 @@ fn is_create_tanks(data: Res<NewTanksData>) -> bool {
 }
 
 fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+    _commands: Commands,
+    _meshes: ResMut<Assets<Mesh>>,
+    _materials: ResMut<Assets<StandardMaterial>>,
     //   asset_server: Res<AssetServer>,
 ) {
     println!("Tank setup");
@@ -288,7 +294,7 @@ pub fn process_spawn_tanks(
 }
 
 fn create_debug_tank(
-    mut commands: &mut Commands,
+    commands: &mut Commands,
     player_handle: usize,
     pos: Vec3,
     angle: f32,
@@ -306,7 +312,7 @@ fn create_debug_tank(
         })
         .id();
 
-    let (body, axles, wheels) = create_body(body, &mut commands, pos, angle, config);
+    let (body, axles, wheels) = create_body(body, commands, pos, angle, config);
 
     /*    commands
             .entity(body)
@@ -383,7 +389,7 @@ fn create_debug_tank(
 }
 
 fn create_tank(
-    mut commands: &mut Commands,
+    commands: &mut Commands,
     //    asset_server: &Res<AssetServer>,
     player_handle: usize,
     pos: Vec3,
@@ -411,7 +417,7 @@ fn create_tank(
         })
         .id();
 
-    let (body, axles, wheels) = create_body(body, &mut commands, pos, angle, config);
+    let (body, axles, wheels) = create_body(body, commands, pos, angle, config);
 
     let turret = commands
         .spawn(SpatialBundle {
@@ -539,18 +545,18 @@ pub fn tank_shift(
 
             data.time -= time.delta_seconds();
 
-            transform.translation = transform.translation + delta_pos;
+            transform.translation += delta_pos;
             transform.rotation = set_angle_y(normalize_angle(get_angle_y(&transform.rotation) + delta_angle));      
             
             for axle in &entityes.axles {
                 if let Ok(mut transform) = transforms_query.get_mut(*axle) {
-                    transform.translation = transform.translation + delta_pos;
+                    transform.translation += delta_pos;
                 }
             }
 
             for wheel in &entityes.wheels {
                 if let Ok(mut transform) = transforms_query.get_mut(*wheel) {
-                    transform.translation = transform.translation + delta_pos;
+                    transform.translation += delta_pos;
                 }
             }
 
@@ -583,7 +589,6 @@ pub fn tank_place(
             force.torque = Vec3::ZERO;
 
             let old_pos = transform.translation;
-            let old_angle = transform.rotation.to_euler(EulerRot::YXZ).0;
             let delta_pos = data.pos - old_pos;
 
             transform.translation = data.pos;
@@ -591,13 +596,13 @@ pub fn tank_place(
             
             for axle in &entityes.axles {
                 if let Ok(mut transform) = transforms_query.get_mut(*axle) {
-                    transform.translation = transform.translation + delta_pos;
+                    transform.translation += delta_pos;
                 }
             }
 
             for wheel in &entityes.wheels {
                 if let Ok(mut transform) = transforms_query.get_mut(*wheel) {
-                    transform.translation = transform.translation + delta_pos;
+                    transform.translation += delta_pos;
                 }
             }
         }
@@ -0,0 +1,344 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_rapier3d::plugin::{PhysicsStages, SimulationToRenderTime};
+use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::game::{POS_EPSILON_QRT, ANGLE_EPSILON};
+use crate::player::ControlMove;
+use crate::tank::{brake_wheels, move_with_body, update_body_moving, wheel_movement, TankEntityes, TankShift, WheelData};
+use crate::utils::*;
+
+/// Most local inputs waiting for the authority, about 4 seconds of frames.
+const INPUTS_LEN: usize = 256;
+
+/// Errors up to these are smoothed, larger ones snap the tank to the replayed place.
+const SNAP_DISTANCE: f32 = 1.5;
+const SNAP_ANGLE: f32 = 0.5;
+
+/// Time in which a small error is smoothed out, sec.
+const SMOOTH_TIME: f32 = 0.2;
+
+/// State of a tank after its input `ack`, sent by the authority to the owner of the tank.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct CorrectionData {
+    /// Peer id of the owner.
+    pub target: String,
+    pub ack: u32,
+    pub pos: Vec2,
+    pub angle: f32,
+    pub linvel: Vec2,
+    pub angvel: f32,
+}
+
+/// Input of one frame and the place of the tank when it was applied.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InputFrame {
+    pub seq: u32,
+    pub pos: Vec2,
+    pub angle: f32,
+    pub movement: Vec2,
+    /// Times the movement keys were held, sec.
+    pub time_linear: f32,
+    pub time_angular: f32,
+    /// Length of the frame, sec.
+    pub delta_time: f32,
+}
+
+/// Sequence-numbered inputs of the local tank not yet acknowledged by the authority.
+#[derive(Resource, Debug, Default)]
+pub struct InputBuffer {
+    next_seq: u32,
+    frames: VecDeque<InputFrame>,
+    corrections: Vec<CorrectionData>,
+}
+
+impl InputBuffer {
+    /// Number the input of this frame, the place of `frame` is the one before it's applied.
+    pub fn record(&mut self, mut frame: InputFrame) -> u32 {
+        frame.seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        self.frames.push_back(frame);
+        if self.frames.len() > INPUTS_LEN {
+            self.frames.pop_front();
+        }
+
+        frame.seq
+    }
+
+    pub fn receive(&mut self, correction: CorrectionData) {
+        self.corrections.push(correction);
+    }
+
+    /// Drop the inputs up to the acknowledged one, false if it's not in the buffer.
+    fn acknowledge(&mut self, correction: &CorrectionData) -> bool {
+        let first = match self.frames.front() {
+            Some(frame) => frame.seq,
+            None => return false,
+        };
+
+        let acked = correction.ack.wrapping_add(1).wrapping_sub(first) as usize;
+        if acked > self.frames.len() {
+            // acknowledged before the buffer or not sent yet
+            return false;
+        }
+
+        self.frames.drain(..acked);
+        true
+    }
+
+    /// Apply the unacknowledged inputs again from the place `pos` and `angle` with `step`,
+    /// the frames get their new places. Returns the place after the last input.
+    fn replay(
+        &mut self,
+        mut pos: Vec2,
+        mut angle: f32,
+        mut step: impl FnMut(&InputFrame) -> (Vec2, f32),
+    ) -> (Vec2, f32) {
+        for frame in self.frames.iter_mut() {
+            frame.pos = pos;
+            frame.angle = angle;
+            (pos, angle) = step(frame);
+        }
+
+        (pos, angle)
+    }
+}
+
+/// Wheel motors and the physics of one replayed frame.
+#[derive(Resource)]
+pub struct ReplaySchedule(Schedule);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+enum ReplayStage {
+    Motors,
+    Step,
+}
+
+impl Default for ReplaySchedule {
+    fn default() -> Self {
+        let mut schedule = Schedule::default();
+        schedule
+            .add_stage(ReplayStage::Motors, SystemStage::single_threaded().with_system(update_body_moving))
+            .add_stage(
+                PhysicsStages::SyncBackend,
+                SystemStage::parallel()
+                    .with_system_set(RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::SyncBackend)),
+            )
+            .add_stage(ReplayStage::Step, SystemStage::single_threaded().with_system(step_replay))
+            .add_stage(
+                PhysicsStages::Writeback,
+                SystemStage::parallel()
+                    .with_system_set(RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::Writeback)),
+            );
+
+        Self(schedule)
+    }
+}
+
+/// Length of the replayed frame, sec.
+#[derive(Resource, Debug, Default)]
+pub struct ReplayStep(f32);
+
+/// Physics step of the replayed frame, without collision events: the shells
+/// are put back after the replay and explode in the regular step.
+fn step_replay(
+    config: Res<RapierConfiguration>,
+    step: Res<ReplayStep>,
+    time: Res<Time>,
+    mut context: ResMut<RapierContext>,
+) {
+    let (dt, substeps) = match config.timestep_mode {
+        TimestepMode::Fixed { dt, substeps } => (dt, substeps),
+        TimestepMode::Variable { max_dt, time_scale, substeps } => ((step.0 * time_scale).min(max_dt), substeps),
+        TimestepMode::Interpolated { dt, substeps, .. } => (dt, substeps),
+    };
+
+    context.step_simulation(
+        config.gravity,
+        TimestepMode::Fixed { dt, substeps },
+        None,
+        &(),
+        &time,
+        &mut SimulationToRenderTime::default(),
+        None,
+    );
+}
+
+/// Reconcile the local tank with the latest correction of the authority before its input of this frame.
+///
+/// The tank is put to the acknowledged state and the inputs after it are run through
+/// the wheel motors and the physics again. The other bodies are put back after the replay.
+/// A small difference of the replayed place and the shown one is smoothed out, a large one snaps.
+pub fn correct_player_body(world: &mut World) {
+    if world.resource::<InputBuffer>().corrections.is_empty() {
+        return;
+    }
+
+    let corrections = std::mem::take(&mut world.resource_mut::<InputBuffer>().corrections);
+
+    let entityes = match world.query_filtered::<&TankEntityes, With<ControlMove>>().get_single(world) {
+        Ok(entityes) => entityes.clone(),
+        Err(_) => return,
+    };
+
+    let mut correction = None;
+    {
+        let mut inputs = world.resource_mut::<InputBuffer>();
+        for data in corrections {
+            if inputs.acknowledge(&data) {
+                correction = Some(data);
+            }
+        }
+    }
+
+    let correction = match correction {
+        Some(correction) => correction,
+        None => return,
+    };
+
+    let saved: Vec<(Entity, Transform, Velocity)> = world
+        .query::<(Entity, &Transform, &Velocity)>()
+        .iter(world)
+        .map(|(entity, transform, velocity)| (entity, *transform, *velocity))
+        .collect();
+
+    let saved_body = match world.get::<Transform>(entityes.body) {
+        Some(transform) => *transform,
+        None => return,
+    };
+
+    let mut acked_body = saved_body;
+    acked_body.translation = Vec3::new(correction.pos.x, saved_body.translation.y, correction.pos.y);
+    acked_body.rotation = set_angle_y(correction.angle);
+    place_tank(world, &entityes, &acked_body);
+
+    if let Some(mut velocity) = world.get_mut::<Velocity>(entityes.body) {
+        velocity.linvel.x = correction.linvel.x;
+        velocity.linvel.z = correction.linvel.y;
+        velocity.angvel.y = correction.angvel;
+    }
+
+    let (pos, angle) = world.resource_scope(|world, mut schedule: Mut<ReplaySchedule>| {
+        world.resource_scope(|world, mut inputs: Mut<InputBuffer>| {
+            inputs.replay(correction.pos, correction.angle, |frame| {
+                let movement = wheel_movement(frame.movement, frame.time_linear, frame.time_angular);
+                for wheel in &entityes.wheels {
+                    if let Some(mut wheel_data) = world.get_mut::<WheelData>(*wheel) {
+                        wheel_data.movement = brake_wheels(wheel_data.movement, movement, frame.delta_time);
+                    }
+                }
+
+                world.resource_mut::<ReplayStep>().0 = frame.delta_time;
+                schedule.0.run(world);
+
+                let transform = world.get::<Transform>(entityes.body).copied().unwrap_or(acked_body);
+                (v3_2(transform.translation), get_angle_y(&transform.rotation))
+            })
+        })
+    });
+
+    let delta_pos = pos - v3_2(saved_body.translation);
+    let delta_angle = delta_angle(angle, get_angle_y(&saved_body.rotation));
+    let snap = delta_pos.length() > SNAP_DISTANCE || delta_angle.abs() > SNAP_ANGLE;
+
+    let tank = [entityes.body].into_iter().chain(entityes.axles.iter().copied()).chain(entityes.wheels.iter().copied());
+    let tank: Vec<Entity> = tank.collect();
+
+    for (entity, transform, velocity) in saved {
+        let is_tank = tank.contains(&entity);
+        if is_tank && snap {
+            continue;
+        }
+
+        if let Some(mut saved_transform) = world.get_mut::<Transform>(entity) {
+            *saved_transform = transform;
+        }
+        if let Some(mut global_transform) = world.get_mut::<GlobalTransform>(entity) {
+            *global_transform = GlobalTransform::from(transform);
+        }
+        // the tank keeps the replayed velocities
+        if !is_tank {
+            if let Some(mut saved_velocity) = world.get_mut::<Velocity>(entity) {
+                *saved_velocity = velocity;
+            }
+        }
+    }
+
+    if snap {
+        log::info!("correct_player_body snap error pos:{} angle:{}", delta_pos, delta_angle);
+        for entity in &tank {
+            let transform = world.get::<Transform>(*entity).copied();
+            if let (Some(transform), Some(mut global_transform)) = (transform, world.get_mut::<GlobalTransform>(*entity)) {
+                *global_transform = GlobalTransform::from(transform);
+            }
+        }
+        world.entity_mut(entityes.body).remove::<TankShift>();
+    } else if delta_pos.length_squared() > POS_EPSILON_QRT || delta_angle.abs() > ANGLE_EPSILON {
+        world.entity_mut(entityes.body).insert(TankShift {
+            velosity: v2_3(delta_pos) / SMOOTH_TIME,
+            rotation: delta_angle / SMOOTH_TIME,
+            time: SMOOTH_TIME,
+        });
+    }
+}
+
+/// Put the body of the tank to `transform`, the axles and the wheels turn with it.
+fn place_tank(world: &mut World, entityes: &TankEntityes, transform: &Transform) {
+    let old = match world.get_mut::<Transform>(entityes.body) {
+        Some(mut body) => std::mem::replace(&mut *body, *transform),
+        None => return,
+    };
+
+    for entity in entityes.axles.iter().chain(&entityes.wheels) {
+        if let Some(mut part) = world.get_mut::<Transform>(*entity) {
+            move_with_body(&mut part, &old, transform);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_after_correction() {
+        let mut inputs = InputBuffer::default();
+        for i in 0..5 {
+            inputs.record(InputFrame {
+                pos: Vec2::new(0., i as f32),
+                movement: Vec2::new(0., 1.),
+                time_linear: i as f32 * 0.1,
+                delta_time: 0.1 + i as f32 * 0.01,
+                ..Default::default()
+            });
+        }
+
+        let correction = CorrectionData {
+            ack: 1,
+            pos: Vec2::new(0.5, 2.),
+            ..Default::default()
+        };
+        assert!(inputs.acknowledge(&correction));
+
+        // the tank moves by its movement for the time of the frame
+        let mut replayed = Vec::new();
+        let (pos, angle) = inputs.replay(correction.pos, correction.angle, |frame| {
+            replayed.push((frame.seq, frame.time_linear, frame.delta_time));
+            (frame.pos + frame.movement * frame.delta_time, frame.angle)
+        });
+
+        assert_eq!(replayed.iter().map(|frame| frame.0).collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(replayed[1].1, 0.3);
+        assert_eq!(replayed[2].2, 0.14);
+        assert!(pos.abs_diff_eq(Vec2::new(0.5, 2. + 0.12 + 0.13 + 0.14), 1e-5));
+        assert_eq!(angle, 0.);
+        assert_eq!(inputs.frames[0].pos, Vec2::new(0.5, 2.));
+        assert!(inputs.frames[2].pos.abs_diff_eq(Vec2::new(0.5, 2.25), 1e-5));
+
+        let correction = CorrectionData { ack: 100, ..correction };
+        assert!(!inputs.acknowledge(&correction));
+    }
+}
@@ -5,7 +5,8 @@ use bevy_rapier3d::prelude::*;
 
 use super::TankShotData;
 use crate::game::{GameMessage, OutGameMessages, COLLISION_UNIT, COLLISION_TERRAIN, COLLISION_MISSILE, COLLISION_ENVIRONMENT};
-use crate::player::{ControlFire, LocalHandles, PlayerData};
+use crate::interpolation::Interpolation;
+use crate::player::{ControlFire, LocalHandles, PlayerData, PlayerHandle};
 use crate::shot::{ShotData, ShotExplosionData};
 //use crate::shot::Data;
 
@@ -40,6 +41,7 @@ pub fn create_player_cannon_shot(
     local_handles: Res<LocalHandles>,
     mut query: Query<(&GlobalTransform, &TankShotData, &mut ControlFire)>,
     mut output: ResMut<OutGameMessages<GameMessage>>,
+    interpolation: Res<Interpolation>,
 //    mut shot_control: ResMut<ShotData>,
 ) {
     if query.is_empty() {
@@ -57,12 +59,26 @@ pub fn create_player_cannon_shot(
     let pos = global_transform.translation();
     let vel = global_transform.forward() * data.shot_speed(control.time);
 
-    let out_data = ShotData{is_shot: true, pos, vel, radius: data.radius};
+    let out_data = ShotData{is_shot: true, pos, vel, radius: data.radius, delay: interpolation.delay};
 
     output.data.push(GameMessage::from(out_data));
 
+    let handle = *local_handles.handles.first().unwrap();
+    spawn_shell(&mut commands, &mut meshes, &mut materials, data, handle, pos, vel);
+}
+
+/// Shell of a tank flying from `pos`, it explodes on the first hit.
+pub fn spawn_shell(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    data: &TankShotData,
+    handle: PlayerHandle,
+    pos: Vec3,
+    vel: Vec3,
+) -> Entity {
     commands
-            .spawn_bundle(PbrBundle {
+            .spawn(PbrBundle {
                 mesh: meshes.add(Mesh::from(UVSphere {
                     radius: data.radius,
                     sectors: 8,
@@ -77,10 +93,10 @@ pub fn create_player_cannon_shot(
                 ..default()
             })
             .insert(ShotExplosionData::new(data.shot_live_max_time, data.explosion_force))
-            .insert(PlayerData {handle: *local_handles.handles.first().unwrap()})
+            .insert(PlayerData {handle})
             .insert(bevy_rapier3d::prelude::RigidBody::Dynamic)
             .insert(bevy_rapier3d::prelude::Collider::ball(data.radius))
-            //                .insert_bundle(collider)
+            //                .insert(collider)
             .insert(bevy_rapier3d::prelude::ActiveEvents::COLLISION_EVENTS)
             .insert(Restitution::coefficient(0.01))
             .insert(Friction::coefficient(1.0))
@@ -100,5 +116,5 @@ pub fn create_player_cannon_shot(
             ))
             .insert(bevy_rapier3d::prelude::ActiveHooks::FILTER_CONTACT_PAIRS)
 //          .insert(CustomFilterTag::GroupShot)
-            ;
+            .id()
 }
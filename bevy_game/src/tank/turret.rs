@@ -4,9 +4,8 @@ use serde::{Deserialize, Serialize};
 use bevy::prelude::*;
 use std::f32::consts::PI;
 
-use crate::game::{GameMessage, MesState, OutGameMessages, OutMessageState, MAX_OUT_DELTA_TIME, MIN_OUT_DELTA_TIME, OUT_ANGLE_EPSILON, ANGLE_SPEED_EPSILON};
-use crate::network::PingList;
-//use crate::network::PingList;
+use crate::game::{GameMessage, OutGameMessages, OutMessageState, MAX_OUT_DELTA_TIME, MIN_OUT_DELTA_TIME, OUT_ANGLE_EPSILON, ANGLE_SPEED_EPSILON};
+use crate::interpolation::{Interpolate, Interpolation, Snapshots};
 use crate::player::{ControlTurret, PlayerData};
 use crate::utils::*;
 
@@ -17,33 +16,32 @@ pub struct Data {
     pub angle: f32,
 }
 
+impl Interpolate for Data {
+    fn interpolate(&self, next: &Self, t: f32) -> Self {
+        Self {
+            speed: self.speed + (next.speed - self.speed) * t,
+            angle: normalize_angle(self.angle + delta_angle(next.angle, self.angle) * t),
+        }
+    }
+
+    fn extrapolate(&self, delta_time: f32) -> Self {
+        Self {
+            speed: self.speed,
+            angle: normalize_angle(self.angle + self.speed * delta_time),
+        }
+    }
+}
+
 pub fn update_turret_rotation_from_net(
     time: Res<Time>,
-    ping: Res<PingList>,
-    mut query: Query<(&mut Transform, &MesState<Data>, &PlayerData)>,
+    interpolation: Res<Interpolation>,
+    mut query: Query<(&mut Transform, &mut Snapshots<Data>), With<PlayerData>>,
 ) {
-    for (mut transform, state, player) in query.iter_mut() {
-        let data = state.data;
-        let old_angle = transform.rotation.to_euler(EulerRot::YXZ).0;
-
-        let target_angle = if data.speed != 0. {
-            let delta_time = (time.elapsed_seconds() - state.time) as f32 + ping.get_time(player.handle)*0.5;
-            normalize_angle(data.angle + data.speed * delta_time)
-        } else {
-            data.angle
-        }; 
-
-        let new_angle =  calc_angle(
-                target_angle,
-                old_angle,
-                data.speed,
-                time.delta_seconds(),
-            ) 
-            ;
-
-
-        if new_angle != old_angle {
-            transform.rotation = set_angle_y(new_angle);
+    let render_time = interpolation.render_time(time.elapsed_seconds());
+
+    for (mut transform, mut snapshots) in query.iter_mut() {
+        if let Some(data) = snapshots.sample(render_time, interpolation.max_extrapolation) {
+            transform.rotation = set_angle_y(data.angle);
         }
     }
 }
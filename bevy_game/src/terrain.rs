@@ -139,7 +139,7 @@ fn add_terrain_assets(
                 if let Some(entity) = create_gltf_entity(&mut commands, &gltf_meshes, first_node) {    
 
                     let root = commands
-                        .spawn_bundle(SpatialBundle {
+                        .spawn(SpatialBundle {
                             transform: Transform::from_scale(scale),
                             ..Default::default()
                         }).id();
@@ -163,7 +163,7 @@ fn add_terrain_assets(
         println!("terrain add_terrain_assets gltfs.get(&model_assets.terrain) fault");        
     }
 
-    return false;
+    false
 }
 
 fn create_gltf_entities<'n>(
@@ -175,7 +175,7 @@ fn create_gltf_entities<'n>(
 //    println!("terrain create_gltf_entities"); 
 
     for node in gltf_node { 
-        if let Some(entity) = create_gltf_entity(commands, gltf_meshes, &node) {
+        if let Some(entity) = create_gltf_entity(commands, gltf_meshes, node) {
             commands.entity(parent).add_child(entity);
             if !node.children.is_empty() {
                 create_gltf_entities(commands, parent, gltf_meshes, &node.children)
@@ -197,16 +197,13 @@ fn create_gltf_entity(
         .and_then(|mesh| gltf_meshes.get(mesh))
         .and_then(|mesh| mesh.primitives.first());
 
-    if primitive.is_some() && 
-        primitive.unwrap().material.as_ref().is_some() {
+    if let Some((primitive, material)) = primitive.and_then(|primitive| Some((primitive, primitive.material.as_ref()?))) {
   //          println!("terrain create_gltf_entity primitive.material ok"); 
-            let primitive = primitive.unwrap();
-            let material = primitive.material.as_ref().unwrap();
 
-            return Some(commands.spawn_bundle(PbrBundle {
+            return Some(commands.spawn(PbrBundle {
                     mesh: primitive.mesh.clone(),
                     material: material.clone(),
-                    transform: gltf_node.transform.clone(),
+                    transform: gltf_node.transform,
          //           global_transform: todo!(),
          //           visibility: todo!(),
          //           computed_visibility: todo!(),
@@ -220,8 +217,8 @@ fn create_gltf_entity(
             );                    
     } else if !gltf_node.children.is_empty() {
  //       println!("terrain create_gltf_entity TransformBundle ok");  
-        return Some(commands.spawn_bundle(SpatialBundle {
-            transform: gltf_node.transform.clone(),
+        return Some(commands.spawn(SpatialBundle {
+            transform: gltf_node.transform,
            ..Default::default()
             })
             .id()
@@ -230,7 +227,7 @@ fn create_gltf_entity(
 
     println!("terrain create_gltf_entity fault"); 
 
-    return None;
+    None
 }
 
 fn add_test_plane(
@@ -241,7 +238,7 @@ fn add_test_plane(
 ) {
     // Spawn a plane that will represent the ground. It will be used to pick the mouse location in 3D space
     commands
-        .spawn_bundle(PbrBundle {
+        .spawn(PbrBundle {
             mesh: meshes.add(Mesh::from(Plane { size: 50.0 })),
             material: materials.add(Color::rgb(0.3, 0.3, 0.4).into()),
             ..Default::default()
@@ -277,12 +274,12 @@ fn setup_terrain_physics(
 ) {
     for (entity, _global_transform, terrain) in query.iter() {
         if let Some(mesh) = meshes.get(&terrain.mesh.clone()) {
-            if mesh.count_vertices() <= 0 {
+            if mesh.count_vertices() == 0 {
                 continue;
             }
 
             if let Some(mut collider) = bevy_rapier3d::geometry::Collider::from_bevy_mesh(
-                &mesh,
+                mesh,
                 &ComputedColliderShape::TriMesh,
             ) {
                 //make `bevy_mesh` colliders
@@ -1,9 +1,11 @@
+// scratch checks of the helpers, run by AppState::Test
+#![allow(dead_code, unused_variables, non_snake_case)]
+
 use std::f32::consts::FRAC_PI_2;
 
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::Velocity;
 
-use crate::game::*;
 use crate::utils::*;
 use crate::AppState;
 
@@ -34,6 +36,7 @@ fn test_utils_dir() {
         angle: 0.,
         linvel: Vec2::new(0., -1.),
         angvel: 0.,
+        input_seq: 0,
     };
 
     let vel = Velocity {
@@ -178,6 +181,7 @@ fn calc_forse() {
         angle: 0.,
         linvel: Vec2::new(0., -1.),
         angvel: 0.,
+        input_seq: 0,
     };
 
     let vel = Velocity {
@@ -10,13 +10,11 @@ pub fn delta_angle(target_angle: f32, old_angle: f32) -> f32 {
 pub fn delta_angle_max(target_angle: f32, old_angle: f32, rot_speed: f32, delta_time: f32) -> f32 {
     let delta = delta_angle(target_angle, old_angle);
     let max_delta = rot_speed.abs() * delta_time;
-    let res = if delta.abs() > max_delta {
+    if delta.abs() > max_delta {
         max_delta * delta.signum() + delta*(delta_time/1.0)
     } else {
         delta
-    };
-
-    res
+    }
 }
 
 pub fn calc_angle(target_angle: f32, old_angle: f32, rot_speed: f32, delta_time: f32) -> f32 {
@@ -119,13 +117,11 @@ impl Convert for Quat {
         Transform::from_rotation(*self)
         .transform_point(*dir)
     }
-    fn pos_to_local(&self, pos: &Vec3) -> Vec3 {
+    fn pos_to_local(&self, _pos: &Vec3) -> Vec3 {
         panic!("wrong data");
-        Vec3::ZERO
     }
-    fn pos_to_global(&self, pos: &Vec3) -> Vec3 {
+    fn pos_to_global(&self, _pos: &Vec3) -> Vec3 {
         panic!("wrong data");
-        Vec3::ZERO
     }
 }
 
@@ -191,7 +187,7 @@ impl AngleY for Vec2 {
             self.dot(Vec2::NEG_X) * FRAC_PI_2// * self.dot(Vec2::NEG_Y).signum()
         } else {
   //          let norm_self = self.clone().normalize();
-            self.clone().normalize().dot(Vec2::NEG_X) * FRAC_PI_2 //* norm_self.dot(Vec2::NEG_Y).signum()
+            self.normalize().dot(Vec2::NEG_X) * FRAC_PI_2 //* norm_self.dot(Vec2::NEG_Y).signum()
         }
     }
 
@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy_game::{AppState, Headless, HeadlessPlugins, Opts, ServerPlugin};
+use clap::Parser;
+
+/// Longest wait for the models of the server to load.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn server_app() -> App {
+    // nothing listens on the relay, the network task fails and the server keeps connecting
+    let opts = Opts::parse_from(["bb-server", "--relay-address", "http://127.0.0.1:1", "--host"]);
+
+    let mut app = App::new();
+    app.insert_resource(opts)
+        .add_plugins(MinimalPlugins)
+        .add_plugins(HeadlessPlugins)
+        .add_plugin(ServerPlugin);
+    app
+}
+
+fn current_state(app: &App) -> AppState {
+    app.world.resource::<State<AppState>>().current().clone()
+}
+
+#[test]
+fn test_server_boots_headless() {
+    let mut app = server_app();
+    assert!(app.world.contains_resource::<Headless>());
+
+    let started = Instant::now();
+    while current_state(&app) == AppState::Loading {
+        assert!(started.elapsed() < LOAD_TIMEOUT, "the models didn't load");
+        app.update();
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    for _ in 0..10 {
+        app.update();
+    }
+
+    assert_eq!(current_state(&app), AppState::Connecting);
+}
@@ -1,7 +1,22 @@
 use std::hash::{Hash, Hasher};
 
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use hkdf::Hkdf;
 use libp2p::identity::{self, PublicKey};
+use libp2p::PeerId;
 use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{PublicKey as X25519Public, StaticSecret};
+
+use crate::{BlueError, BlueResult};
+
+/// Key derivation context of `Identity::encrypt_for`.
+const ECIES_INFO: &[u8] = b"beyond-blue/ecies/1";
+
+/// Multihash code of peer ids which embed the public key.
+const IDENTITY_MULTIHASH: u64 = 0;
 
 #[derive(Clone)]
 pub struct Identity {
@@ -27,6 +42,65 @@ impl Identity {
         self.key.clone()
     }
 
+    pub fn public_key(&self) -> PublicKey {
+        self.public.clone()
+    }
+
+    /// Encrypt a message only the owner of `recipient` can read (ECIES).
+    ///
+    /// The ed25519 keys are converted to x25519, the shared secret of an
+    /// ephemeral key is expanded by HKDF-SHA256 into a ChaCha20-Poly1305 key.
+    /// The output is the ephemeral public key followed by the sealed message.
+    pub fn encrypt_for(recipient: &PublicKey, plaintext: &[u8]) -> BlueResult<Vec<u8>> {
+        let recipient = x25519_public(recipient)?;
+
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        let ephemeral = StaticSecret::from(seed);
+        let ephemeral_public = X25519Public::from(&ephemeral);
+
+        let cipher = cipher(&ephemeral.diffie_hellman(&recipient).to_bytes(), &ephemeral_public, &recipient);
+        // Every message has its own key, so the nonce is never reused.
+        let sealed = cipher
+            .encrypt(&Nonce::default(), plaintext)
+            .map_err(BlueError::local_err)?;
+
+        let mut res = ephemeral_public.as_bytes().to_vec();
+        res.extend_from_slice(&sealed);
+        Ok(res)
+    }
+
+    /// Decrypt a message of `encrypt_for` sent to this identity.
+    pub fn decrypt(&self, data: &[u8]) -> BlueResult<Vec<u8>> {
+        if data.len() < 32 {
+            return Err(BlueError::remote_err("encrypted message is too short"));
+        }
+
+        let (ephemeral_public, sealed) = data.split_at(32);
+        let ephemeral_public = X25519Public::from(<[u8; 32]>::try_from(ephemeral_public).unwrap());
+
+        let secret = self.x25519_secret()?;
+        let public = X25519Public::from(&secret);
+
+        let cipher = cipher(&secret.diffie_hellman(&ephemeral_public).to_bytes(), &ephemeral_public, &public);
+        cipher
+            .decrypt(&Nonce::default(), sealed)
+            .map_err(BlueError::remote_err)
+    }
+
+    fn x25519_secret(&self) -> BlueResult<StaticSecret> {
+        match &self.key {
+            identity::Keypair::Ed25519(key) => {
+                // Same scalar as the ed25519 signing key, x25519 clamps it.
+                let hash = Sha512::digest(key.secret().as_ref());
+                let mut secret = [0u8; 32];
+                secret.copy_from_slice(&hash[..32]);
+                Ok(StaticSecret::from(secret))
+            }
+            _ => Err(BlueError::local_err("only ed25519 identities can decrypt")),
+        }
+    }
+
     /// Generate keypair
     fn generate_ed25519(seed: &mut [u8]) -> identity::Keypair {
         let secret_key = identity::ed25519::SecretKey::from_bytes(seed)
@@ -48,3 +122,52 @@ impl Hash for Identity {
 }
 
 impl Eq for Identity {}
+
+/// Public key embedded in the peer id, `None` for ids which are hashes of the key.
+pub fn public_key_of(peer_id: &PeerId) -> Option<PublicKey> {
+    let multihash = peer_id.as_ref();
+    if multihash.code() != IDENTITY_MULTIHASH {
+        return None;
+    }
+
+    PublicKey::from_protobuf_encoding(multihash.digest()).ok()
+}
+
+fn x25519_public(key: &PublicKey) -> BlueResult<X25519Public> {
+    match key {
+        PublicKey::Ed25519(key) => CompressedEdwardsY(key.encode())
+            .decompress()
+            .map(|point| X25519Public::from(point.to_montgomery().to_bytes()))
+            .ok_or_else(|| BlueError::local_err("invalid ed25519 public key")),
+        _ => Err(BlueError::local_err("only ed25519 keys can be encrypted to")),
+    }
+}
+
+fn cipher(shared: &[u8; 32], ephemeral: &X25519Public, recipient: &X25519Public) -> ChaCha20Poly1305 {
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(ECIES_INFO, &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_for_peer() {
+        let alice = Identity::from_file("nothing".into());
+        let bob = Identity::from_file("nothing".into());
+
+        let bob_key = public_key_of(&bob.public_key().to_peer_id()).unwrap();
+        let data = Identity::encrypt_for(&bob_key, b"hello").unwrap();
+
+        assert_eq!(bob.decrypt(&data).unwrap(), b"hello");
+        assert!(alice.decrypt(&data).is_err());
+    }
+}
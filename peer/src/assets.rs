@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use common::{BlueError, BlueResult};
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::RequestResponseCodec;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Largest part of a file sent in one response.
+pub(crate) const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Largest file fetched from a peer.
+pub(crate) const MAX_ASSET_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Sha256 of the content of a file, the address of the file in the swarm.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ContentHash(pub [u8; 32]);
+
+impl ContentHash {
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+}
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ContentHash({})", self)
+    }
+}
+
+/// Files served to other peers and files fetched from them.
+///
+/// Provided files stay where they are, fetched files are verified and
+/// written to the cache directory under their hash.
+#[derive(Debug)]
+pub struct AssetStore {
+    dir: PathBuf,
+    paths: HashMap<ContentHash, PathBuf>,
+}
+
+impl AssetStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            paths: HashMap::new(),
+        }
+    }
+
+    /// Serve the file to other peers.
+    pub fn provide(&mut self, path: &Path) -> BlueResult<ContentHash> {
+        let hash = ContentHash::of(&fs::read(path)?);
+        self.paths.insert(hash, path.to_path_buf());
+        Ok(hash)
+    }
+
+    /// Local file with the content, provided or cached.
+    pub fn path(&self, hash: &ContentHash) -> Option<PathBuf> {
+        if let Some(path) = self.paths.get(hash) {
+            return Some(path.clone());
+        }
+
+        let cached = self.cache_path(hash);
+        cached.is_file().then_some(cached)
+    }
+
+    /// Verify fetched content and write it to the cache.
+    pub(crate) fn store(&mut self, hash: ContentHash, data: &[u8]) -> BlueResult<PathBuf> {
+        if ContentHash::of(data) != hash {
+            return Err(BlueError::remote_err(format!("content of {} doesn't match the hash", hash)));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.cache_path(&hash);
+        // A partly written file must never be taken for a cached one.
+        let tmp = path.with_extension("part");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+
+        self.paths.insert(hash, path.clone());
+        Ok(path)
+    }
+
+    /// Part of the file from `offset` and the size of the whole file.
+    pub(crate) fn read_chunk(&self, hash: &ContentHash, offset: u64) -> io::Result<(u64, Vec<u8>)> {
+        let path = self
+            .path(hash)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, hash.to_string()))?;
+
+        let mut file = fs::File::open(path)?;
+        let total = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = Vec::with_capacity(CHUNK_SIZE);
+        file.take(CHUNK_SIZE as u64).read_to_end(&mut data)?;
+        Ok((total, data))
+    }
+
+    fn cache_path(&self, hash: &ContentHash) -> PathBuf {
+        self.dir.join(hash.to_string())
+    }
+}
+
+pub(crate) type SharedAssets = Arc<Mutex<AssetStore>>;
+
+#[derive(Debug, Clone)]
+pub struct AssetProtocol;
+
+impl ProtocolName for AssetProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/beyond-blue/asset/1"
+    }
+}
+
+/// Ask for the part of a file from `offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssetRequest {
+    pub hash: ContentHash,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssetResponse {
+    /// Up to `CHUNK_SIZE` bytes and the size of the whole file.
+    Chunk { total: u64, data: Vec<u8> },
+    NotFound,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AssetCodec;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[async_trait]
+impl RequestResponseCodec for AssetCodec {
+    type Protocol = AssetProtocol;
+    type Request = AssetRequest;
+    type Response = AssetResponse;
+
+    async fn read_request<T>(&mut self, _: &AssetProtocol, io: &mut T) -> io::Result<AssetRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 40).await?;
+        if data.len() != 40 {
+            return Err(invalid_data("bad asset request"));
+        }
+
+        Ok(AssetRequest {
+            hash: ContentHash(data[..32].try_into().unwrap()),
+            offset: u64::from_be_bytes(data[32..].try_into().unwrap()),
+        })
+    }
+
+    async fn read_response<T>(&mut self, _: &AssetProtocol, io: &mut T) -> io::Result<AssetResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, CHUNK_SIZE + 8).await?;
+        if data.is_empty() {
+            return Ok(AssetResponse::NotFound);
+        }
+        if data.len() < 8 {
+            return Err(invalid_data("bad asset response"));
+        }
+
+        Ok(AssetResponse::Chunk {
+            total: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            data: data[8..].to_vec(),
+        })
+    }
+
+    async fn write_request<T>(&mut self, _: &AssetProtocol, io: &mut T, req: AssetRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut data = req.hash.0.to_vec();
+        data.extend_from_slice(&req.offset.to_be_bytes());
+        write_length_prefixed(io, data).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &AssetProtocol, io: &mut T, res: AssetResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        // An empty response is `NotFound`, a chunk always starts with its size.
+        let data = match res {
+            AssetResponse::Chunk { total, data } => {
+                let mut res = total.to_be_bytes().to_vec();
+                res.extend_from_slice(&data);
+                res
+            }
+            AssetResponse::NotFound => Vec::new(),
+        };
+
+        write_length_prefixed(io, data).await?;
+        io.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_verifies_and_reads_chunks() {
+        let dir = std::env::temp_dir().join(format!("bb-assets-{}", std::process::id()));
+        let mut store = AssetStore::new(dir.clone());
+
+        let data = vec![7u8; CHUNK_SIZE + 10];
+        let hash = ContentHash::of(&data);
+        assert!(store.store(ContentHash::of(b"other"), &data).is_err());
+        assert_eq!(store.path(&hash), None);
+
+        let path = store.store(hash, &data).unwrap();
+        assert_eq!(AssetStore::new(dir.clone()).path(&hash), Some(path));
+
+        let (total, chunk) = store.read_chunk(&hash, 0).unwrap();
+        assert_eq!((total, chunk.len()), (data.len() as u64, CHUNK_SIZE));
+        let (_, chunk) = store.read_chunk(&hash, CHUNK_SIZE as u64).unwrap();
+        assert_eq!(chunk.len(), 10);
+
+        _ = fs::remove_dir_all(dir);
+    }
+}
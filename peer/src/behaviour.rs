@@ -1,16 +1,26 @@
-use common::BlueResult;
+use common::{BlueError, BlueResult};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::iter;
+use std::num::NonZeroU32;
 use std::time::Duration;
 
 use libp2p::gossipsub::{
-    Gossipsub, GossipsubMessage, MessageAuthenticity, MessageId, ValidationMode,
+    Gossipsub, GossipsubMessage, MessageAuthenticity, MessageId, PeerScoreParams,
+    PeerScoreThresholds, TopicScoreParams, ValidationMode,
 };
 use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
 use libp2p::ping::{Ping, PingConfig, PingEvent};
 use libp2p::relay::v2::client::{self, Client};
-use libp2p::{dcutr, gossipsub};
-use libp2p::{identity, NetworkBehaviour};
+use libp2p::request_response::{
+    ProtocolSupport, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+};
+use libp2p::{autonat, dcutr, gossipsub};
+use libp2p::{identity, NetworkBehaviour, PeerId};
+
+use crate::assets::{AssetCodec, AssetProtocol, AssetRequest, AssetResponse};
+use crate::clock::{ClockCodec, ClockProtocol, ClockRequest, ClockResponse};
+use crate::{wire, Channel};
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "Event", event_process = false)]
@@ -20,6 +30,9 @@ pub struct Behaviour {
     pub dcutr: dcutr::behaviour::Behaviour,
     pub gossip: gossipsub::Gossipsub,
     pub ping: Ping,
+    pub clock: RequestResponse<ClockCodec>,
+    pub autonat: autonat::Behaviour,
+    pub assets: RequestResponse<AssetCodec>,
 }
 
 impl Behaviour {
@@ -31,34 +44,95 @@ impl Behaviour {
             identify: Identify::new(IdentifyConfig::new("/TODO/0.0.1".to_string(), key.public())),
             dcutr: dcutr::behaviour::Behaviour::new(),
             gossip,
-            ping: Ping::new(PingConfig::new().with_keep_alive(true)),
+            // Frequent pings keep the link estimates fresh, a lost ping is counted
+            // by the swarm and the connection is closed after five in a row.
+            ping: Ping::new(
+                PingConfig::new()
+                    .with_keep_alive(true)
+                    .with_interval(Duration::from_secs(1))
+                    .with_timeout(Duration::from_secs(5))
+                    .with_max_failures(NonZeroU32::new(5).unwrap()),
+            ),
+            clock: RequestResponse::new(
+                ClockCodec,
+                iter::once((ClockProtocol, ProtocolSupport::Full)),
+                RequestResponseConfig::default(),
+            ),
+            // The default waits 15 s before the first probe, the player would
+            // only see the result long after the match has started.
+            autonat: autonat::Behaviour::new(
+                PeerId::from(key.public()),
+                autonat::Config {
+                    boot_delay: Duration::from_secs(1),
+                    retry_interval: Duration::from_secs(5),
+                    ..Default::default()
+                },
+            ),
+            assets: RequestResponse::new(
+                AssetCodec,
+                iter::once((AssetProtocol, ProtocolSupport::Full)),
+                RequestResponseConfig::default(),
+            ),
         })
     }
 
     fn new_gossip_config(key: &identity::Keypair) -> BlueResult<Gossipsub> {
-        // To content-address message, we can take the hash of message and use it as an ID.
-        let message_id_fn = |message: &GossipsubMessage| {
-            let mut s = DefaultHasher::new();
-            let id = format!("{:?}{:?}", message.source, message.data);
-            id.hash(&mut s);
-            MessageId::from(s.finish().to_string())
+        // Identify a message by its author and the author's sequence number, so
+        // repeated game messages with equal content are still delivered.
+        // The numbering starts at the wall clock, so the ids differ between runs.
+        let message_id_fn = |message: &GossipsubMessage| match wire::decode_frame(&message.data) {
+            Some(wire::Frame { seq, .. }) => {
+                let mut id = message.source.map(|s| s.to_bytes()).unwrap_or_default();
+                id.extend_from_slice(&seq.to_be_bytes());
+                MessageId::from(id)
+            }
+            None => {
+                // Malformed frame, it's dropped on receive anyway.
+                let mut s = DefaultHasher::new();
+                message.data.hash(&mut s);
+                MessageId::from(s.finish().to_string())
+            }
         };
 
         // Set a custom gossipsub
         let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
             .validation_mode(ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message signing)
-            .message_id_fn(message_id_fn) // content-address messages. No two messages of the
-            // same content will be propagated.
+            .message_id_fn(message_id_fn) // address messages by (author, seq).
+            .validate_messages() // forward only messages the swarm accepted.
             .build()
             .expect("Valid config");
         // build a gossipsub network behaviour
-        let gossipsub: gossipsub::Gossipsub =
+        let mut gossipsub: gossipsub::Gossipsub =
             gossipsub::Gossipsub::new(MessageAuthenticity::Signed(key.clone()), gossipsub_config)
                 .expect("Correct configuration");
 
+        let score_params = PeerScoreParams {
+            // Relayed peers share the address of the relay.
+            ip_colocation_factor_weight: 0.,
+            ..Default::default()
+        };
+        gossipsub
+            .with_peer_score(score_params, PeerScoreThresholds::default())
+            .map_err(BlueError::local_err)?;
+
         Ok(gossipsub)
     }
+
+    /// Score parameters of a session channel, set when joining the session.
+    pub fn topic_score_params(channel: Channel) -> TopicScoreParams {
+        TopicScoreParams {
+            topic_weight: match channel {
+                Channel::State | Channel::Events => 1.,
+                Channel::Chat => 0.5,
+            },
+            // A quiet channel like chat is no reason to penalize a peer.
+            mesh_message_deliveries_weight: 0.,
+            mesh_failure_penalty_weight: 0.,
+            invalid_message_deliveries_weight: -100.,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -68,6 +142,9 @@ pub enum Event {
     Relay(client::Event),
     Dcutr(dcutr::behaviour::Event),
     Gossipsub(gossipsub::GossipsubEvent),
+    Clock(RequestResponseEvent<ClockRequest, ClockResponse>),
+    Autonat(autonat::Event),
+    Asset(RequestResponseEvent<AssetRequest, AssetResponse>),
 }
 
 impl From<PingEvent> for Event {
@@ -99,3 +176,21 @@ impl From<gossipsub::GossipsubEvent> for Event {
         Event::Gossipsub(e)
     }
 }
+
+impl From<RequestResponseEvent<ClockRequest, ClockResponse>> for Event {
+    fn from(e: RequestResponseEvent<ClockRequest, ClockResponse>) -> Self {
+        Event::Clock(e)
+    }
+}
+
+impl From<autonat::Event> for Event {
+    fn from(e: autonat::Event) -> Self {
+        Event::Autonat(e)
+    }
+}
+
+impl From<RequestResponseEvent<AssetRequest, AssetResponse>> for Event {
+    fn from(e: RequestResponseEvent<AssetRequest, AssetResponse>) -> Self {
+        Event::Asset(e)
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::RequestResponseCodec;
+
+/// Number of clock samples the offset is picked from.
+const SAMPLES_LEN: usize = 8;
+
+/// Shared match clock in microseconds.
+///
+/// Every peer follows the clock of the session member with the smallest
+/// `PeerId`, so all peers of a match stamp and age messages with the same clock.
+/// The clock jumps when the reference peer changes.
+#[derive(Debug, Clone)]
+pub struct MatchClock {
+    origin: Instant,
+    offset: Arc<AtomicI64>,
+    synced: Arc<AtomicBool>,
+}
+
+impl Default for MatchClock {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            offset: Arc::default(),
+            synced: Arc::default(),
+        }
+    }
+}
+
+impl MatchClock {
+    /// Current match time.
+    pub fn now(&self) -> u64 {
+        self.at(Instant::now())
+    }
+
+    /// Match time of a local instant.
+    pub fn at(&self, instant: Instant) -> u64 {
+        let local = self.local_at(instant) as i64;
+        (local + self.offset.load(Ordering::Relaxed)).max(0) as u64
+    }
+
+    /// Time passed since the match time `at`, zero for times in the future.
+    pub fn age(&self, at: u64) -> Duration {
+        Duration::from_micros(self.now().saturating_sub(at))
+    }
+
+    /// The clock follows the reference peer or is the reference itself.
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::Relaxed)
+    }
+
+    /// Local time without the offset, used for the clock samples.
+    pub(crate) fn local(&self) -> u64 {
+        self.local_at(Instant::now())
+    }
+
+    fn local_at(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.origin).as_micros() as u64
+    }
+
+    pub(crate) fn set_offset(&self, offset: i64) {
+        self.offset.store(offset, Ordering::Relaxed);
+        self.synced.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Offset estimation from NTP-style request and response samples.
+#[derive(Default)]
+pub(crate) struct ClockSync {
+    /// Offset and round trip time of the latest samples.
+    samples: VecDeque<(i64, u64)>,
+}
+
+impl ClockSync {
+    /// Add a sample: the local time the request was sent, the match time of
+    /// the reference when it answered and the local time the response arrived.
+    /// Returns the offset of the sample with the shortest round trip, the one
+    /// least distorted by queueing.
+    pub fn add_sample(&mut self, sent: u64, remote: u64, received: u64) -> Option<i64> {
+        if received < sent {
+            return None;
+        }
+
+        let rtt = received - sent;
+        let offset = remote as i64 - (sent + rtt / 2) as i64;
+
+        if self.samples.len() >= SAMPLES_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((offset, rtt));
+
+        self.samples
+            .iter()
+            .min_by_key(|(_, rtt)| *rtt)
+            .map(|(offset, _)| *offset)
+    }
+
+    /// Forget the samples of the previous reference peer.
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClockProtocol;
+
+impl ProtocolName for ClockProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/beyond-blue/clock/1"
+    }
+}
+
+/// Local time of the requesting peer, echoed back in the response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockRequest(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockResponse {
+    /// Echo of `ClockRequest`.
+    pub sent: u64,
+    /// Match time of the responding peer.
+    pub remote: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClockCodec;
+
+fn read_u64(data: &[u8]) -> io::Result<u64> {
+    data.try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad clock message"))
+}
+
+#[async_trait]
+impl RequestResponseCodec for ClockCodec {
+    type Protocol = ClockProtocol;
+    type Request = ClockRequest;
+    type Response = ClockResponse;
+
+    async fn read_request<T>(&mut self, _: &ClockProtocol, io: &mut T) -> io::Result<ClockRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 8).await?;
+        Ok(ClockRequest(read_u64(&data)?))
+    }
+
+    async fn read_response<T>(&mut self, _: &ClockProtocol, io: &mut T) -> io::Result<ClockResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 16).await?;
+        if data.len() != 16 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad clock message"));
+        }
+
+        Ok(ClockResponse {
+            sent: read_u64(&data[..8])?,
+            remote: read_u64(&data[8..])?,
+        })
+    }
+
+    async fn write_request<T>(&mut self, _: &ClockProtocol, io: &mut T, ClockRequest(sent): ClockRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, sent.to_be_bytes()).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &ClockProtocol, io: &mut T, res: ClockResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut data = res.sent.to_be_bytes().to_vec();
+        data.extend_from_slice(&res.remote.to_be_bytes());
+        write_length_prefixed(io, data).await?;
+        io.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset_of_shortest_round_trip() {
+        let mut sync = ClockSync::default();
+
+        // The remote clock is 1000 ahead, the second sample was queued on the way back.
+        assert_eq!(sync.add_sample(100, 1150, 200), Some(1000));
+        assert_eq!(sync.add_sample(300, 1350, 700), Some(1000));
+        assert_eq!(sync.add_sample(800, 1840, 880), Some(1000));
+
+        sync.reset();
+        assert_eq!(sync.add_sample(300, 1350, 700), Some(850));
+        assert_eq!(sync.add_sample(10, 0, 5), None);
+    }
+}
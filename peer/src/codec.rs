@@ -0,0 +1,131 @@
+use std::str::FromStr;
+
+use common::{BlueError, BlueResult};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Serialization of game messages on the wire.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> BlueResult<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> BlueResult<T>;
+}
+
+/// MessagePack, compact and self-describing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> BlueResult<Vec<u8>> {
+        rmp_serde::to_vec(value).map_err(BlueError::local_err)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> BlueResult<T> {
+        rmp_serde::from_slice(data).map_err(BlueError::remote_err)
+    }
+}
+
+/// Bincode, the smallest encoding, field names and types are not sent.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> BlueResult<Vec<u8>> {
+        bincode::serialize(value).map_err(BlueError::local_err)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> BlueResult<T> {
+        bincode::deserialize(data).map_err(BlueError::remote_err)
+    }
+}
+
+/// Human-readable JSON to inspect the traffic while debugging desyncs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> BlueResult<Vec<u8>> {
+        serde_json::to_vec(value).map_err(BlueError::local_err)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> BlueResult<T> {
+        serde_json::from_slice(data).map_err(BlueError::remote_err)
+    }
+}
+
+/// Codec selected at `Swarm` construction.
+///
+/// Outgoing messages are encoded with the selected codec, the codec id is
+/// sent in the frame header, so peers with different codecs understand each other.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WireCodec {
+    #[default]
+    MessagePack,
+    Bincode,
+    Json,
+}
+
+impl WireCodec {
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            WireCodec::MessagePack => 0,
+            WireCodec::Bincode => 1,
+            WireCodec::Json => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(WireCodec::MessagePack),
+            1 => Some(WireCodec::Bincode),
+            2 => Some(WireCodec::Json),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for WireCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> BlueResult<Vec<u8>> {
+        match self {
+            WireCodec::MessagePack => MessagePackCodec.encode(value),
+            WireCodec::Bincode => BincodeCodec.encode(value),
+            WireCodec::Json => JsonCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> BlueResult<T> {
+        match self {
+            WireCodec::MessagePack => MessagePackCodec.decode(data),
+            WireCodec::Bincode => BincodeCodec.decode(data),
+            WireCodec::Json => JsonCodec.decode(data),
+        }
+    }
+}
+
+impl FromStr for WireCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "msgpack" => Ok(WireCodec::MessagePack),
+            "bincode" => Ok(WireCodec::Bincode),
+            "json" => Ok(WireCodec::Json),
+            _ => Err(format!("unknown codec {}, expected msgpack, bincode or json", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codecs_roundtrip() {
+        let value = (vec![1u8, 2, 3], Some("msg".to_string()), 7.5f32);
+
+        for codec in [WireCodec::MessagePack, WireCodec::Bincode, WireCodec::Json] {
+            let data = codec.encode(&value).unwrap();
+            let res: (Vec<u8>, Option<String>, f32) = codec.decode(&data).unwrap();
+            assert_eq!(res, value);
+            assert_eq!(WireCodec::from_id(codec.id()), Some(codec));
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{NetSimConfig, RateLimit, WireCodec};
+
+/// Settings of the peer `Swarm`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Encoding of outgoing messages.
+    pub codec: WireCodec,
+    /// Outgoing messages are collected for this time and published as one
+    /// frame per channel, zero publishes every message at once.
+    pub batch_interval: Duration,
+    /// Upper bound of messages in one frame.
+    pub batch_max_len: usize,
+    /// Compress frames with lz4 when it makes them smaller.
+    pub compress: bool,
+    /// Capacity of the outgoing state queue, see `OutQueue`.
+    pub state_queue_len: usize,
+    /// Capacity of the outgoing event queue, see `OutQueue`.
+    pub events_queue_len: usize,
+    /// Limit of frames every author may publish, flooding peers are banned.
+    pub rate_limit: RateLimit,
+    /// Directory of the assets fetched from other peers.
+    pub asset_dir: PathBuf,
+    /// Simulate a bad network on the links of the received frames, for testing only.
+    pub netsim: Option<NetSimConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            codec: WireCodec::default(),
+            batch_interval: Duration::from_millis(16),
+            batch_max_len: 64,
+            compress: true,
+            state_queue_len: 64,
+            events_queue_len: 256,
+            rate_limit: RateLimit::default(),
+            asset_dir: std::env::temp_dir().join("beyond-blue").join("assets"),
+            netsim: None,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use common::*;
+use futures::channel::mpsc::UnboundedSender;
+use libp2p::{identity, PeerId};
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::assets::SharedAssets;
+use crate::link::SharedLinks;
+use crate::swarm::Command;
+use crate::{
+    Channel, Config, ContentHash, LinkQuality, MatchClock, MessageKind, NetworkEvent, NetworkStats, OutQueue,
+    QueueStats, Session, SharedStats, Swarm,
+};
+
+/// Capacity of the incoming event channel of the network task.
+const EVENTS_CAPACITY: usize = 256;
+
+/// Connected peers, updated by the network task.
+pub(crate) type ConnectedPeers = Arc<StdMutex<HashSet<PeerId>>>;
+
+/// Sending half of the event channel of the network task, counts the events
+/// waiting to be taken by `PeerHandle`.
+pub struct EventSender<M> {
+    tx: mpsc::Sender<NetworkEvent<M>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl<M> EventSender<M> {
+    /// Channel of `capacity` events and the shared count of the events in it.
+    pub fn channel(capacity: usize) -> (Self, mpsc::Receiver<NetworkEvent<M>>, Arc<AtomicUsize>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let pending = Arc::new(AtomicUsize::new(0));
+        (Self { tx, pending: pending.clone() }, rx, pending)
+    }
+
+    pub async fn send(&self, event: NetworkEvent<M>) -> Result<(), SendError<NetworkEvent<M>>> {
+        // counted before sending, so the receiver never takes an uncounted event
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let res = self.tx.send(event).await;
+        if res.is_err() {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+/// Handle of a running peer, cheap to clone and share between threads.
+///
+/// Dropping the handle doesn't stop the network task, `shutdown` does.
+pub struct PeerHandle<M> {
+    local_peer_id: PeerId,
+    out: OutQueue<M>,
+    events: Arc<Mutex<mpsc::Receiver<NetworkEvent<M>>>>,
+    pending_events: Arc<AtomicUsize>,
+    connected: ConnectedPeers,
+    clock: MatchClock,
+    stats: SharedStats,
+    links: SharedLinks,
+    assets: SharedAssets,
+    commands: UnboundedSender<Command>,
+}
+
+impl<M> Clone for PeerHandle<M> {
+    fn clone(&self) -> Self {
+        Self {
+            local_peer_id: self.local_peer_id,
+            out: self.out.clone(),
+            events: self.events.clone(),
+            pending_events: self.pending_events.clone(),
+            connected: self.connected.clone(),
+            clock: self.clock.clone(),
+            stats: self.stats.clone(),
+            links: self.links.clone(),
+            assets: self.assets.clone(),
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+impl<M> PeerHandle<M>
+where
+    M: Serialize + DeserializeOwned + Clone + MessageKind + Send + 'static,
+{
+    /// Create the swarm and spawn the network task on the current tokio runtime.
+    ///
+    /// Connecting to the relay happens in the background, failures end the task
+    /// and close the event stream.
+    pub async fn start(
+        local_key: identity::Keypair,
+        config: Config,
+        base_url: url::Url,
+        session: Session,
+    ) -> BlueResult<Self> {
+        let out = OutQueue::new(config.state_queue_len, config.events_queue_len);
+        let (events_in, events_out, pending_events) = EventSender::channel(EVENTS_CAPACITY);
+
+        let mut swarm = Swarm::new_with_default_transport(local_key, config).await?;
+        let local_peer_id = swarm.local_peer_id();
+        let connected = swarm.connected_peers();
+        let clock = swarm.clock();
+        let stats = swarm.stats();
+        let links = swarm.links();
+        let assets = swarm.assets();
+        let commands = swarm.commands();
+
+        let local_out = out.clone();
+        tokio::spawn(async move {
+            let res = swarm
+                .spawn::<M>(base_url, session, events_in, local_out)
+                .await;
+
+            info!("swarm result: {:?}", res);
+        });
+
+        Ok(Self {
+            local_peer_id,
+            out,
+            events: Arc::new(Mutex::new(events_out)),
+            pending_events,
+            connected,
+            clock,
+            stats,
+            links,
+            assets,
+            commands,
+        })
+    }
+}
+
+impl<M> PeerHandle<M> {
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Match clock shared by the peers of the session.
+    pub fn clock(&self) -> &MatchClock {
+        &self.clock
+    }
+
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.connected.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Send a message which must be delivered, it is handed back if the queue is full.
+    pub fn send(&self, channel: Channel, msg: M) -> Result<(), M> {
+        self.out.push_event(channel, msg)
+    }
+
+    /// Send a state update on `Channel::State`, see `OutQueue::push_state`.
+    pub fn send_state(&self, key: u64, msg: M) {
+        self.out.push_state(key, msg)
+    }
+
+    /// Take a received event without waiting, also `None` while another
+    /// clone of the handle waits in `recv`.
+    pub fn try_recv(&self) -> Option<NetworkEvent<M>> {
+        let event = self.events.try_lock().ok()?.try_recv().ok()?;
+        self.pending_events.fetch_sub(1, Ordering::Relaxed);
+        Some(event)
+    }
+
+    /// Received events waiting to be taken.
+    pub fn events_len(&self) -> usize {
+        self.pending_events.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the next event, `None` once the network task has stopped.
+    pub async fn recv(&self) -> Option<NetworkEvent<M>> {
+        let event = self.events.lock().await.recv().await?;
+        self.pending_events.fetch_sub(1, Ordering::Relaxed);
+        Some(event)
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.out.len()
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        self.out.stats()
+    }
+
+    /// Traffic counters since the start or the last `reset_stats`.
+    pub fn stats(&self) -> NetworkStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.stats.lock().unwrap() = NetworkStats::default();
+    }
+
+    /// Round trip estimates of a directly connected peer, `None` until the
+    /// first ping or for peers reached only through the gossip mesh.
+    pub fn link_quality(&self, peer: &PeerId) -> Option<LinkQuality> {
+        self.links.lock().unwrap().get(peer).copied()
+    }
+
+    /// Serve the file to other peers by its hash.
+    pub fn provide_asset(&self, path: &Path) -> BlueResult<ContentHash> {
+        self.assets.lock().unwrap().provide(path)
+    }
+
+    /// Local file of a provided or fetched asset.
+    pub fn asset_path(&self, hash: &ContentHash) -> Option<PathBuf> {
+        self.assets.lock().unwrap().path(hash)
+    }
+
+    /// Fetch the asset from the peer, answered with `NetworkEvent::AssetReady`
+    /// or `NetworkEvent::AssetFailed`.
+    pub fn fetch_asset(&self, hash: ContentHash, peer: PeerId) {
+        _ = self.commands.unbounded_send(Command::FetchAsset(hash, peer));
+    }
+
+    /// Stop the network task once the queued messages are sent.
+    pub fn shutdown(&self) {
+        self.out.close();
+    }
+}
@@ -1,5 +1,30 @@
+mod assets;
 mod behaviour;
+mod clock;
+mod codec;
+mod config;
+mod handle;
+mod limit;
+mod link;
+mod netsim;
+mod queue;
+mod stats;
 mod swarm;
+mod topic;
+mod wire;
 
+pub use assets::{AssetStore, ContentHash};
 pub use behaviour::*;
+pub use clock::MatchClock;
+pub use codec::*;
+pub use config::*;
+pub use handle::*;
+pub use limit::RateLimit;
+pub use link::LinkQuality;
+pub use netsim::NetSimConfig;
+pub use queue::*;
+pub use stats::*;
 pub use swarm::*;
+pub use topic::*;
+
+pub use libp2p::PeerId;
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use libp2p::PeerId;
+
+/// Limit of gossip frames a single author may publish.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    /// Frames per second refilled into the bucket of every author.
+    pub frames_per_sec: f64,
+    /// Frames an author may publish at once.
+    pub burst: f64,
+    /// Frames over the limit after which the author is banned, counted
+    /// until the bucket of the author refills.
+    pub max_violations: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        // Three channels with a frame per batch interval are about 190 frames per second.
+        Self {
+            frames_per_sec: 250.,
+            burst: 500.,
+            max_violations: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allow,
+    /// Drop the frame.
+    Limit,
+    /// Drop the frame and ban the author, returned once per author.
+    Ban,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    violations: u32,
+}
+
+/// Token bucket per author of received frames.
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<PeerId, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, peer: PeerId, now: Instant) -> Verdict {
+        let limit = &self.limit;
+        let bucket = self.buckets.entry(peer).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+            violations: 0,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.frames_per_sec).min(limit.burst);
+        bucket.updated = now;
+
+        // A full bucket means the author kept to the limit since the last violation.
+        if bucket.tokens >= limit.burst {
+            bucket.violations = 0;
+        }
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            return Verdict::Allow;
+        }
+
+        bucket.violations += 1;
+        if bucket.violations == limit.max_violations {
+            Verdict::Ban
+        } else {
+            Verdict::Limit
+        }
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.buckets.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_limit_and_ban() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            frames_per_sec: 10.,
+            burst: 2.,
+            max_violations: 2,
+        });
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(peer, now), Verdict::Allow);
+        assert_eq!(limiter.check(peer, now), Verdict::Allow);
+        assert_eq!(limiter.check(peer, now), Verdict::Limit);
+
+        // The bucket refills with time.
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.check(peer, later), Verdict::Allow);
+        assert_eq!(limiter.check(peer, later), Verdict::Ban);
+        assert_eq!(limiter.check(peer, later), Verdict::Limit);
+
+        assert_eq!(limiter.check(PeerId::random(), later), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_violations_reset_when_refilled() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            frames_per_sec: 10.,
+            burst: 1.,
+            max_violations: 2,
+        });
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(peer, now), Verdict::Allow);
+        assert_eq!(limiter.check(peer, now), Verdict::Limit);
+
+        // An occasional burst in a long match doesn't add up to a ban.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(peer, later), Verdict::Allow);
+        assert_eq!(limiter.check(peer, later), Verdict::Limit);
+        assert_eq!(limiter.check(peer, later), Verdict::Ban);
+    }
+}
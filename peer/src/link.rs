@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+/// Weight of a new round trip in the smoothed round trip, as in TCP.
+const RTT_GAIN: f64 = 1. / 8.;
+
+/// Weight of a new deviation in the jitter, as in RTP.
+const JITTER_GAIN: f64 = 1. / 16.;
+
+/// Weight of a new ping in the loss rate.
+const LOSS_GAIN: f64 = 1. / 10.;
+
+/// Round trip estimates of a directly connected peer, from libp2p pings.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkQuality {
+    /// Smoothed round trip time.
+    pub rtt: Duration,
+    /// Smoothed deviation of round trips from `rtt`.
+    pub jitter: Duration,
+    /// Share of recent pings which failed, from 0 to 1.
+    pub loss: f64,
+    /// Pings answered since the connection was established.
+    pub samples: u64,
+}
+
+impl LinkQuality {
+    pub(crate) fn add_rtt(&mut self, rtt: Duration) {
+        let rtt = rtt.as_secs_f64();
+
+        if self.samples == 0 {
+            self.rtt = Duration::from_secs_f64(rtt);
+            self.jitter = Duration::from_secs_f64(rtt / 2.);
+        } else {
+            let srtt = self.rtt.as_secs_f64();
+            let jitter = self.jitter.as_secs_f64();
+            self.jitter = Duration::from_secs_f64(jitter + ((rtt - srtt).abs() - jitter) * JITTER_GAIN);
+            self.rtt = Duration::from_secs_f64(srtt + (rtt - srtt) * RTT_GAIN);
+        }
+
+        self.samples += 1;
+        self.loss -= self.loss * LOSS_GAIN;
+    }
+
+    pub(crate) fn add_loss(&mut self) {
+        self.loss += (1. - self.loss) * LOSS_GAIN;
+    }
+}
+
+/// Link estimates of the connected peers, updated by the event loop.
+pub(crate) type SharedLinks = Arc<Mutex<HashMap<PeerId, LinkQuality>>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_jitter_and_loss() {
+        let mut link = LinkQuality::default();
+
+        link.add_rtt(Duration::from_millis(100));
+        assert_eq!(link.rtt.as_millis(), 100);
+
+        for _ in 0..100 {
+            link.add_rtt(Duration::from_millis(100));
+        }
+        assert!(link.jitter < Duration::from_millis(1));
+
+        link.add_rtt(Duration::from_millis(180));
+        assert!((link.rtt.as_secs_f64() - 0.11).abs() < 1e-6);
+        assert!(link.jitter > Duration::from_millis(4));
+
+        link.add_loss();
+        assert!((link.loss - 0.1).abs() < 1e-9);
+        assert_eq!(link.samples, 102);
+    }
+}
@@ -0,0 +1,224 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Network conditions injected by `NetSim`, every link gets its own
+/// bandwidth and ordering.
+#[derive(Debug, Clone, Default)]
+pub struct NetSimConfig {
+    /// Fixed one-way delay of every frame.
+    pub latency: Duration,
+    /// Random extra delay, uniformly distributed up to this value.
+    pub jitter: Duration,
+    /// Probability to lose a frame, from 0 to 1.
+    pub loss: f64,
+    /// Bytes per second of a link, `None` is unlimited.
+    pub bandwidth: Option<u64>,
+    /// Seed of the random generator to make a run reproducible.
+    pub seed: Option<u64>,
+}
+
+impl NetSimConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.latency.is_zero()
+            || !self.jitter.is_zero()
+            || self.loss > 0.
+            || self.bandwidth.is_some()
+    }
+}
+
+#[derive(Default)]
+struct Link {
+    /// The link is busy sending earlier frames until this time.
+    busy_until: Option<Instant>,
+    /// Frames of a link are delivered in order, like over a stream.
+    last_delivery: Option<Instant>,
+}
+
+struct Delayed<T> {
+    at: Instant,
+    id: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Delayed<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.id) == (other.at, other.id)
+    }
+}
+
+impl<T> Eq for Delayed<T> {}
+
+impl<T> PartialOrd for Delayed<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Delayed<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.id).cmp(&(other.at, other.id))
+    }
+}
+
+/// Delays, throttles and drops frames to reproduce a bad network locally.
+pub(crate) struct NetSim<T> {
+    config: NetSimConfig,
+    rng: StdRng,
+    links: HashMap<PeerId, Link>,
+    queue: BinaryHeap<Reverse<Delayed<T>>>,
+    next_id: u64,
+}
+
+impl<T> NetSim<T> {
+    pub fn new(config: NetSimConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Self {
+            config,
+            rng,
+            links: HashMap::new(),
+            queue: BinaryHeap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Queue a frame of `size` bytes sent over the link to `peer`,
+    /// `false` if the frame was lost.
+    pub fn push(&mut self, peer: PeerId, size: usize, item: T, now: Instant) -> bool {
+        if self.config.loss > 0. && self.rng.gen_bool(self.config.loss.min(1.)) {
+            return false;
+        }
+
+        let link = self.links.entry(peer).or_default();
+
+        let mut sent = now;
+        if let Some(bandwidth) = self.config.bandwidth {
+            let start = link.busy_until.map_or(now, |busy| busy.max(now));
+            sent = start + Duration::from_secs_f64(size as f64 / bandwidth.max(1) as f64);
+            link.busy_until = Some(sent);
+        }
+
+        let jitter = if self.config.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=self.config.jitter)
+        };
+
+        let mut at = sent + self.config.latency + jitter;
+        if let Some(last) = link.last_delivery {
+            at = at.max(last);
+        }
+        link.last_delivery = Some(at);
+
+        self.queue.push(Reverse(Delayed {
+            at,
+            id: self.next_id,
+            item,
+        }));
+        self.next_id += 1;
+
+        true
+    }
+
+    /// Take the next frame which is due.
+    pub fn pop_ready(&mut self, now: Instant) -> Option<T> {
+        match self.queue.peek() {
+            Some(Reverse(delayed)) if delayed.at <= now => {
+                self.queue.pop().map(|Reverse(delayed)| delayed.item)
+            }
+            _ => None,
+        }
+    }
+
+    /// Time when the next frame is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse(delayed)| delayed.at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> NetSimConfig {
+        NetSimConfig {
+            latency: Duration::from_millis(100),
+            seed: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_latency_and_order() {
+        let mut sim = NetSim::new(NetSimConfig {
+            jitter: Duration::from_millis(50),
+            ..config()
+        });
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        for i in 0..10 {
+            assert!(sim.push(peer, 10, i, now));
+        }
+
+        assert_eq!(sim.pop_ready(now + Duration::from_millis(99)), None);
+
+        let later = now + Duration::from_millis(150);
+        let delivered: Vec<_> = std::iter::from_fn(|| sim.pop_ready(later)).collect();
+        assert_eq!(delivered, (0..10).collect::<Vec<_>>());
+        assert_eq!(sim.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let mut sim = NetSim::new(NetSimConfig {
+            bandwidth: Some(1000),
+            ..config()
+        });
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        sim.push(peer, 1000, 1, now);
+        sim.push(peer, 1000, 2, now);
+
+        assert_eq!(sim.next_deadline(), Some(now + Duration::from_millis(1100)));
+        assert_eq!(sim.pop_ready(now + Duration::from_millis(1100)), Some(1));
+        assert_eq!(sim.pop_ready(now + Duration::from_millis(1100)), None);
+        assert_eq!(sim.pop_ready(now + Duration::from_millis(2100)), Some(2));
+    }
+
+    #[test]
+    fn test_links_are_independent() {
+        let mut sim = NetSim::new(NetSimConfig {
+            bandwidth: Some(1000),
+            ..config()
+        });
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+
+        sim.push(first, 1000, 1, now);
+        sim.push(second, 1000, 2, now);
+
+        let due = now + Duration::from_millis(1100);
+        let delivered: Vec<_> = std::iter::from_fn(|| sim.pop_ready(due)).collect();
+        assert_eq!(delivered, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_loss() {
+        let mut sim = NetSim::new(NetSimConfig {
+            loss: 1.,
+            ..config()
+        });
+
+        assert!(!sim.push(PeerId::random(), 10, (), Instant::now()));
+        assert_eq!(sim.next_deadline(), None);
+    }
+}
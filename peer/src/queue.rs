@@ -0,0 +1,205 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::Notify;
+
+use crate::Channel;
+
+/// Overflow counters of an `OutQueue`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    /// State updates replaced by a newer update with the same key.
+    pub state_coalesced: u64,
+    /// State updates dropped because the queue was full.
+    pub state_dropped: u64,
+    /// Events refused because the queue was full, the sender has to retry them.
+    pub events_rejected: u64,
+}
+
+/// Message taken from the queue with the time the game queued it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Queued<M> {
+    pub channel: Channel,
+    pub queued_at: Instant,
+    pub msg: M,
+}
+
+struct Queues<M> {
+    events: VecDeque<Queued<M>>,
+    state: VecDeque<(u64, Queued<M>)>,
+    closed: bool,
+    stats: QueueStats,
+}
+
+struct Inner<M> {
+    queues: Mutex<Queues<M>>,
+    notify: Notify,
+    state_capacity: usize,
+    events_capacity: usize,
+}
+
+/// Bounded queue of outgoing messages between the game and the network task.
+///
+/// State updates are replaced by newer ones with the same key and dropped
+/// oldest first when the queue is full. Events are never dropped: when their
+/// queue is full they are handed back to the sender. Events are sent first.
+pub struct OutQueue<M> {
+    inner: Arc<Inner<M>>,
+}
+
+impl<M> Clone for OutQueue<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M> OutQueue<M> {
+    pub fn new(state_capacity: usize, events_capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                queues: Mutex::new(Queues {
+                    events: VecDeque::new(),
+                    state: VecDeque::new(),
+                    closed: false,
+                    stats: QueueStats::default(),
+                }),
+                notify: Notify::new(),
+                state_capacity,
+                events_capacity,
+            }),
+        }
+    }
+
+    /// Queue a state update, a queued update with the same key is replaced.
+    pub fn push_state(&self, key: u64, msg: M) {
+        let mut queues = self.inner.queues.lock().unwrap();
+
+        let queued = Queued {
+            channel: Channel::State,
+            queued_at: Instant::now(),
+            msg,
+        };
+
+        if let Some(state) = queues.state.iter_mut().find(|(k, _)| *k == key) {
+            state.1 = queued;
+            queues.stats.state_coalesced += 1;
+            return;
+        }
+
+        if queues.state.len() >= self.inner.state_capacity {
+            queues.state.pop_front();
+            queues.stats.state_dropped += 1;
+        }
+
+        queues.state.push_back((key, queued));
+        drop(queues);
+        self.inner.notify.notify_one();
+    }
+
+    /// Queue an event, the event is returned back if the queue is full.
+    pub fn push_event(&self, channel: Channel, msg: M) -> Result<(), M> {
+        let mut queues = self.inner.queues.lock().unwrap();
+
+        if queues.events.len() >= self.inner.events_capacity {
+            queues.stats.events_rejected += 1;
+            return Err(msg);
+        }
+
+        queues.events.push_back(Queued {
+            channel,
+            queued_at: Instant::now(),
+            msg,
+        });
+        drop(queues);
+        self.inner.notify.notify_one();
+        Ok(())
+    }
+
+    /// Take the next message, `None` once the queue is closed and empty.
+    pub async fn pop(&self) -> Option<Queued<M>> {
+        loop {
+            let notified = self.inner.notify.notified();
+
+            {
+                let mut queues = self.inner.queues.lock().unwrap();
+                if let Some(item) = queues.events.pop_front() {
+                    return Some(item);
+                }
+                if let Some((_, item)) = queues.state.pop_front() {
+                    return Some(item);
+                }
+                if queues.closed {
+                    return None;
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Stop the network task after it sent the queued messages.
+    pub fn close(&self) {
+        self.inner.queues.lock().unwrap().closed = true;
+        self.inner.notify.notify_one();
+    }
+
+    pub fn len(&self) -> usize {
+        let queues = self.inner.queues.lock().unwrap();
+        queues.events.len() + queues.state.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.inner.queues.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn pop<M>(queue: &OutQueue<M>) -> Option<(Channel, M)> {
+        queue.pop().await.map(|item| (item.channel, item.msg))
+    }
+
+    #[tokio::test]
+    async fn test_state_coalesced_and_dropped() {
+        let queue = OutQueue::new(2, 2);
+        queue.push_state(1, "a1");
+        queue.push_state(1, "a2");
+        queue.push_state(2, "b");
+        queue.push_state(3, "c");
+
+        assert_eq!(pop(&queue).await, Some((Channel::State, "b")));
+        assert_eq!(pop(&queue).await, Some((Channel::State, "c")));
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                state_coalesced: 1,
+                state_dropped: 1,
+                events_rejected: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_first_and_never_dropped() {
+        let queue = OutQueue::new(2, 1);
+        queue.push_state(1, "state");
+        assert_eq!(queue.push_event(Channel::Events, "shot"), Ok(()));
+        assert_eq!(queue.push_event(Channel::Events, "explosion"), Err("explosion"));
+
+        assert_eq!(pop(&queue).await, Some((Channel::Events, "shot")));
+        assert_eq!(pop(&queue).await, Some((Channel::State, "state")));
+        assert_eq!(queue.stats().events_rejected, 1);
+
+        queue.close();
+        assert_eq!(pop(&queue).await, None);
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use libp2p::PeerId;
+
+use crate::Channel;
+
+/// Traffic counters of gossip frames, without the gossipsub overhead.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+    pub frames: u64,
+    pub messages: u64,
+    pub bytes: u64,
+    /// Frames which failed to encode or decode.
+    pub errors: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, messages: usize, bytes: usize) {
+        self.frames += 1;
+        self.messages += messages as u64;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Name of a game message for the counters by message type, e.g. its enum variant.
+pub trait MessageKind {
+    fn kind(&self) -> &'static str;
+}
+
+/// Counters of the network task, see `PeerHandle::stats`.
+#[derive(Debug, Default, Clone)]
+pub struct NetworkStats {
+    pub sent: HashMap<Channel, Traffic>,
+    pub received: HashMap<Channel, Traffic>,
+    /// Received traffic by the author of the frames.
+    pub received_from: HashMap<PeerId, Traffic>,
+    /// Sent messages by `MessageKind`.
+    pub sent_kinds: HashMap<&'static str, u64>,
+    /// Received messages by `MessageKind`.
+    pub received_kinds: HashMap<&'static str, u64>,
+}
+
+impl NetworkStats {
+    pub fn total_sent(&self) -> Traffic {
+        Self::total(self.sent.values())
+    }
+
+    pub fn total_received(&self) -> Traffic {
+        Self::total(self.received.values())
+    }
+
+    fn total<'a>(traffic: impl Iterator<Item = &'a Traffic>) -> Traffic {
+        traffic.fold(Traffic::default(), |total, t| Traffic {
+            frames: total.frames + t.frames,
+            messages: total.messages + t.messages,
+            bytes: total.bytes + t.bytes,
+            errors: total.errors + t.errors,
+        })
+    }
+
+    pub(crate) fn record_sent(&mut self, channel: Channel, messages: usize, bytes: usize) {
+        self.sent.entry(channel).or_default().add(messages, bytes);
+    }
+
+    pub(crate) fn record_sent_kind(&mut self, kind: &'static str) {
+        *self.sent_kinds.entry(kind).or_default() += 1;
+    }
+
+    pub(crate) fn record_received_kind(&mut self, kind: &'static str) {
+        *self.received_kinds.entry(kind).or_default() += 1;
+    }
+
+    pub(crate) fn record_send_error(&mut self, channel: Channel) {
+        self.sent.entry(channel).or_default().errors += 1;
+    }
+
+    pub(crate) fn record_received(&mut self, channel: Channel, source: PeerId, messages: usize, bytes: usize) {
+        self.received.entry(channel).or_default().add(messages, bytes);
+        self.received_from.entry(source).or_default().add(messages, bytes);
+    }
+
+    /// Count a rejected frame, `source` is `None` for unsigned frames.
+    pub(crate) fn record_receive_error(&mut self, channel: Channel, source: Option<PeerId>) {
+        self.received.entry(channel).or_default().errors += 1;
+        if let Some(source) = source {
+            self.received_from.entry(source).or_default().errors += 1;
+        }
+    }
+}
+
+pub(crate) type SharedStats = Arc<Mutex<NetworkStats>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_totals() {
+        let peer = PeerId::random();
+        let mut stats = NetworkStats::default();
+
+        stats.record_received(Channel::State, peer, 3, 100);
+        stats.record_received(Channel::Events, peer, 1, 20);
+        stats.record_receive_error(Channel::Events, Some(peer));
+        stats.record_received_kind("shot");
+        stats.record_received_kind("shot");
+
+        assert_eq!(
+            stats.total_received(),
+            Traffic {
+                frames: 2,
+                messages: 4,
+                bytes: 120,
+                errors: 1,
+            }
+        );
+        assert_eq!(stats.received_from[&peer], stats.total_received());
+        assert_eq!(stats.received_kinds["shot"], 2);
+        assert_eq!(stats.total_sent(), Traffic::default());
+    }
+}
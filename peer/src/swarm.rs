@@ -1,41 +1,159 @@
 use common::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::net::Ipv4Addr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::Fuse;
 use futures::{select, FutureExt, StreamExt};
 use libp2p::core::multiaddr::{Multiaddr, Protocol};
 use libp2p::core::transport::OrTransport;
 use libp2p::core::upgrade;
 use libp2p::dns::DnsConfig;
-use libp2p::gossipsub::{GossipsubEvent, IdentTopic, Topic};
+use libp2p::autonat::{self, NatStatus};
+use libp2p::gossipsub::{GossipsubEvent, MessageAcceptance, PeerScoreThresholds};
 use libp2p::identify::{IdentifyEvent, IdentifyInfo};
+use libp2p::ping::{PingEvent, PingSuccess};
 use libp2p::relay::v2::client::Client;
+use libp2p::request_response::{RequestId, RequestResponseEvent, RequestResponseMessage};
 use libp2p::swarm::SwarmEvent;
 use libp2p::tcp::{GenTcpConfig, TcpTransport};
 use libp2p::{core::transport, swarm::SwarmBuilder, PeerId};
 use libp2p::{identity, noise, Transport};
 use libp2p_core::muxing::StreamMuxerBox;
-use log::info;
+use log::{info, warn};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::Event;
+use crate::assets::{AssetRequest, AssetResponse, ContentHash, SharedAssets, MAX_ASSET_SIZE};
+use crate::clock::{ClockRequest, ClockResponse, ClockSync};
+use crate::limit::{RateLimiter, Verdict};
+use crate::link::SharedLinks;
+use crate::netsim::NetSim;
+use crate::{
+    wire, Channel, Codec, Config, ConnectedPeers, Event, EventSender, MatchClock, MessageKind, OutQueue, Queued,
+    Session, AssetStore, SharedStats, WireCodec,
+};
+
+/// How often the clock is synchronized with the reference peer.
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the gossipsub scores of the session members are checked.
+const SCORE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Origin and timing of a received message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Authenticated author of the gossip message.
+    pub source: PeerId,
+    /// Sequence number of the author, grows with every sent message.
+    pub seq: u64,
+    /// Match time when the author sent the message, see `MatchClock`.
+    pub sent_at: u64,
+}
+
+/// Whether other peers can dial us directly, as probed by AutoNAT through the relay.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// Reachable on the address, peers can connect directly.
+    Public(Multiaddr),
+    /// Behind a NAT, connections go through the relay unless hole punching succeeds.
+    Private,
+    Unknown,
+}
+
+impl From<NatStatus> for Reachability {
+    fn from(status: NatStatus) -> Self {
+        match status {
+            NatStatus::Public(addr) => Reachability::Public(addr),
+            NatStatus::Private => Reachability::Private,
+            NatStatus::Unknown => Reachability::Unknown,
+        }
+    }
+}
+
+/// Why a peer was banned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BanReason {
+    /// The peer kept publishing over the rate limit.
+    RateLimit,
+    /// The gossipsub score of the peer fell below the graylist threshold.
+    LowScore,
+}
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Clone, Debug)]
 pub enum NetworkEvent<M> {
-    NewConnection(String),
-    Event(String, M),
+    NewConnection(PeerId),
+    Event(Header, M),
+    /// The last connection to the peer was closed.
+    Disconnected(PeerId),
+    /// Our reachability changed.
+    Reachability(Reachability),
+    /// The peer is ignored and disconnected for the rest of the session.
+    Banned(PeerId, BanReason),
+    /// The fetched asset is verified and stored at the path.
+    AssetReady(ContentHash, PathBuf),
+    AssetFailed(ContentHash, String),
+}
+
+/// Requests of a `PeerHandle` to the event loop.
+#[derive(Debug)]
+pub(crate) enum Command {
+    FetchAsset(ContentHash, PeerId),
+}
+
+/// Asset being fetched chunk by chunk.
+struct Download {
+    peer: PeerId,
+    data: Vec<u8>,
 }
 
 type BBSwarm = libp2p::swarm::Swarm<crate::Behaviour>;
 
+/// Messages are numbered from the wall clock, so a restarted peer neither reuses
+/// the gossip message ids of its last run nor looks stale to the receivers.
+fn initial_seq() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+/// A frame on a simulated downlink, with its signed source.
+type InFrame = (Channel, Option<PeerId>, Vec<u8>);
+
 pub struct Swarm {
     swarm: BBSwarm,
     origin: PeerId,
+    seq: u64,
+    config: Config,
+    connected: ConnectedPeers,
+    stats: SharedStats,
+    links: SharedLinks,
+    /// Simulated links per propagating peer, frames are received when they leave them.
+    /// A gossip frame is published to the whole mesh at once, so only the receiver
+    /// can tell the links apart.
+    inbound: Option<NetSim<InFrame>>,
+    clock: MatchClock,
+    clock_sync: ClockSync,
+    /// Session members, the smallest id of them and us is the reference clock.
+    members: HashSet<PeerId>,
+    reference: PeerId,
+    limiter: RateLimiter,
+    banned: HashSet<PeerId>,
+    assets: SharedAssets,
+    downloads: HashMap<ContentHash, Download>,
+    asset_requests: HashMap<RequestId, ContentHash>,
+    command_tx: UnboundedSender<Command>,
+    command_rx: UnboundedReceiver<Command>,
 }
 
 impl Swarm {
-    pub async fn new_with_default_transport(local_key: identity::Keypair) -> BlueResult<Self> {
+    pub async fn new_with_default_transport(
+        local_key: identity::Keypair,
+        config: Config,
+    ) -> BlueResult<Self> {
         let local_peer_id = PeerId::from(local_key.public());
         let (relay_transport, client) = Client::new_transport_and_behaviour(local_peer_id);
 
@@ -55,31 +173,91 @@ impl Swarm {
         .boxed();
 
         let behaviour = crate::Behaviour::new(client, &local_key)?;
-        Self::try_new(transport, behaviour, local_peer_id)
+        Self::try_new(transport, behaviour, local_peer_id, config)
     }
 
     pub fn try_new(
         transport: transport::Boxed<(PeerId, StreamMuxerBox)>,
         behaviour: crate::Behaviour,
         peer_id: PeerId,
+        config: Config,
     ) -> BlueResult<Self> {
         let swarm = SwarmBuilder::new(transport, behaviour, peer_id)
             .dial_concurrency_factor(10_u8.try_into().map_err(BlueError::local_err)?)
             .build();
+        let netsim = config.netsim.clone().filter(|netsim| netsim.is_enabled());
+        if let Some(netsim) = &netsim {
+            warn!("network simulation enabled: {:?}", netsim);
+        }
+
+        let limiter = RateLimiter::new(config.rate_limit.clone());
+        let assets = AssetStore::new(config.asset_dir.clone());
+        let (command_tx, command_rx) = mpsc::unbounded();
+
         Ok(Self {
             swarm,
             origin: peer_id,
+            seq: initial_seq(),
+            config,
+            connected: Arc::default(),
+            stats: SharedStats::default(),
+            links: SharedLinks::default(),
+            inbound: netsim.map(NetSim::new),
+            clock: MatchClock::default(),
+            clock_sync: ClockSync::default(),
+            members: HashSet::new(),
+            reference: peer_id,
+            limiter,
+            banned: HashSet::new(),
+            assets: Arc::new(Mutex::new(assets)),
+            downloads: HashMap::new(),
+            asset_requests: HashMap::new(),
+            command_tx,
+            command_rx,
         })
     }
 
+    pub fn local_peer_id(&self) -> PeerId {
+        self.origin
+    }
+
+    pub fn clock(&self) -> MatchClock {
+        self.clock.clone()
+    }
+
+    /// Traffic counters, kept up to date by the event loop.
+    pub(crate) fn stats(&self) -> SharedStats {
+        self.stats.clone()
+    }
+
+    /// Peers with an open connection, kept up to date by the event loop.
+    pub(crate) fn connected_peers(&self) -> ConnectedPeers {
+        self.connected.clone()
+    }
+
+    /// Round trip estimates, kept up to date by the event loop.
+    pub(crate) fn links(&self) -> SharedLinks {
+        self.links.clone()
+    }
+
+    /// Files served to and fetched from other peers.
+    pub(crate) fn assets(&self) -> SharedAssets {
+        self.assets.clone()
+    }
+
+    pub(crate) fn commands(&self) -> UnboundedSender<Command> {
+        self.command_tx.clone()
+    }
+
     pub async fn spawn<M>(
         &mut self,
         base_url: url::Url,
-        tx: Sender<NetworkEvent<M>>,
-        rx: Receiver<M>,
+        session: Session,
+        tx: EventSender<M>,
+        rx: OutQueue<M>,
     ) -> BlueResult<()>
     where
-        M: Serialize + DeserializeOwned + Clone,
+        M: Serialize + DeserializeOwned + Clone + MessageKind,
     {
         self.listen().await?;
 
@@ -128,7 +306,16 @@ impl Swarm {
             return Err(BlueError::local_err("Unable to connect to relay"));
         }
 
+        // The relay probes whether we are reachable directly.
+        let relay_peer_id = PeerId::from_str(&relay_info.peer_id).map_err(BlueError::local_err)?;
+        self.swarm
+            .behaviour_mut()
+            .autonat
+            .add_server(relay_peer_id, Some(relay_address.clone()));
+
         self.listen_on_relay(relay_address.clone())?;
+        self.join(&session)?;
+
         for peer in peer_info.iter() {
             _ = self.dial(
                 &relay_address,
@@ -136,7 +323,7 @@ impl Swarm {
             );
         }
 
-        self.spawn_event_loop(tx, rx).await;
+        self.spawn_event_loop(session, tx, rx).await;
 
         Ok(())
     }
@@ -163,6 +350,10 @@ impl Swarm {
                         SwarmEvent::NewListenAddr { address, .. } => {
                             info!("Listening on {:?}", address);
                         }
+                        // autonat may already probe while the listeners come up
+                        SwarmEvent::Behaviour(event) => {
+                            info!("{:?}", event)
+                        }
                         event => return Err(BlueError::local_err(format!("unexpected swarm event {:?}", event))),
                     }
                 }
@@ -240,15 +431,35 @@ impl Swarm {
         Ok(())
     }
 
+    /// Subscribe to every channel of the session.
+    fn join(&mut self, session: &Session) -> BlueResult<()> {
+        for (channel, topic) in session.topics() {
+            self.swarm
+                .behaviour_mut()
+                .gossip
+                .subscribe(&topic)
+                .map_err(BlueError::local_err)?;
+            self.swarm
+                .behaviour_mut()
+                .gossip
+                .set_topic_params(topic, crate::Behaviour::topic_score_params(channel))
+                .map_err(BlueError::local_err)?;
+            info!("joined session {} channel {:?}", session.id(), channel);
+        }
+
+        Ok(())
+    }
+
     async fn spawn_event_loop<M>(
         &mut self,
-        remote_in: Sender<NetworkEvent<M>>,
-        mut local_out: Receiver<M>,
+        session: Session,
+        remote_in: EventSender<M>,
+        local_out: OutQueue<M>,
     ) where
-        M: Serialize + DeserializeOwned + Clone,
+        M: Serialize + DeserializeOwned + Clone + MessageKind,
     {
         let stream = async_stream::stream! {
-            while let Some(item) = local_out.recv().await {
+            while let Some(item) = local_out.pop().await {
                 yield item;
             }
         };
@@ -256,15 +467,47 @@ impl Swarm {
 
         tokio::pin!(stream);
 
+        let mut pending: HashMap<Channel, Vec<(u64, M)>> = HashMap::new();
+        let mut flush_timer = self.flush_timer();
+        let mut sim_timer = self.sim_timer();
+        let mut clock_timer = futures_timer::Delay::new(Duration::ZERO).fuse();
+        let mut score_timer = futures_timer::Delay::new(SCORE_CHECK_INTERVAL).fuse();
+
         loop {
             select! {
-                msg = stream.select_next_some() => {
-                    let msg = NetworkEvent::Event(self.origin.to_string(), msg);
-                    let msg = rmp_serde::to_vec(&msg).unwrap();
-                    _ = self.swarm
-                        .behaviour_mut()
-                        .gossip
-                        .publish(IdentTopic::new(self.origin.to_string()), msg);
+                item = stream.next() => match item {
+                    Some(Queued { channel, queued_at, msg }) => {
+                        let batch = pending.entry(channel).or_default();
+                        batch.push((self.clock.at(queued_at), msg));
+
+                        if self.config.batch_interval.is_zero() || batch.len() >= self.config.batch_max_len {
+                            self.flush(&session, &mut pending);
+                        }
+                    }
+                    None => {
+                        // The queue was closed by a shutdown.
+                        self.flush(&session, &mut pending);
+                        info!("event loop stopped");
+                        break;
+                    }
+                },
+                _ = flush_timer => {
+                    self.flush(&session, &mut pending);
+                    flush_timer = self.flush_timer();
+                },
+                _ = sim_timer => {
+                    self.deliver_simulated(&remote_in).await;
+                },
+                _ = clock_timer => {
+                    self.sync_clock();
+                    clock_timer = futures_timer::Delay::new(CLOCK_SYNC_INTERVAL).fuse();
+                },
+                _ = score_timer => {
+                    self.check_scores(&remote_in).await;
+                    score_timer = futures_timer::Delay::new(SCORE_CHECK_INTERVAL).fuse();
+                },
+                command = self.command_rx.select_next_some() => match command {
+                    Command::FetchAsset(hash, peer) => self.fetch_asset(hash, peer, &remote_in).await,
                 },
                 event = self.swarm.select_next_some() => match event {
                     SwarmEvent::NewListenAddr { address, .. } => {
@@ -279,21 +522,98 @@ impl Swarm {
                     SwarmEvent::Behaviour(Event::Identify(event)) => {
                         info!("{:?}", event)
                     }
+                    SwarmEvent::Behaviour(Event::Autonat(autonat::Event::StatusChanged { old, new })) => {
+                        info!("reachability changed from {:?} to {:?}", old, new);
+                        _ = remote_in.send(NetworkEvent::Reachability(new.into())).await;
+                    }
+                    SwarmEvent::Behaviour(Event::Autonat(event)) => {
+                        info!("{:?}", event)
+                    }
+                    SwarmEvent::Behaviour(Event::Ping(PingEvent { peer, result })) => {
+                        let mut links = self.links.lock().unwrap();
+                        let link = links.entry(peer).or_default();
+                        match result {
+                            Ok(PingSuccess::Ping { rtt }) => link.add_rtt(rtt),
+                            Ok(PingSuccess::Pong) => {}
+                            Err(_) => link.add_loss(),
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Clock(event)) => {
+                        self.handle_clock_event(event);
+                    }
+                    SwarmEvent::Behaviour(Event::Asset(event)) => {
+                        self.handle_asset_event(event, &remote_in).await;
+                    }
+                    SwarmEvent::Behaviour(Event::Gossipsub(GossipsubEvent::Subscribed { peer_id, topic }))
+                        if session.channel(&topic).is_some() =>
+                    {
+                        self.members.insert(peer_id);
+                    }
                     SwarmEvent::Behaviour(Event::Gossipsub(GossipsubEvent::Message {
-                        propagation_source: _peer_id,
-                        message_id: _id,
+                        propagation_source,
+                        message_id,
                         message,
                     })) => {
-                        let msg: NetworkEvent<M> = rmp_serde::from_slice(&message.data).unwrap();
-                        _ = remote_in.send(msg.clone()).await;
+                        // `Reject` penalizes `propagation_source`, which only forwarded
+                        // the frame, so it's kept for frames that are invalid in themselves.
+                        let channel = session.channel(&message.topic);
+                        let mut received = Vec::new();
+                        let acceptance = match (channel, message.source) {
+                            (Some(channel), Some(source)) => match self.check_rate(source, &remote_in).await {
+                                MessageAcceptance::Accept => match &mut self.inbound {
+                                    // the frame is validated now and received when the simulated link delivers it
+                                    Some(inbound) => match Self::decode_batch::<M>(source, &message.data) {
+                                        Ok(_) => {
+                                            let size = message.data.len();
+                                            inbound.push(propagation_source, size, (channel, message.source, message.data), Instant::now());
+                                            MessageAcceptance::Accept
+                                        }
+                                        Err(_) => MessageAcceptance::Reject,
+                                    },
+                                    None => match self.receive(channel, message.source, &message.data) {
+                                        Ok(events) => {
+                                            received = events;
+                                            MessageAcceptance::Accept
+                                        }
+                                        Err(_) => MessageAcceptance::Reject,
+                                    },
+                                },
+                                acceptance => acceptance,
+                            },
+                            (Some(_), None) => MessageAcceptance::Reject,
+                            (None, _) => MessageAcceptance::Ignore,
+                        };
+
+                        _ = self
+                            .swarm
+                            .behaviour_mut()
+                            .gossip
+                            .report_message_validation_result(&message_id, &propagation_source, acceptance);
+
+                        for msg in received {
+                            _ = remote_in.send(msg).await;
+                        }
                     },
                     SwarmEvent::ConnectionEstablished {
                         peer_id, endpoint, ..
                     } => {
-                        let topic: IdentTopic = Topic::new(peer_id.to_string());
-                        _ = self.swarm.behaviour_mut().gossip.subscribe(&topic);
-                        _ = remote_in.send(NetworkEvent::NewConnection(peer_id.to_string())).await;
-                        info!("Established connection to {:?} via {:?}", peer_id, endpoint);
+                        if self.banned.contains(&peer_id) {
+                            _ = self.swarm.disconnect_peer_id(peer_id);
+                        } else {
+                            self.connected.lock().unwrap().insert(peer_id);
+                            _ = remote_in.send(NetworkEvent::NewConnection(peer_id)).await;
+                            info!("Established connection to {:?} via {:?}", peer_id, endpoint);
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed {
+                        peer_id, num_established: 0, ..
+                    } => {
+                        self.connected.lock().unwrap().remove(&peer_id);
+                        self.members.remove(&peer_id);
+                        self.limiter.remove(&peer_id);
+                        self.links.lock().unwrap().remove(&peer_id);
+                        _ = remote_in.send(NetworkEvent::Disconnected(peer_id)).await;
+                        info!("Disconnected from {:?}", peer_id);
                     }
                     SwarmEvent::OutgoingConnectionError { peer_id, error } => {
                         info!("Outgoing connection error to {:?}: {:?}", peer_id, error);
@@ -301,8 +621,381 @@ impl Swarm {
                     _ => {}
                 }
             }
+
+            // Any branch may have queued or delivered simulated frames.
+            if self.inbound.is_some() {
+                sim_timer = self.sim_timer();
+            }
         }
     }
+
+    fn flush_timer(&self) -> Fuse<futures_timer::Delay> {
+        // Without batching every message is flushed as soon as it arrives,
+        // the timer only has to stay out of the way.
+        let interval = if self.config.batch_interval.is_zero() {
+            Duration::from_secs(60)
+        } else {
+            self.config.batch_interval
+        };
+
+        futures_timer::Delay::new(interval).fuse()
+    }
+
+    /// Fires when the next simulated frame is due.
+    fn sim_timer(&self) -> Fuse<futures_timer::Delay> {
+        let deadline = self.inbound.as_ref().and_then(NetSim::next_deadline);
+
+        let interval = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => Duration::from_secs(60),
+        };
+
+        futures_timer::Delay::new(interval).fuse()
+    }
+
+    /// Receive the simulated frames which are due.
+    async fn deliver_simulated<M>(&mut self, remote_in: &EventSender<M>)
+    where
+        M: DeserializeOwned + MessageKind,
+    {
+        let now = Instant::now();
+
+        while let Some((channel, source, data)) = self.inbound.as_mut().and_then(|sim| sim.pop_ready(now)) {
+            for msg in self.receive(channel, source, &data).unwrap_or_default() {
+                _ = remote_in.send(msg).await;
+            }
+        }
+    }
+
+    fn publish(&mut self, session: &Session, channel: Channel, frame: Vec<u8>) {
+        _ = self
+            .swarm
+            .behaviour_mut()
+            .gossip
+            .publish(session.topic(channel), frame);
+    }
+
+    /// Rate limit the frames of an author, the author is banned when it keeps flooding.
+    ///
+    /// Limited frames are ignored, not rejected: the score penalty would hit
+    /// the neighbour which forwarded the frame rather than its author.
+    async fn check_rate<M>(&mut self, source: PeerId, remote_in: &EventSender<M>) -> MessageAcceptance {
+        match self.limiter.check(source, Instant::now()) {
+            Verdict::Allow => MessageAcceptance::Accept,
+            Verdict::Limit => MessageAcceptance::Ignore,
+            Verdict::Ban => {
+                self.ban(source, BanReason::RateLimit, remote_in).await;
+                MessageAcceptance::Ignore
+            }
+        }
+    }
+
+    /// Ban the session members which gossipsub would graylist.
+    async fn check_scores<M>(&mut self, remote_in: &EventSender<M>) {
+        let threshold = PeerScoreThresholds::default().graylist_threshold;
+        let gossip = &self.swarm.behaviour().gossip;
+        let low_score: Vec<PeerId> = self
+            .members
+            .iter()
+            .filter(|peer| gossip.peer_score(peer).is_some_and(|score| score < threshold))
+            .copied()
+            .collect();
+
+        for peer in low_score {
+            self.ban(peer, BanReason::LowScore, remote_in).await;
+        }
+    }
+
+    async fn ban<M>(&mut self, peer: PeerId, reason: BanReason, remote_in: &EventSender<M>) {
+        if !self.banned.insert(peer) {
+            return;
+        }
+
+        warn!("banning {}: {:?}", peer, reason);
+        self.swarm.behaviour_mut().gossip.blacklist_peer(&peer);
+        _ = self.swarm.disconnect_peer_id(peer);
+        self.members.remove(&peer);
+        _ = remote_in.send(NetworkEvent::Banned(peer, reason)).await;
+    }
+
+    /// Follow the clock of the session member with the smallest id.
+    fn sync_clock(&mut self) {
+        let reference = self
+            .members
+            .iter()
+            .copied()
+            .chain(std::iter::once(self.origin))
+            .min()
+            .unwrap_or(self.origin);
+
+        if reference != self.reference {
+            info!("clock reference changed to {}", reference);
+            self.reference = reference;
+            self.clock_sync.reset();
+        }
+
+        if reference == self.origin {
+            self.clock.set_offset(0);
+        } else {
+            let request = ClockRequest(self.clock.local());
+            self.swarm.behaviour_mut().clock.send_request(&reference, request);
+        }
+    }
+
+    fn handle_clock_event(&mut self, event: RequestResponseEvent<ClockRequest, ClockResponse>) {
+        match event {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { request, channel, .. },
+                ..
+            } => {
+                let response = ClockResponse {
+                    sent: request.0,
+                    remote: self.clock.now(),
+                };
+                _ = self.swarm.behaviour_mut().clock.send_response(channel, response);
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response { response, .. },
+            } => {
+                if peer != self.reference {
+                    return;
+                }
+
+                let received = self.clock.local();
+                if let Some(offset) = self.clock_sync.add_sample(response.sent, response.remote, received) {
+                    self.clock.set_offset(offset);
+                }
+            }
+            event => info!("{:?}", event),
+        }
+    }
+
+    /// Fetch the asset unless it is cached or already being fetched.
+    async fn fetch_asset<M>(&mut self, hash: ContentHash, peer: PeerId, remote_in: &EventSender<M>) {
+        let cached = self.assets.lock().unwrap().path(&hash);
+        if let Some(path) = cached {
+            _ = remote_in.send(NetworkEvent::AssetReady(hash, path)).await;
+            return;
+        }
+
+        if self.downloads.contains_key(&hash) {
+            return;
+        }
+
+        info!("fetching asset {} from {}", hash, peer);
+        self.downloads.insert(hash, Download { peer, data: Vec::new() });
+        self.request_chunk(hash, peer, 0);
+    }
+
+    fn request_chunk(&mut self, hash: ContentHash, peer: PeerId, offset: u64) {
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .assets
+            .send_request(&peer, AssetRequest { hash, offset });
+        self.asset_requests.insert(request_id, hash);
+    }
+
+    async fn handle_asset_event<M>(
+        &mut self,
+        event: RequestResponseEvent<AssetRequest, AssetResponse>,
+        remote_in: &EventSender<M>,
+    ) {
+        let (hash, result) = match event {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { request, channel, .. },
+                ..
+            } => {
+                let chunk = self.assets.lock().unwrap().read_chunk(&request.hash, request.offset);
+                let response = match chunk {
+                    Ok((total, data)) => AssetResponse::Chunk { total, data },
+                    Err(_) => AssetResponse::NotFound,
+                };
+                _ = self.swarm.behaviour_mut().assets.send_response(channel, response);
+                return;
+            }
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, response },
+                ..
+            } => match self.asset_requests.remove(&request_id) {
+                Some(hash) => (hash, self.receive_chunk(hash, response)),
+                None => return,
+            },
+            RequestResponseEvent::OutboundFailure { request_id, error, .. } => match self.asset_requests.remove(&request_id) {
+                Some(hash) => (hash, Err(BlueError::remote_err(format!("{:?}", error)))),
+                None => return,
+            },
+            event => {
+                info!("{:?}", event);
+                return;
+            }
+        };
+
+        match result {
+            Ok(Some(path)) => {
+                info!("asset {} stored at {:?}", hash, path);
+                _ = remote_in.send(NetworkEvent::AssetReady(hash, path)).await;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("fetching asset {} failed: {:?}", hash, e);
+                self.downloads.remove(&hash);
+                _ = remote_in.send(NetworkEvent::AssetFailed(hash, e.to_string())).await;
+            }
+        }
+    }
+
+    /// Append the chunk, request the next one or store the complete asset.
+    fn receive_chunk(&mut self, hash: ContentHash, response: AssetResponse) -> BlueResult<Option<PathBuf>> {
+        let (total, chunk) = match response {
+            AssetResponse::Chunk { total, data } => (total, data),
+            AssetResponse::NotFound => return Err(BlueError::remote_err("asset not found")),
+        };
+
+        if total > MAX_ASSET_SIZE {
+            return Err(BlueError::remote_err(format!("asset of {} bytes is too large", total)));
+        }
+
+        let download = self
+            .downloads
+            .get_mut(&hash)
+            .ok_or_else(|| BlueError::local_err("asset isn't being fetched"))?;
+        download.data.extend_from_slice(&chunk);
+
+        let received = download.data.len() as u64;
+        if received > total || (chunk.is_empty() && received < total) {
+            return Err(BlueError::remote_err("asset chunk doesn't match the size"));
+        }
+
+        if received < total {
+            let peer = download.peer;
+            self.request_chunk(hash, peer, received);
+            return Ok(None);
+        }
+
+        let download = self.downloads.remove(&hash).unwrap();
+        self.assets.lock().unwrap().store(hash, &download.data).map(Some)
+    }
+
+    /// Publish the pending messages, one frame per channel.
+    fn flush<M>(&mut self, session: &Session, pending: &mut HashMap<Channel, Vec<(u64, M)>>)
+    where
+        M: Serialize + MessageKind,
+    {
+        for (channel, batch) in pending.iter_mut() {
+            if batch.is_empty() {
+                continue;
+            }
+
+            match self.encode_batch(batch) {
+                Ok(frame) => {
+                    let mut stats = self.stats.lock().unwrap();
+                    stats.record_sent(*channel, batch.len(), frame.len());
+                    for (_, msg) in batch.iter() {
+                        stats.record_sent_kind(msg.kind());
+                    }
+                    drop(stats);
+
+                    self.publish(session, *channel, frame);
+                }
+                Err(e) => {
+                    self.stats.lock().unwrap().record_send_error(*channel);
+                    warn!("failed to encode messages: {:?}", e);
+                }
+            }
+
+            // Every message owns a sequence number even if the frame is lost.
+            self.seq += batch.len() as u64;
+            batch.clear();
+        }
+    }
+
+    /// Encode messages stamped with the match time they were sent at.
+    fn encode_batch<M>(&self, batch: &[(u64, M)]) -> BlueResult<Vec<u8>>
+    where
+        M: Serialize,
+    {
+        let codec = self.config.codec;
+        let payload = codec.encode(&(self.origin.to_bytes(), batch))?;
+
+        let compressed = if self.config.compress {
+            wire::compress(&payload)
+        } else {
+            None
+        };
+
+        Ok(match compressed {
+            Some(compressed) => {
+                wire::encode_frame(codec.id(), wire::FLAG_COMPRESSED, self.seq, &compressed)
+            }
+            None => wire::encode_frame(codec.id(), 0, self.seq, &payload),
+        })
+    }
+
+    /// Decode a gossip frame, `source` is the signed author of the message.
+    /// Frames which claim to come from another peer are rejected.
+    fn receive<M>(&self, channel: Channel, source: Option<PeerId>, data: &[u8]) -> BlueResult<Vec<NetworkEvent<M>>>
+    where
+        M: DeserializeOwned + MessageKind,
+    {
+        let mut stats = self.stats.lock().unwrap();
+
+        let source = match source {
+            Some(source) => source,
+            None => {
+                stats.record_receive_error(channel, None);
+                return Err(BlueError::remote_err("unsigned frame"));
+            }
+        };
+
+        match Self::decode_batch::<M>(source, data) {
+            Ok((seq, batch)) => {
+                stats.record_received(channel, source, batch.len(), data.len());
+                for (_, msg) in batch.iter() {
+                    stats.record_received_kind(msg.kind());
+                }
+                Ok(batch
+                    .into_iter()
+                    .zip(seq..)
+                    .map(|((sent_at, msg), seq)| {
+                        NetworkEvent::Event(Header { source, seq, sent_at }, msg)
+                    })
+                    .collect())
+            }
+            Err(e) => {
+                stats.record_receive_error(channel, Some(source));
+                warn!("rejected message from {}: {:?}", source, e);
+                Err(e)
+            }
+        }
+    }
+
+    fn decode_batch<M>(source: PeerId, data: &[u8]) -> BlueResult<(u64, Vec<(u64, M)>)>
+    where
+        M: DeserializeOwned,
+    {
+        let frame = wire::decode_frame(data).ok_or_else(|| BlueError::remote_err("short frame"))?;
+        let codec = WireCodec::from_id(frame.codec)
+            .ok_or_else(|| BlueError::remote_err(format!("unknown codec {}", frame.codec)))?;
+
+        let decompressed;
+        let payload = if frame.flags & wire::FLAG_COMPRESSED != 0 {
+            decompressed = wire::decompress(frame.payload)
+                .ok_or_else(|| BlueError::remote_err("bad compressed payload"))?;
+            &decompressed[..]
+        } else {
+            frame.payload
+        };
+
+        let (origin, batch) = codec.decode::<(Vec<u8>, Vec<(u64, M)>)>(payload)?;
+
+        if PeerId::from_bytes(&origin).ok() != Some(source) {
+            return Err(BlueError::remote_err("claimed origin doesn't match the source"));
+        }
+
+        Ok((frame.seq, batch))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
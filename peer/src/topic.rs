@@ -0,0 +1,61 @@
+use libp2p::gossipsub::{IdentTopic, TopicHash};
+use serde::{Deserialize, Serialize};
+
+/// Gossip channels every member of a session subscribes to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Frequently repeated state where only the latest value matters.
+    State,
+    /// Discrete game events which must reach every peer.
+    Events,
+    /// Player chat.
+    Chat,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::State, Channel::Events, Channel::Chat];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::State => "state",
+            Channel::Events => "events",
+            Channel::Chat => "chat",
+        }
+    }
+}
+
+/// Topic layout of a single match: `beyond-blue/<session>/<channel>`.
+///
+/// All peers of a match share the same topics, so gossipsub relays messages
+/// through the mesh to peers we have no direct connection with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    id: String,
+}
+
+impl Session {
+    pub fn new<S: Into<String>>(id: S) -> Self {
+        Self { id: id.into() }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn topic(&self, channel: Channel) -> IdentTopic {
+        IdentTopic::new(format!("beyond-blue/{}/{}", self.id, channel.name()))
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = (Channel, IdentTopic)> + '_ {
+        Channel::ALL
+            .into_iter()
+            .map(move |channel| (channel, self.topic(channel)))
+    }
+
+    /// Find the channel of a received message, `None` for foreign topics.
+    pub fn channel(&self, topic: &TopicHash) -> Option<Channel> {
+        self.topics()
+            .find(|(_, t)| t.hash() == *topic)
+            .map(|(channel, _)| channel)
+    }
+}
@@ -0,0 +1,98 @@
+use std::convert::TryInto;
+
+/// Length of the frame header: codec id, flags and the sequence number of
+/// the first message of the batch, big-endian.
+pub(crate) const HEADER_LEN: usize = 10;
+
+/// The payload is compressed with lz4.
+pub(crate) const FLAG_COMPRESSED: u8 = 0b0000_0001;
+
+/// Payloads shorter than this are sent as is, compression wouldn't pay off.
+const COMPRESS_MIN_LEN: usize = 128;
+
+/// Upper bound of a decompressed payload, protects from decompression bombs.
+const DECOMPRESSED_MAX_LEN: usize = 1 << 20;
+
+pub(crate) struct Frame<'a> {
+    pub codec: u8,
+    pub flags: u8,
+    pub seq: u64,
+    pub payload: &'a [u8],
+}
+
+/// Prepend the header to an encoded payload.
+pub(crate) fn encode_frame(codec: u8, flags: u8, seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.push(codec);
+    frame.push(flags);
+    frame.extend_from_slice(&seq.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Split a frame into the header fields and the payload.
+pub(crate) fn decode_frame(frame: &[u8]) -> Option<Frame<'_>> {
+    if frame.len() < HEADER_LEN {
+        return None;
+    }
+
+    let (header, payload) = frame.split_at(HEADER_LEN);
+    Some(Frame {
+        codec: header[0],
+        flags: header[1],
+        seq: u64::from_be_bytes(header[2..].try_into().ok()?),
+        payload,
+    })
+}
+
+/// Compress the payload if it gets smaller.
+pub(crate) fn compress(payload: &[u8]) -> Option<Vec<u8>> {
+    if payload.len() < COMPRESS_MIN_LEN {
+        return None;
+    }
+
+    let compressed = lz4_flex::compress_prepend_size(payload);
+    if compressed.len() < payload.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    if len > DECOMPRESSED_MAX_LEN {
+        return None;
+    }
+
+    lz4_flex::decompress_size_prepended(data).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let data = encode_frame(1, FLAG_COMPRESSED, 42, &[1, 2, 3]);
+        let frame = decode_frame(&data).unwrap();
+        assert_eq!(
+            (frame.codec, frame.flags, frame.seq, frame.payload),
+            (1, FLAG_COMPRESSED, 42, &[1u8, 2, 3][..])
+        );
+    }
+
+    #[test]
+    fn test_short_frame() {
+        assert!(decode_frame(&[0; HEADER_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let payload = [7u8; 512];
+        let compressed = compress(&payload).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert_eq!(decompress(&compressed).unwrap(), payload);
+        assert!(compress(&payload[..COMPRESS_MIN_LEN - 1]).is_none());
+    }
+}
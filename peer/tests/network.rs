@@ -0,0 +1,224 @@
+//! Relay, its HTTP API and several peers in one process on loopback.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{web, App, HttpServer};
+use libp2p::multiaddr::{Multiaddr, Protocol};
+use peer::{Channel, Config, MessageKind, NetworkEvent, PeerHandle, PeerId, Session};
+use relay::{api_config, MemoryPeerStore, SharedStore};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, timeout, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum TestMessage {
+    Hello(String),
+}
+
+impl MessageKind for TestMessage {
+    fn kind(&self) -> &'static str {
+        "hello"
+    }
+}
+
+struct Harness {
+    base_url: url::Url,
+    session: Session,
+}
+
+impl Harness {
+    /// Start the relay swarm and its HTTP API on random loopback ports.
+    async fn start(session: &str) -> Self {
+        let store: SharedStore = Arc::new(Mutex::new(MemoryPeerStore::default()));
+
+        let id = common::Identity::from_file("nothing".into());
+        let mut swarm = relay::Swarm::new_with_default_transport(id.get_key(), store.clone())
+            .await
+            .unwrap();
+
+        let listen_addr = Multiaddr::empty()
+            .with(Protocol::from(Ipv4Addr::LOCALHOST))
+            .with(Protocol::Tcp(0));
+        swarm.listen_on(listen_addr).await.unwrap();
+        tokio::spawn(async move { swarm.spawn().await });
+
+        let http_api = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(store.clone()))
+                .configure(api_config)
+        })
+        .bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap();
+        let http_addr: SocketAddr = http_api.addrs()[0];
+        tokio::spawn(http_api.run());
+
+        let harness = Self {
+            base_url: url::Url::parse(&format!("http://{}", http_addr)).unwrap(),
+            session: Session::new(session),
+        };
+
+        // The relay publishes its address once it is listening.
+        let h = &harness;
+        h.wait_for(|| async move {
+            let relay = h.api("/api/relay").await?;
+            (!relay["ips"].as_array()?.is_empty()).then_some(())
+        })
+        .await;
+
+        harness
+    }
+
+    async fn api(&self, path: &str) -> Option<serde_json::Value> {
+        reqwest::get(self.base_url.join(path).ok()?)
+            .await
+            .ok()?
+            .json()
+            .await
+            .ok()
+    }
+
+    /// Start a peer and wait until the relay lists its reservation.
+    async fn add_peer(&self) -> PeerHandle<TestMessage> {
+        let id = common::Identity::from_file("nothing".into());
+        let config = Config {
+            batch_interval: Duration::ZERO,
+            ..Default::default()
+        };
+
+        let handle = PeerHandle::start(id.get_key(), config, self.base_url.clone(), self.session.clone())
+            .await
+            .unwrap();
+
+        let peer_id = &handle.local_peer_id().to_string();
+        self.wait_for(|| async move {
+            self.api("/api/peers")
+                .await?
+                .as_array()?
+                .iter()
+                .any(|peer| peer["addr"] == peer_id.as_str())
+                .then_some(())
+        })
+        .await;
+
+        handle
+    }
+
+    async fn wait_for<T, F, Fut>(&self, mut check: F) -> T
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Option<T>>,
+    {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Some(res) = check().await {
+                return res;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// Wait for an event matching `filter`, other events are skipped.
+async fn expect_event<T>(
+    handle: &PeerHandle<TestMessage>,
+    mut filter: impl FnMut(NetworkEvent<TestMessage>) -> Option<T>,
+) -> T {
+    timeout(TIMEOUT, async {
+        loop {
+            let event = handle.recv().await.expect("network task stopped");
+            if let Some(res) = filter(event) {
+                return res;
+            }
+        }
+    })
+    .await
+    .expect("timed out")
+}
+
+async fn expect_connection(handle: &PeerHandle<TestMessage>, peer_id: PeerId) {
+    expect_event(handle, |event| match event {
+        NetworkEvent::NewConnection(id) if id == peer_id => Some(()),
+        _ => None,
+    })
+    .await
+}
+
+/// Resend until the message arrives, the gossip subscriptions take a while to spread.
+async fn exchange(from: &PeerHandle<TestMessage>, to: &PeerHandle<TestMessage>, text: &str) -> u64 {
+    let msg = TestMessage::Hello(text.to_string());
+    let source = from.local_peer_id();
+
+    timeout(TIMEOUT, async {
+        loop {
+            from.send(Channel::Events, msg.clone()).unwrap();
+
+            let received = timeout(Duration::from_millis(500), async {
+                loop {
+                    match to.recv().await.expect("network task stopped") {
+                        NetworkEvent::Event(header, m) if header.source == source && m == msg => return header.seq,
+                        _ => {}
+                    }
+                }
+            })
+            .await;
+
+            if let Ok(seq) = received {
+                return seq;
+            }
+        }
+    })
+    .await
+    .expect("timed out")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_peers_connect_and_exchange_messages() {
+    let harness = Harness::start("test-exchange").await;
+
+    let first = harness.add_peer().await;
+    let second = harness.add_peer().await;
+
+    expect_connection(&first, second.local_peer_id()).await;
+    expect_connection(&second, first.local_peer_id()).await;
+    assert!(first.connected_peers().contains(&second.local_peer_id()));
+
+    exchange(&first, &second, "hello second").await;
+    exchange(&second, &first, "hello first").await;
+
+    // Both peers follow the clock of the one with the smaller id.
+    let (a, b) = (&first, &second);
+    harness
+        .wait_for(|| async move {
+            let diff = a.clock().now().abs_diff(b.clock().now());
+            (a.clock().is_synced() && b.clock().is_synced() && diff < 50_000).then_some(())
+        })
+        .await;
+
+    let third = harness.add_peer().await;
+    expect_connection(&third, first.local_peer_id()).await;
+    exchange(&first, &third, "hello third").await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_peer_sees_disconnection() {
+    let harness = Harness::start("test-disconnect").await;
+
+    let first = harness.add_peer().await;
+    let second = harness.add_peer().await;
+
+    expect_connection(&first, second.local_peer_id()).await;
+
+    let second_id = second.local_peer_id();
+    second.shutdown();
+
+    expect_event(&first, |event| match event {
+        NetworkEvent::Disconnected(id) if id == second_id => Some(()),
+        _ => None,
+    })
+    .await;
+    assert!(!first.connected_peers().contains(&second_id));
+}
@@ -2,7 +2,7 @@ use common::BlueResult;
 use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
 use libp2p::ping::{Ping, PingConfig, PingEvent};
 use libp2p::relay::v2::relay::{self, Relay};
-use libp2p::{identity, NetworkBehaviour, PeerId};
+use libp2p::{autonat, identity, NetworkBehaviour, PeerId};
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "Event", event_process = false)]
@@ -10,6 +10,7 @@ pub struct Behaviour {
     relay: Relay,
     ping: Ping,
     identify: Identify,
+    autonat: autonat::Behaviour,
 }
 
 impl Behaviour {
@@ -19,6 +20,8 @@ impl Behaviour {
             relay: Relay::new(peer_id, Default::default()),
             ping: Ping::new(PingConfig::new().with_keep_alive(true)),
             identify: Identify::new(IdentifyConfig::new("/TODO/0.0.1".to_string(), key.public())),
+            // Answers the reachability probes of the peers.
+            autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
         })
     }
 }
@@ -28,6 +31,7 @@ pub enum Event {
     Ping(PingEvent),
     Identify(IdentifyEvent),
     Relay(relay::Event),
+    Autonat(autonat::Event),
 }
 
 impl From<PingEvent> for Event {
@@ -47,3 +51,9 @@ impl From<relay::Event> for Event {
         Event::Relay(e)
     }
 }
+
+impl From<autonat::Event> for Event {
+    fn from(e: autonat::Event) -> Self {
+        Event::Autonat(e)
+    }
+}
@@ -1,4 +1,5 @@
 mod behaviour;
+#[allow(clippy::module_inception)]
 mod swarm;
 
 pub use behaviour::*;
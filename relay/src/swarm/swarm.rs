@@ -120,6 +120,9 @@ impl Swarm {
                 SwarmEvent::Behaviour(Event::Relay(event)) => {
                     println!("{:?}", event)
                 }
+                SwarmEvent::Behaviour(Event::Autonat(event)) => {
+                    println!("{:?}", event)
+                }
                 SwarmEvent::NewListenAddr { address, .. } => {
                     self.store
                         .lock()